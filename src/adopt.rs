@@ -0,0 +1,159 @@
+use crate::errors::DotcopterError;
+use crate::expand;
+use crate::files;
+use crate::import;
+use crate::model::{DotFile, DotFileType};
+use crate::mutate;
+use crate::scan;
+use slog::Logger;
+use slog::{error, info, warn};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Moves `file` into the dotfiles repository, adds a link entry for it and
+/// links it back. A failing step undoes the steps before it. Files that look
+/// like they contain secrets are refused unless `allow_secrets` is set.
+pub fn adopt(log: &Logger, config: &str, config_file: &str, file: &str, repo_path: Option<&str>, allow_secrets: bool) -> Result<(), DotcopterError> {
+  let target = PathBuf::from(expand::expand_path(file)?);
+  let metadata = target
+    .symlink_metadata()
+    .map_err(|_| DotcopterError::Message(format!("{} does not exist", target.display())))?;
+  if metadata.file_type().is_symlink() {
+    return Err(DotcopterError::Message(format!(
+      "{} is a symlink, only files and directories can be adopted",
+      target.display()
+    )));
+  }
+  let findings = scan::scan(&target);
+  for finding in &findings {
+    warn!(log, "{}", finding);
+  }
+  if !findings.is_empty() && !allow_secrets {
+    return Err(DotcopterError::Message(format!(
+      "{} looks like it contains secrets, use --allow-secrets to adopt it anyway or encrypt it",
+      target.display()
+    )));
+  }
+  let source = match repo_path {
+    Some(repo_path) if !is_inside_repo(Path::new(repo_path)) => {
+      return Err(DotcopterError::Message(format!(
+        "{} has to be a relative path inside the repository",
+        repo_path
+      )));
+    }
+    Some(repo_path) => PathBuf::from(repo_path),
+    None => repo_name(&target, expand::home_dir().as_deref().map(Path::new)),
+  };
+  if source.symlink_metadata().is_ok() {
+    return Err(DotcopterError::Message(format!("{} already exists in the repository", source.display())));
+  }
+  let target_string = target
+    .to_str()
+    .ok_or_else(|| DotcopterError::Message("target is not valid unicode".to_string()))?;
+  let dot_file = DotFile {
+    source: source
+      .to_str()
+      .ok_or_else(|| DotcopterError::Message("repository path is not valid unicode".to_string()))?
+      .to_string(),
+    target: import::replace_home_with_tilde(log, target_string)?,
+    dot_file_type: DotFileType::LINK,
+    mode: None,
+  };
+
+  let source_string = dot_file.source.clone();
+  let new_config = mutate::add_dotfiles_to_config(log, config, &[dot_file])?;
+  let config_existed = Path::new(config_file).exists();
+  files::move_path(&target, &source)?;
+  info!(log, "Moved file into repository"; "from" => target_string, "to" => &source_string);
+
+  if let Err(e) = fs::write(config_file, new_config) {
+    error!(log, "Failed to write config file. Undoing."; "error" => e.to_string());
+    undo_move(log, &source, &target);
+    return Err(e.into());
+  }
+  info!(log, "Added entry to configuration");
+
+  if let Err(e) = files::link_dot_file(&source, &target) {
+    error!(log, "Failed to create link. Undoing."; "error" => e.to_string());
+    let restored = if config_existed {
+      fs::write(config_file, config)
+    } else {
+      fs::remove_file(config_file)
+    };
+    if let Err(e) = restored {
+      error!(log, "Failed to restore config file"; "error" => e.to_string());
+    }
+    undo_move(log, &source, &target);
+    return Err(e);
+  }
+  info!(log, "Link created successfully");
+  Ok(())
+}
+
+fn undo_move(log: &Logger, source: &Path, target: &Path) {
+  match files::move_path(source, target) {
+    Ok(_) => warn!(log, "Moved file back"; "to" => format!("{}", target.display())),
+    Err(e) => error!(log, "Failed to move file back"; "from" => format!("{}", source.display()), "error" => e.to_string()),
+  }
+}
+
+/// Whether `repo_path` stays inside the repository, i.e. it is relative and
+/// has no `..` component.
+fn is_inside_repo(repo_path: &Path) -> bool {
+  repo_path.components().next().is_some()
+    && repo_path
+      .components()
+      .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// The path inside the repository for an adopted file: its path relative to
+/// the home directory without the leading dot, e.g. `~/.config/nvim` becomes
+/// `config/nvim`. Files outside of home keep only their file name.
+pub fn repo_name(target: &Path, home: Option<&Path>) -> PathBuf {
+  let relative = match home.and_then(|home| target.strip_prefix(home).ok()) {
+    Some(relative) if relative.components().next().is_some() => relative.to_path_buf(),
+    _ => target.file_name().map(PathBuf::from).unwrap_or_else(|| target.to_path_buf()),
+  };
+  let mut name = PathBuf::new();
+  for (index, component) in relative.components().enumerate() {
+    match component {
+      Component::Normal(part) if index == 0 => {
+        let part = part.to_string_lossy();
+        name.push(
+          part
+            .strip_prefix('.')
+            .filter(|stripped| !stripped.is_empty() && !stripped.starts_with('.'))
+            .unwrap_or(&part),
+        );
+      }
+      other => name.push(other.as_os_str()),
+    }
+  }
+  name
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[test]
+  fn test_repo_name() {
+    let home = Path::new("/home/blubb");
+    assert_that(&repo_name(Path::new("/home/blubb/.zshrc"), Some(home))).is_equal_to(PathBuf::from("zshrc"));
+    assert_that(&repo_name(Path::new("/home/blubb/.config/nvim"), Some(home))).is_equal_to(PathBuf::from("config/nvim"));
+    assert_that(&repo_name(Path::new("/home/blubb/bin/tool"), Some(home))).is_equal_to(PathBuf::from("bin/tool"));
+    assert_that(&repo_name(Path::new("/etc/.hidden"), Some(home))).is_equal_to(PathBuf::from("hidden"));
+    assert_that(&repo_name(Path::new("/home/blubb/..."), Some(home))).is_equal_to(PathBuf::from("..."));
+  }
+
+  #[test]
+  fn test_is_inside_repo() {
+    assert_that(&is_inside_repo(Path::new("config/nvim"))).is_true();
+    assert_that(&is_inside_repo(Path::new("./zshrc"))).is_true();
+    assert_that(&is_inside_repo(Path::new("../zshrc"))).is_false();
+    assert_that(&is_inside_repo(Path::new("config/../../zshrc"))).is_false();
+    assert_that(&is_inside_repo(Path::new("/tmp/zshrc"))).is_false();
+    assert_that(&is_inside_repo(Path::new(""))).is_false();
+  }
+}
@@ -0,0 +1,255 @@
+use crate::host;
+use slog::Logger;
+use slog::{debug, info};
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Comma separated tags of this machine, used by `class.` alternates and rcm's
+/// `tag-<name>` directories.
+const TAGS_VARIABLE: &str = "DOTCOPTER_TAGS";
+
+/// What alternates are matched against.
+pub struct Facts {
+  pub hostname: Option<String>,
+  pub os: String,
+  pub arch: String,
+  pub distro: Option<String>,
+  pub user: Option<String>,
+  pub tags: Vec<String>,
+}
+
+impl Facts {
+  pub fn current() -> Facts {
+    Facts {
+      hostname: host::hostname(),
+      os: host::os().to_string(),
+      arch: host::arch().to_string(),
+      distro: host::distro(),
+      user: host::username(),
+      tags: env::var(TAGS_VARIABLE)
+        .map(|tags| tags.split(',').map(|tag| tag.trim().to_string()).filter(|tag| !tag.is_empty()).collect())
+        .unwrap_or_default(),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq)]
+enum Condition {
+  Default,
+  Arch(String),
+  Os(String),
+  Distro(String),
+  Class(String),
+  Hostname(String),
+  User(String),
+  Unknown(String),
+}
+
+/// Picks the source to use for `source`. Alternates are files or directories
+/// next to the source named `<name>##<condition>,...` like yadm does, or the
+/// same path below rcm's `host-<hostname>/` and `tag-<tag>/` directories in
+/// `repo`. The alternate matching the most specific conditions wins, the
+/// plain source is the fallback.
+pub fn resolve(log: &Logger, source: &Path, repo: &Path, facts: &Facts) -> PathBuf {
+  match select(source, repo, facts) {
+    Some(alternate) => {
+      info!(log, "Using alternate"; "alternate" => format!("{}", alternate.display()));
+      alternate
+    }
+    None => {
+      debug!(log, "No matching alternate");
+      source.to_path_buf()
+    }
+  }
+}
+
+/// Whether any alternate exists for `source`, matching this host or not.
+pub fn has_alternates(source: &Path, repo: &Path) -> bool {
+  !candidates(source, repo).is_empty()
+}
+
+/// Every alternate of `source`, for all hosts.
+pub fn alternates(source: &Path, repo: &Path) -> Vec<PathBuf> {
+  candidates(source, repo).into_iter().map(|(path, _)| path).collect()
+}
+
+/// The best matching alternate of `source`, `None` if no alternate matches.
+pub fn select(source: &Path, repo: &Path, facts: &Facts) -> Option<PathBuf> {
+  select_with_tags(source, repo, facts).map(|(path, _)| path)
+}
+
+/// The best matching alternate of `source` and the tags it was selected by.
+pub fn select_with_tags(source: &Path, repo: &Path, facts: &Facts) -> Option<(PathBuf, Vec<String>)> {
+  let mut best: Option<(u32, PathBuf, Vec<Condition>)> = None;
+  for (path, conditions) in candidates(source, repo) {
+    if let Some(score) = score(&conditions, facts) {
+      if best.as_ref().map(|(best_score, _, _)| score > *best_score).unwrap_or(true) {
+        best = Some((score, path, conditions));
+      }
+    }
+  }
+  best.map(|(_, path, conditions)| {
+    let tags = conditions
+      .into_iter()
+      .filter_map(|condition| match condition {
+        Condition::Class(tag) => Some(tag),
+        _ => None,
+      })
+      .collect();
+    (path, tags)
+  })
+}
+
+/// The alternates of `source` sorted by path, with their conditions.
+fn candidates(source: &Path, repo: &Path) -> Vec<(PathBuf, Vec<Condition>)> {
+  let mut candidates = Vec::new();
+  if let Some(name) = source.file_name().and_then(|name| name.to_str()) {
+    let parent = source.parent().filter(|parent| !parent.as_os_str().is_empty());
+    let dir = match parent {
+      Some(parent) if parent.is_relative() => repo.join(parent),
+      Some(parent) => parent.to_path_buf(),
+      None => repo.to_path_buf(),
+    };
+    let prefix = format!("{}##", name);
+    if let Ok(entries) = fs::read_dir(&dir) {
+      for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(conditions) = file_name.strip_prefix(&prefix) {
+          let path = parent.map(|parent| parent.join(&file_name)).unwrap_or_else(|| PathBuf::from(&file_name));
+          candidates.push((path, conditions.split(',').map(parse_condition).collect()));
+        }
+      }
+    }
+  }
+  let inside_repo = source.is_relative() && source.components().all(|component| matches!(component, Component::Normal(_)));
+  if inside_repo {
+    if let Ok(entries) = fs::read_dir(repo) {
+      for entry in entries.flatten() {
+        let dir_name = entry.file_name().to_string_lossy().into_owned();
+        let condition = if let Some(hostname) = dir_name.strip_prefix("host-") {
+          Condition::Hostname(hostname.to_string())
+        } else if let Some(tag) = dir_name.strip_prefix("tag-") {
+          Condition::Class(tag.to_string())
+        } else {
+          continue;
+        };
+        let path = Path::new(&dir_name).join(source);
+        if repo.join(&path).symlink_metadata().is_ok() {
+          candidates.push((path, vec![condition]));
+        }
+      }
+    }
+  }
+  candidates.sort_by(|(a, _), (b, _)| a.cmp(b));
+  candidates
+}
+
+fn parse_condition(condition: &str) -> Condition {
+  let (key, value) = match condition.find('.') {
+    Some(index) => (&condition[..index], condition[index + 1..].to_string()),
+    None => (condition, String::new()),
+  };
+  match key {
+    "default" if value.is_empty() => Condition::Default,
+    "a" | "arch" => Condition::Arch(value),
+    "o" | "os" => Condition::Os(value),
+    "d" | "distro" => Condition::Distro(value),
+    "c" | "class" => Condition::Class(value),
+    "h" | "hostname" => Condition::Hostname(value),
+    "u" | "user" => Condition::User(value),
+    _ => Condition::Unknown(condition.to_string()),
+  }
+}
+
+/// Sums the weights of the conditions, more specific conditions weigh more.
+/// `None` if any condition does not match.
+fn score(conditions: &[Condition], facts: &Facts) -> Option<u32> {
+  let mut score = 0;
+  for condition in conditions {
+    let (matches, weight) = match condition {
+      Condition::Default => (true, 0),
+      Condition::Arch(arch) => (same_arch(arch, &facts.arch), 1),
+      Condition::Os(os) => (same_os(os, &facts.os), 2),
+      Condition::Distro(distro) => (equal(facts.distro.as_deref(), distro), 4),
+      Condition::Class(class) => (facts.tags.iter().any(|tag| tag == class), 8),
+      Condition::Hostname(hostname) => (equal(facts.hostname.as_deref(), hostname), 16),
+      Condition::User(user) => (facts.user.as_deref() == Some(user.as_str()), 32),
+      Condition::Unknown(_) => (false, 0),
+    };
+    if !matches {
+      return None;
+    }
+    score += weight;
+  }
+  Some(score)
+}
+
+fn equal(fact: Option<&str>, value: &str) -> bool {
+  fact.map(|fact| fact.eq_ignore_ascii_case(value)).unwrap_or(false)
+}
+
+/// yadm uses `uname -s`, so `Darwin` has to match rust's `macos`.
+fn same_os(os: &str, fact: &str) -> bool {
+  os.eq_ignore_ascii_case(fact) || (os.eq_ignore_ascii_case("darwin") && fact == "macos")
+}
+
+fn same_arch(arch: &str, fact: &str) -> bool {
+  let normalize = |arch: &str| match arch.to_ascii_lowercase().as_ref() {
+    "arm64" => "aarch64".to_string(),
+    "amd64" => "x86_64".to_string(),
+    other => other.to_string(),
+  };
+  normalize(arch) == normalize(fact)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  fn facts() -> Facts {
+    Facts {
+      hostname: Some("laptop".to_string()),
+      os: "macos".to_string(),
+      arch: "aarch64".to_string(),
+      distro: None,
+      user: Some("blubb".to_string()),
+      tags: vec!["work".to_string()],
+    }
+  }
+
+  #[test]
+  fn test_select() {
+    let repo = env::temp_dir().join(format!("dotcopter-alternate-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&repo);
+    fs::create_dir_all(repo.join("git")).unwrap();
+    fs::create_dir_all(repo.join("tag-work/vim")).unwrap();
+    fs::create_dir_all(repo.join("host-laptop")).unwrap();
+    for file in &[
+      "git/gitconfig",
+      "git/gitconfig##default",
+      "git/gitconfig##os.Darwin",
+      "git/gitconfig##os.Darwin,h.laptop",
+      "git/gitconfig##os.Linux,h.laptop",
+      "git/gitconfig##template",
+      "tag-work/vim/vimrc",
+      "host-laptop/zshrc",
+      "zshrc##u.blubb",
+      "tmux.conf",
+    ] {
+      fs::write(repo.join(file), "").unwrap();
+    }
+
+    let select = |source: &str| select(Path::new(source), &repo, &facts()).map(|path| path.display().to_string());
+    assert_that(&select("git/gitconfig")).is_equal_to(Some("git/gitconfig##os.Darwin,h.laptop".to_string()));
+    assert_that(&select("vim/vimrc")).is_equal_to(Some("tag-work/vim/vimrc".to_string()));
+    assert_that(&select("zshrc")).is_equal_to(Some("zshrc##u.blubb".to_string()));
+    assert_that(&select("tmux.conf")).is_equal_to(None);
+    assert_that(&select_with_tags(Path::new("vim/vimrc"), &repo, &facts()).map(|(_, tags)| tags)).is_equal_to(Some(vec!["work".to_string()]));
+    assert_that(&select_with_tags(Path::new("zshrc"), &repo, &facts()).map(|(_, tags)| tags)).is_equal_to(Some(vec![]));
+    assert_that(&has_alternates(Path::new("vim/vimrc"), &repo)).is_true();
+    assert_that(&has_alternates(Path::new("tmux.conf"), &repo)).is_false();
+    fs::remove_dir_all(&repo).unwrap();
+  }
+}
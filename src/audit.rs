@@ -0,0 +1,325 @@
+use crate::json;
+use crate::list;
+use crate::model::{format_mode, DotFile, DotFileType};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Files that are executed by a shell when it starts.
+const SHELL_RC_FILES: [&str; 14] = [
+  ".profile",
+  ".bashrc",
+  ".bash_profile",
+  ".bash_login",
+  ".bash_logout",
+  ".zshrc",
+  ".zshenv",
+  ".zprofile",
+  ".zlogin",
+  ".zlogout",
+  ".kshrc",
+  ".mkshrc",
+  ".cshrc",
+  ".tcshrc",
+];
+/// Directories whose content should only be accessible by the owner.
+const PRIVATE_DIRS: [&str; 2] = [".ssh", ".gnupg"];
+/// Files in a private directory that hold no secrets, like public keys.
+const PUBLIC_FILES: [&str; 6] = [
+  "known_hosts",
+  "known_hosts2",
+  "authorized_keys",
+  "authorized_keys2",
+  "allowed_signers",
+  "config",
+];
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Severity {
+  Low,
+  Medium,
+  High,
+}
+
+impl fmt::Display for Severity {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Severity::Low => write!(f, "low"),
+      Severity::Medium => write!(f, "medium"),
+      Severity::High => write!(f, "high"),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Finding {
+  pub severity: Severity,
+  pub path: PathBuf,
+  pub message: String,
+}
+
+pub enum Format {
+  Text,
+  Json,
+}
+
+/// Checks the installed targets of `dot_files` and the repository for
+/// permissions that let other users read secrets or change what is run.
+/// Findings are sorted by severity, most severe first.
+pub fn audit(dot_files: &[DotFile], repo: &Path, home: Option<&Path>) -> Vec<Finding> {
+  let mut findings = BTreeSet::new();
+  for entry in list::resolve(dot_files, repo) {
+    let target = Path::new(&entry.target);
+    let metadata = match target.metadata() {
+      Ok(metadata) => metadata,
+      Err(_) => continue,
+    };
+    let mode = metadata.permissions().mode() & 0o7777;
+    let relative = home.and_then(|home| target.strip_prefix(home).ok());
+    if let Some(private_dir) = home.zip(relative.and_then(private_dir)).map(|(home, private_dir)| home.join(private_dir)) {
+      check_private(&mut findings, target, mode);
+      if let Ok(dir_metadata) = private_dir.metadata() {
+        let dir_mode = dir_metadata.permissions().mode() & 0o7777;
+        if dir_mode & 0o077 != 0 {
+          findings.insert(finding(
+            Severity::Medium,
+            &private_dir,
+            format!("directory is accessible by other users ({})", format_mode(dir_mode)),
+          ));
+        }
+      }
+    }
+    if relative.map(is_shell_rc).unwrap_or(false) {
+      check_writable(&mut findings, target, mode, "shell startup file", Severity::High);
+    }
+    if entry.dot_file.dot_file_type == DotFileType::ENCRYPTED && mode & 0o077 != 0 {
+      findings.insert(finding(
+        Severity::High,
+        target,
+        format!("decrypted file is accessible by other users ({}), expected 0600", format_mode(mode)),
+      ));
+    }
+    if entry.dot_file.dot_file_type == DotFileType::LINK && is_symlink(target) {
+      if let Ok(source) = fs::canonicalize(target) {
+        check_link_source(&mut findings, target, &source, repo);
+      }
+    }
+  }
+  check_repo(&mut findings, repo);
+  let mut findings: Vec<Finding> = findings.into_iter().collect();
+  findings.sort_by(|a, b| b.severity.cmp(&a.severity).then_with(|| a.path.cmp(&b.path)));
+  findings
+}
+
+fn finding(severity: Severity, path: &Path, message: String) -> Finding {
+  Finding {
+    severity,
+    path: path.to_path_buf(),
+    message,
+  }
+}
+
+/// The directory below home of a path in `~/.ssh` or `~/.gnupg`.
+fn private_dir(relative: &Path) -> Option<&'static str> {
+  let first = relative.components().next()?.as_os_str().to_str()?;
+  PRIVATE_DIRS.iter().find(|dir| **dir == first).copied()
+}
+
+fn is_shell_rc(relative: &Path) -> bool {
+  let name = relative.to_string_lossy();
+  SHELL_RC_FILES.contains(&name.as_ref()) || name == ".config/fish/config.fish"
+}
+
+fn is_symlink(path: &Path) -> bool {
+  path.symlink_metadata().map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false)
+}
+
+/// Public keys and files like `known_hosts` may be read by anyone,
+/// everything else in a private directory only by the owner.
+fn check_private(findings: &mut BTreeSet<Finding>, target: &Path, mode: u32) {
+  let public = target.extension().map(|extension| extension == "pub").unwrap_or(false)
+    || target
+      .file_name()
+      .and_then(|name| name.to_str())
+      .map(|name| PUBLIC_FILES.contains(&name))
+      .unwrap_or(false);
+  if mode & 0o022 != 0 {
+    findings.insert(finding(Severity::High, target, format!("writable by other users ({})", format_mode(mode))));
+  } else if mode & 0o004 != 0 && !public {
+    findings.insert(finding(Severity::High, target, format!("readable by everyone ({})", format_mode(mode))));
+  } else if mode & 0o040 != 0 && !public {
+    findings.insert(finding(Severity::Medium, target, format!("readable by the group ({})", format_mode(mode))));
+  }
+}
+
+/// Writable by everyone is reported with `severity`, writable by the group
+/// one level lower.
+fn check_writable(findings: &mut BTreeSet<Finding>, path: &Path, mode: u32, what: &str, severity: Severity) {
+  if mode & 0o002 != 0 {
+    findings.insert(finding(severity, path, format!("{} is writable by everyone ({})", what, format_mode(mode))));
+  } else if mode & 0o020 != 0 {
+    let severity = match severity {
+      Severity::High => Severity::Medium,
+      _ => Severity::Low,
+    };
+    findings.insert(finding(severity, path, format!("{} is writable by the group ({})", what, format_mode(mode))));
+  }
+}
+
+/// Other users who can write to the source of a link, or to a directory
+/// above it, can change what the target contains.
+fn check_link_source(findings: &mut BTreeSet<Finding>, target: &Path, source: &Path, repo: &Path) {
+  let repo = repo.canonicalize().unwrap_or_else(|_| repo.to_path_buf());
+  let inside_repo = source.starts_with(&repo);
+  for path in source.ancestors() {
+    if inside_repo && !path.starts_with(&repo) {
+      break;
+    }
+    if !inside_repo && path != source {
+      break;
+    }
+    if let Ok(metadata) = path.metadata() {
+      let mode = metadata.permissions().mode() & 0o7777;
+      if mode & 0o022 != 0 {
+        let what = if metadata.is_dir() { "directory" } else { "file" };
+        let severity = if mode & 0o002 != 0 { Severity::High } else { Severity::Medium };
+        let writable_by = if mode & 0o002 != 0 { "everyone" } else { "the group" };
+        findings.insert(finding(
+          severity,
+          target,
+          format!(
+            "links to {} below a {} writable by {} ({})",
+            source.display(),
+            what,
+            writable_by,
+            format_mode(mode)
+          ),
+        ));
+        return;
+      }
+    }
+  }
+}
+
+/// Files and directories of the repository that other users can change.
+fn check_repo(findings: &mut BTreeSet<Finding>, repo: &Path) {
+  let mut pending = vec![repo.to_path_buf()];
+  while let Some(path) = pending.pop() {
+    let metadata = match path.symlink_metadata() {
+      Ok(metadata) => metadata,
+      Err(_) => continue,
+    };
+    if metadata.file_type().is_symlink() {
+      continue;
+    }
+    let mode = metadata.permissions().mode() & 0o7777;
+    if metadata.is_dir() {
+      if path.file_name().map(|name| name == ".git").unwrap_or(false) {
+        continue;
+      }
+      check_writable(findings, &path, mode, "repository directory", Severity::High);
+      if let Ok(entries) = fs::read_dir(&path) {
+        pending.extend(entries.flatten().map(|entry| entry.path()));
+      }
+    } else {
+      check_writable(findings, &path, mode, "repository file", Severity::Medium);
+    }
+  }
+}
+
+pub fn render(findings: &[Finding], format: &Format) -> String {
+  match format {
+    Format::Text => findings
+      .iter()
+      .map(|finding| format!("{:<6}  {}: {}\n", finding.severity.to_string(), finding.path.display(), finding.message))
+      .collect(),
+    Format::Json => {
+      let objects: Vec<String> = findings
+        .iter()
+        .map(|finding| {
+          json::object(&[
+            ("severity", json::string(&finding.severity.to_string())),
+            ("path", json::string(&finding.path.to_string_lossy())),
+            ("message", json::string(&finding.message)),
+          ])
+        })
+        .collect();
+      let mut rendered = json::array(&objects);
+      rendered.push('\n');
+      rendered
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::a_dot_file;
+  use spectral::prelude::*;
+  use std::env;
+
+  fn set_mode(path: &Path, mode: u32) {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+  }
+
+  #[test]
+  fn test_audit() {
+    let root = env::temp_dir().join(format!("dotcopter-audit-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let repo = root.join("repo");
+    let home = root.join("home");
+    fs::create_dir_all(repo.join("shared")).unwrap();
+    fs::create_dir_all(home.join(".ssh")).unwrap();
+    set_mode(&repo, 0o755);
+    set_mode(&home.join(".ssh"), 0o700);
+    for (path, mode) in [
+      (home.join(".ssh/id_ed25519"), 0o644),
+      (home.join(".ssh/id_ed25519.pub"), 0o644),
+      (home.join(".ssh/known_hosts"), 0o644),
+      (home.join(".ssh/config"), 0o664),
+      (home.join(".netrc"), 0o640),
+      (home.join(".zshrc"), 0o664),
+      (repo.join("shared/vimrc"), 0o644),
+      (repo.join("gitconfig"), 0o644),
+    ] {
+      fs::write(&path, "").unwrap();
+      set_mode(&path, mode);
+    }
+    set_mode(&repo.join("shared"), 0o777);
+    std::os::unix::fs::symlink(repo.join("shared/vimrc"), home.join(".vimrc")).unwrap();
+    std::os::unix::fs::symlink(repo.join("gitconfig"), home.join(".gitconfig")).unwrap();
+    let dot_file = |target: &str, dot_file_type| a_dot_file("gitconfig", &home.join(target).to_string_lossy(), dot_file_type, None);
+    let dot_files = vec![
+      dot_file(".ssh/id_ed25519", DotFileType::COPY),
+      dot_file(".ssh/id_ed25519.pub", DotFileType::COPY),
+      dot_file(".ssh/known_hosts", DotFileType::COPY),
+      dot_file(".ssh/config", DotFileType::COPY),
+      dot_file(".netrc", DotFileType::ENCRYPTED),
+      dot_file(".zshrc", DotFileType::COPY),
+      dot_file(".vimrc", DotFileType::LINK),
+      dot_file(".gitconfig", DotFileType::LINK),
+    ];
+    let findings: Vec<String> = audit(&dot_files, &repo, Some(&home))
+      .iter()
+      .map(|finding| format!("{} {}", finding.severity, finding.path.strip_prefix(&root).unwrap().display()))
+      .collect();
+    assert_that(&findings).is_equal_to(vec![
+      "high home/.netrc".to_string(),
+      "high home/.ssh/config".to_string(),
+      "high home/.ssh/id_ed25519".to_string(),
+      "high home/.vimrc".to_string(),
+      "high repo/shared".to_string(),
+      "medium home/.zshrc".to_string(),
+    ]);
+
+    let json = render(&audit(&dot_files[5..6], &repo, Some(&home))[..1], &Format::Json);
+    assert_that(&json).is_equal_to(format!(
+      "[{{\"severity\":\"high\",\"path\":\"{}\",\"message\":\"repository directory is writable by everyone (0777)\"}}]\n",
+      repo.join("shared").display()
+    ));
+    set_mode(&repo.join("shared"), 0o755);
+    fs::remove_dir_all(&root).unwrap();
+  }
+}
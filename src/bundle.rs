@@ -0,0 +1,344 @@
+use crate::alternate;
+use crate::checksum;
+use crate::errors::DotcopterError;
+use crate::expand;
+use crate::model::DotFile;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use slog::Logger;
+use slog::{debug, info, o, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Name of the configuration inside a bundle.
+pub const CONFIG: &str = "dotcopter.yaml";
+/// `sha256sum` compatible list of every file in a bundle. Links are listed as
+/// `symlink  <path> -> <target>`, which `sha256sum -c` skips with a warning.
+pub const MANIFEST: &str = "MANIFEST.sha256";
+/// Marks a link in the manifest instead of a hash.
+const SYMLINK: &str = "symlink";
+
+/// Packs the configuration and every source it references, with the
+/// alternates for other hosts, into a gzipped tar archive. Sources outside
+/// of `repo` cannot be relocated and are left out.
+pub fn create(log: &Logger, config: &str, dot_files: &[DotFile], repo: &Path, out: &Path) -> Result<(), DotcopterError> {
+  let repo = repo.canonicalize()?;
+  let mut files = BTreeMap::new();
+  for dot_file in dot_files {
+    let log = log.new(o!("target" => dot_file.target.clone(), "source" => dot_file.source.clone()));
+    let source = match expand::expand_path(&dot_file.source) {
+      Ok(source) => PathBuf::from(source),
+      Err(e) => {
+        warn!(log, "Failed to expand source, leaving it out"; "error" => e.to_string());
+        continue;
+      }
+    };
+    let mut sources = alternate::alternates(&source, &repo);
+    sources.push(source);
+    for source in sources {
+      let path = expand::normalize(&repo.join(&source));
+      match path.strip_prefix(&repo) {
+        Ok(relative) if path.symlink_metadata().is_ok() => add_recursively(&mut files, &repo, relative)?,
+        Ok(_) => warn!(log, "Source does not exist, leaving it out"; "path" => format!("{}", source.display())),
+        Err(_) => warn!(log, "Source is outside the repository, leaving it out"; "path" => format!("{}", source.display())),
+      }
+    }
+  }
+  for reserved in &[CONFIG, MANIFEST] {
+    if files.contains_key(Path::new(reserved)) {
+      return Err(DotcopterError::Message(format!(
+        "a source is named {}, which is reserved for the bundle",
+        reserved
+      )));
+    }
+  }
+
+  let mut builder = tar::Builder::new(GzEncoder::new(File::create(out)?, Compression::default()));
+  let mut manifest = String::new();
+  append_file(&mut builder, Path::new(CONFIG), config.as_bytes(), 0o644)?;
+  manifest.push_str(&format!("{}  {}\n", checksum::hash_bytes(config.as_bytes()), CONFIG));
+  for (relative, path) in &files {
+    let metadata = path.symlink_metadata()?;
+    if relative.to_string_lossy().contains('\n') {
+      return Err(DotcopterError::Message(format!(
+        "{} contains a newline and cannot be bundled",
+        relative.display()
+      )));
+    }
+    if metadata.file_type().is_symlink() {
+      let target = fs::read_link(path)?;
+      if [relative, target.as_path()]
+        .iter()
+        .any(|path| path.to_string_lossy().contains(" -> ") || path.to_string_lossy().contains('\n'))
+      {
+        return Err(DotcopterError::Message(format!("link {} cannot be listed in the manifest", relative.display())));
+      }
+      let mut header = header(tar::EntryType::Symlink, 0o777, 0);
+      builder.append_link(&mut header, relative, &target)?;
+      manifest.push_str(&format!("{}  {} -> {}\n", SYMLINK, relative.display(), target.display()));
+    } else if metadata.is_dir() {
+      let mut header = header(tar::EntryType::Directory, metadata.permissions().mode() & 0o7777, 0);
+      builder.append_data(&mut header, relative, std::io::empty())?;
+    } else {
+      append_file(&mut builder, relative, &fs::read(path)?, metadata.permissions().mode() & 0o7777)?;
+      manifest.push_str(&format!("{}  {}\n", checksum::hash(path)?, relative.display()));
+    }
+    debug!(log, "Added to bundle"; "path" => format!("{}", relative.display()));
+  }
+  append_file(&mut builder, Path::new(MANIFEST), manifest.as_bytes(), 0o644)?;
+  builder.into_inner()?.finish()?.flush()?;
+  info!(log, "Bundled files"; "count" => files.len());
+  Ok(())
+}
+
+fn add_recursively(files: &mut BTreeMap<PathBuf, PathBuf>, repo: &Path, relative: &Path) -> Result<(), DotcopterError> {
+  for ancestor in relative.ancestors().skip(1).filter(|ancestor| !ancestor.as_os_str().is_empty()) {
+    files.entry(ancestor.to_path_buf()).or_insert_with(|| repo.join(ancestor));
+  }
+  let path = repo.join(relative);
+  files.insert(relative.to_path_buf(), path.clone());
+  if path.symlink_metadata()?.is_dir() {
+    for entry in fs::read_dir(&path)? {
+      add_recursively(files, repo, &relative.join(entry?.file_name()))?;
+    }
+  }
+  Ok(())
+}
+
+fn header(entry_type: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+  let mut header = tar::Header::new_gnu();
+  header.set_entry_type(entry_type);
+  header.set_mode(mode);
+  header.set_size(size);
+  header.set_mtime(0);
+  header.set_uid(0);
+  header.set_gid(0);
+  header
+}
+
+fn append_file<W: Write>(builder: &mut tar::Builder<W>, path: &Path, content: &[u8], mode: u32) -> Result<(), DotcopterError> {
+  let mut header = header(tar::EntryType::Regular, mode, content.len() as u64);
+  builder.append_data(&mut header, path, content)?;
+  Ok(())
+}
+
+/// Checks a bundle against its manifest and unpacks it into the cache
+/// directory. Returns the directory, which holds the configuration as
+/// `CONFIG` and the sources relative to it.
+pub fn unpack(log: &Logger, bundle: &Path) -> Result<PathBuf, DotcopterError> {
+  let content = fs::read(bundle)?;
+  verify(&content)?;
+  let cache = dirs::cache_dir()
+    .ok_or_else(|| DotcopterError::Message("cache directory not set".to_string()))?
+    .join("dotcopter/bundles")
+    .join(checksum::hash_bytes(&content));
+  if cache.symlink_metadata().is_ok() {
+    fs::remove_dir_all(&cache)?;
+  }
+  fs::create_dir_all(&cache)?;
+  info!(log, "Unpacking bundle"; "cache" => format!("{}", cache.display()));
+  let mut archive = tar::Archive::new(GzDecoder::new(content.as_slice()));
+  archive.set_preserve_permissions(true);
+  if let Err(e) = archive.unpack(&cache) {
+    let _ = fs::remove_dir_all(&cache);
+    return Err(e.into());
+  }
+  Ok(cache)
+}
+
+/// Every regular file has to be listed in the manifest with its hash and
+/// every link with its target. Other entry types, entries that appear twice
+/// and paths leaving the bundle are refused. Nothing is written to disk.
+fn verify(bundle: &[u8]) -> Result<(), DotcopterError> {
+  let mut files = BTreeMap::new();
+  let mut links = BTreeMap::new();
+  let mut manifest = None;
+  let mut archive = tar::Archive::new(GzDecoder::new(bundle));
+  for entry in archive.entries()? {
+    let mut entry = entry?;
+    let path = bundle_path(&entry.path()?)?;
+    if files.contains_key(&path) || links.contains_key(&path) || (manifest.is_some() && path == Path::new(MANIFEST)) {
+      return Err(DotcopterError::Message(format!("{} is in the bundle more than once", path.display())));
+    }
+    match entry.header().entry_type() {
+      tar::EntryType::Directory => {}
+      tar::EntryType::Regular => {
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        if path == Path::new(MANIFEST) {
+          manifest = Some(String::from_utf8(content).map_err(|_| DotcopterError::Message(format!("{} is not valid unicode", MANIFEST)))?);
+        } else {
+          files.insert(path, checksum::hash_bytes(&content));
+        }
+      }
+      tar::EntryType::Symlink => {
+        let target = entry
+          .link_name()?
+          .ok_or_else(|| DotcopterError::Message(format!("link {} has no target", path.display())))?;
+        links.insert(path, target.into_owned());
+      }
+      other => return Err(DotcopterError::Message(format!("{} has the unsupported type {:?}", path.display(), other))),
+    }
+  }
+  let manifest = manifest.ok_or_else(|| DotcopterError::Message(format!("bundle has no {}", MANIFEST)))?;
+  for line in manifest.lines() {
+    let malformed = || DotcopterError::Message(format!("malformed manifest line: {}", line));
+    let (hash, path) = line.split_once("  ").ok_or_else(malformed)?;
+    let (path, matches) = if hash == SYMLINK {
+      let (path, target) = path.split_once(" -> ").ok_or_else(malformed)?;
+      let path = bundle_path(Path::new(path))?;
+      let matches = links.remove(&path).map(|actual| actual == Path::new(target));
+      (path, matches)
+    } else {
+      let path = bundle_path(Path::new(path))?;
+      let matches = files.remove(&path).map(|actual| actual == hash);
+      (path, matches)
+    };
+    match matches {
+      Some(true) => {}
+      Some(false) => return Err(DotcopterError::Message(format!("{} does not match the manifest", path.display()))),
+      None => return Err(DotcopterError::Message(format!("{} is missing from the bundle", path.display()))),
+    }
+  }
+  match files.keys().chain(links.keys()).next() {
+    Some(path) => Err(DotcopterError::Message(format!("{} is not in the manifest", path.display()))),
+    None => Ok(()),
+  }
+}
+
+/// A path inside the bundle, which has to be relative and stay below it.
+fn bundle_path(path: &Path) -> Result<PathBuf, DotcopterError> {
+  let mut relative = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::Normal(part) => relative.push(part),
+      Component::CurDir => {}
+      _ => return Err(DotcopterError::Message(format!("{} leaves the bundle", path.display()))),
+    }
+  }
+  Ok(relative)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::{a_dot_file, DotFileType};
+  use spectral::prelude::*;
+  use std::env;
+
+  fn a_logger() -> Logger {
+    use slog::Drain;
+    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+    let drain = slog_term::FullFormat::new(plain).build().fuse();
+    Logger::root(drain, o!())
+  }
+
+  type Entries = Vec<(tar::EntryType, PathBuf, Vec<u8>)>;
+
+  fn read_entries(bundle: &[u8]) -> Entries {
+    let mut archive = tar::Archive::new(GzDecoder::new(bundle));
+    archive
+      .entries()
+      .unwrap()
+      .map(|entry| {
+        let mut entry = entry.unwrap();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).unwrap();
+        if let Some(target) = entry.link_name().unwrap() {
+          content = target.to_string_lossy().into_owned().into_bytes();
+        }
+        (entry.header().entry_type(), entry.path().unwrap().into_owned(), content)
+      })
+      .collect()
+  }
+
+  fn write_entries(entries: &Entries) -> Vec<u8> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (entry_type, path, content) in entries {
+      let mut header = header(*entry_type, 0o644, 0);
+      if *entry_type == tar::EntryType::Symlink {
+        builder.append_link(&mut header, path, String::from_utf8_lossy(content).as_ref()).unwrap();
+      } else {
+        header.set_size(content.len() as u64);
+        builder.append_data(&mut header, path, content.as_slice()).unwrap();
+      }
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+  }
+
+  fn changed(bundle: &[u8], change: impl Fn(&mut Entries)) -> Vec<u8> {
+    let mut entries = read_entries(bundle);
+    change(&mut entries);
+    write_entries(&entries)
+  }
+
+  fn set_content(entries: &mut Entries, path: &str, content: &str) {
+    let entry = entries.iter_mut().find(|(_, entry_path, _)| entry_path == Path::new(path)).unwrap();
+    entry.2 = content.as_bytes().to_vec();
+  }
+
+  #[test]
+  fn test_create_and_verify() {
+    let root = env::temp_dir().join(format!("dotcopter-bundle-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let repo = root.join("repo");
+    fs::create_dir_all(repo.join("nvim/lua")).unwrap();
+    fs::write(repo.join("nvim/lua/init.lua"), "vim.o.number = true\n").unwrap();
+    std::os::unix::fs::symlink("init.lua", repo.join("nvim/lua/alias.lua")).unwrap();
+    fs::write(repo.join("gitconfig"), "[user]\n").unwrap();
+    fs::write(repo.join("gitconfig##os.Darwin"), "[mac]\n").unwrap();
+    fs::write(repo.join("unused"), "").unwrap();
+    let dot_files: Vec<DotFile> = ["nvim", "gitconfig", "/etc/hosts"]
+      .iter()
+      .map(|source| a_dot_file(source, "~/x", DotFileType::LINK, None))
+      .collect();
+    let bundle = root.join("dots.tar.gz");
+    create(&a_logger(), "files:\n", &dot_files, &repo, &bundle).unwrap();
+    let again = root.join("again.tar.gz");
+    create(&a_logger(), "files:\n", &dot_files, &repo, &again).unwrap();
+    let bundle = fs::read(&bundle).unwrap();
+    assert_that(&bundle).is_equal_to(fs::read(&again).unwrap());
+
+    let entries = read_entries(&bundle);
+    let (_, _, manifest) = entries.iter().find(|(_, path, _)| path == Path::new(MANIFEST)).unwrap();
+    let manifest = String::from_utf8(manifest.clone()).unwrap();
+    let paths: Vec<&str> = manifest.lines().map(|line| line.split_once("  ").unwrap().1).collect();
+    assert_that(&paths).is_equal_to(vec![
+      CONFIG,
+      "gitconfig",
+      "gitconfig##os.Darwin",
+      "nvim/lua/alias.lua -> init.lua",
+      "nvim/lua/init.lua",
+    ]);
+    assert_that(&verify(&bundle)).is_ok();
+    assert_that(&verify(&write_entries(&entries))).is_ok();
+
+    let tampered = changed(&bundle, |entries| set_content(entries, "gitconfig", "[tampered]\n"));
+    assert_that(&verify(&tampered)).is_err();
+    let relinked = changed(&bundle, |entries| set_content(entries, "nvim/lua/alias.lua", "/etc/passwd"));
+    assert_that(&verify(&relinked)).is_err();
+    let extra_file = changed(&bundle, |entries| {
+      entries.push((tar::EntryType::Regular, PathBuf::from("nvim/extra"), Vec::new()))
+    });
+    assert_that(&verify(&extra_file)).is_err();
+    let extra_link = changed(&bundle, |entries| {
+      entries.push((tar::EntryType::Symlink, PathBuf::from("nvim/extra"), b"/etc".to_vec()))
+    });
+    assert_that(&verify(&extra_link)).is_err();
+    let twice = changed(&bundle, |entries| entries.push(entries[1].clone()));
+    assert_that(&verify(&twice)).is_err();
+    for path in ["../outside", "/etc/passwd"] {
+      let escaping = changed(&bundle, |entries| {
+        let line = format!("{}  {}\n", checksum::hash_bytes(b""), path);
+        set_content(entries, MANIFEST, &(manifest.clone() + &line));
+      });
+      assert_that(&verify(&escaping).unwrap_err().to_string()).contains("leaves the bundle");
+    }
+    fs::remove_dir_all(&root).unwrap();
+  }
+}
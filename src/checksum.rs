@@ -21,3 +21,9 @@ pub fn hash(path: &Path) -> Result<String, std::io::Error> {
   }
   Ok(hash.result_str())
 }
+
+pub fn hash_bytes(content: &[u8]) -> String {
+  let mut hash = Sha256::new();
+  hash.input(content);
+  hash.result_str()
+}
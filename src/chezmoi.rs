@@ -0,0 +1,370 @@
+use crate::errors::DotcopterError;
+use crate::expand;
+use crate::model::{DotFile, DotFileType};
+use crate::template;
+use glob::{MatchOptions, Pattern};
+use slog::Logger;
+use slog::{debug, info};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A chezmoi source state translated into entries. Everything that has no
+/// equivalent in dotcopter is listed in `problems` with the source path.
+pub struct ChezmoiImport {
+  pub dot_files: Vec<DotFile>,
+  pub problems: Vec<String>,
+}
+
+#[derive(Default)]
+struct Attributes {
+  name: String,
+  create: bool,
+  dot: bool,
+  empty: bool,
+  encrypted: bool,
+  exact: bool,
+  executable: bool,
+  external: bool,
+  modify: bool,
+  private: bool,
+  readonly: bool,
+  remove: bool,
+  run: bool,
+  symlink: bool,
+  template: bool,
+}
+
+const PREFIXES: [&str; 14] = [
+  "create_",
+  "dot_",
+  "empty_",
+  "encrypted_",
+  "exact_",
+  "executable_",
+  "external_",
+  "modify_",
+  "private_",
+  "readonly_",
+  "remove_",
+  "run_",
+  "symlink_",
+  "literal_",
+];
+const IGNORE_FILE: &str = ".chezmoiignore";
+
+/// The patterns of `.chezmoiignore`, matched against target paths relative
+/// to home. Patterns starting with `!` take priority over all others.
+struct Ignore {
+  includes: Vec<Pattern>,
+  excludes: Vec<Pattern>,
+}
+
+impl Ignore {
+  /// The file is a template in chezmoi. Template lines are reported and
+  /// skipped, so patterns inside a conditional apply on every host.
+  fn load(root: &Path, problems: &mut Vec<String>) -> Ignore {
+    let mut ignore = Ignore {
+      includes: Vec::new(),
+      excludes: Vec::new(),
+    };
+    let content = fs::read_to_string(root.join(IGNORE_FILE)).unwrap_or_default();
+    for line in content.lines().map(|line| line.split(" #").next().unwrap_or("").trim()) {
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      if line.contains("{{") {
+        problems.push(format!("{}: template line `{}` is not evaluated", IGNORE_FILE, line));
+        continue;
+      }
+      let (patterns, line) = match line.strip_prefix('!') {
+        Some(line) => (&mut ignore.excludes, line),
+        None => (&mut ignore.includes, line),
+      };
+      match Pattern::new(line.trim_start_matches('/')) {
+        Ok(pattern) => patterns.push(pattern),
+        Err(e) => problems.push(format!("{}: invalid pattern `{}`: {}", IGNORE_FILE, line, e)),
+      }
+    }
+    ignore
+  }
+
+  fn is_ignored(&self, target: &Path) -> bool {
+    let options = MatchOptions {
+      case_sensitive: true,
+      require_literal_separator: true,
+      require_literal_leading_dot: false,
+    };
+    let matches = |patterns: &[Pattern]| patterns.iter().any(|pattern| pattern.matches_path_with(target, options));
+    matches(&self.includes) && !matches(&self.excludes)
+  }
+}
+
+/// Reads the chezmoi source directory `dir`. Targets are relative to the home
+/// directory, sources relative to the current directory if they are inside it.
+/// Targets matching `.chezmoiignore` are left out.
+pub fn scan_source(log: &Logger, dir: &Path) -> Result<ChezmoiImport, DotcopterError> {
+  let mut root = dir.canonicalize()?;
+  if let Ok(relative_root) = fs::read_to_string(root.join(".chezmoiroot")) {
+    root = root.join(relative_root.trim()).canonicalize()?;
+    info!(log, "Using source root from .chezmoiroot"; "root" => format!("{}", root.display()));
+  }
+  let mut import = ChezmoiImport {
+    dot_files: Vec::new(),
+    problems: Vec::new(),
+  };
+  let repo = env::current_dir()?;
+  let ignore = Ignore::load(&root, &mut import.problems);
+  scan_dir(log, &root, &root, Path::new(""), &repo, &ignore, &mut import)?;
+  Ok(import)
+}
+
+fn scan_dir(log: &Logger, root: &Path, dir: &Path, target: &Path, repo: &Path, ignore: &Ignore, import: &mut ChezmoiImport) -> Result<(), DotcopterError> {
+  let mut children: Vec<PathBuf> = fs::read_dir(dir)?
+    .map(|dir_entry| dir_entry.map(|dir_entry| dir_entry.path()))
+    .collect::<Result<_, _>>()?;
+  children.sort();
+  for source in children {
+    let name = source.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let relative = source.strip_prefix(root).unwrap_or(&source).display().to_string();
+    if name == IGNORE_FILE {
+      continue;
+    }
+    if name.starts_with(".chezmoi") {
+      import.problems.push(format!("{}: chezmoi special files are not imported", relative));
+      continue;
+    }
+    if name.starts_with('.') {
+      debug!(log, "Skipping hidden source file"; "source" => &relative);
+      continue;
+    }
+    let is_dir = source.symlink_metadata()?.is_dir();
+    let attributes = parse_name(&name, is_dir);
+    if ignore.is_ignored(&target.join(target_name(&attributes))) {
+      debug!(log, "Ignored by .chezmoiignore"; "source" => &relative);
+      continue;
+    }
+    let mut report = |message: &str| import.problems.push(format!("{}: {}", relative, message));
+    if is_dir {
+      if attributes.remove || attributes.external {
+        report("removed and external directories are not supported");
+        continue;
+      }
+      if attributes.exact {
+        report("exact_ is not supported, files missing from the source are not removed");
+      }
+      if attributes.private || attributes.readonly {
+        report("directory permissions are not supported");
+      }
+      scan_dir(log, root, &source, &target.join(target_name(&attributes)), repo, ignore, import)?;
+      continue;
+    }
+    if attributes.run || attributes.modify || attributes.remove {
+      report("scripts, modify_ and remove_ files are not supported");
+      continue;
+    }
+    if attributes.encrypted {
+      report("encrypted files are not supported");
+      continue;
+    }
+    let target = Path::new("~").join(target.join(target_name(&attributes)));
+    let dot_file = if attributes.symlink {
+      if attributes.template {
+        report("templated symlinks are not supported");
+        continue;
+      }
+      let destination = fs::read_to_string(&source)?.trim().to_string();
+      let link_source = if destination.starts_with('/') || destination.starts_with('~') {
+        destination
+      } else {
+        expand::normalize(&target.parent().unwrap_or_else(|| Path::new("~")).join(destination))
+          .display()
+          .to_string()
+      };
+      DotFile {
+        source: link_source,
+        target: target.display().to_string(),
+        dot_file_type: DotFileType::LINK,
+        mode: None,
+      }
+    } else {
+      if attributes.template {
+        let unsupported = template::unsupported_expressions(&fs::read_to_string(&source)?);
+        if !unsupported.is_empty() {
+          report(&format!("template uses unsupported expressions: {}", unsupported.join(", ")));
+          continue;
+        }
+      }
+      if attributes.create {
+        report("create_ is imported as a copy, applying with --force replaces an existing target");
+      }
+      DotFile {
+        source: source.strip_prefix(repo).unwrap_or(&source).display().to_string(),
+        target: target.display().to_string(),
+        dot_file_type: if attributes.template { DotFileType::TEMPLATE } else { DotFileType::COPY },
+        mode: mode(&attributes),
+      }
+    };
+    if attributes.empty {
+      debug!(log, "Empty file is kept as a copy"; "source" => &relative);
+    }
+    info!(log, "Found dotfile"; "target" => &dot_file.target);
+    import.dot_files.push(dot_file);
+  }
+  Ok(())
+}
+
+/// Splits chezmoi's attribute prefixes and suffixes off a source name.
+/// `literal_` and `.literal` stop the parsing.
+fn parse_name(name: &str, is_dir: bool) -> Attributes {
+  let mut attributes = Attributes::default();
+  let mut rest = name;
+  'prefixes: loop {
+    for prefix in PREFIXES.iter() {
+      if let Some(stripped) = rest.strip_prefix(prefix) {
+        match *prefix {
+          "create_" => attributes.create = true,
+          "dot_" => attributes.dot = true,
+          "empty_" => attributes.empty = true,
+          "encrypted_" => attributes.encrypted = true,
+          "exact_" => attributes.exact = true,
+          "executable_" => attributes.executable = true,
+          "external_" => attributes.external = true,
+          "modify_" => attributes.modify = true,
+          "private_" => attributes.private = true,
+          "readonly_" => attributes.readonly = true,
+          "remove_" => attributes.remove = true,
+          "run_" => attributes.run = true,
+          "symlink_" => attributes.symlink = true,
+          _ => {
+            rest = stripped;
+            break 'prefixes;
+          }
+        }
+        rest = stripped;
+        if *prefix == "dot_" {
+          break 'prefixes;
+        }
+        continue 'prefixes;
+      }
+    }
+    break;
+  }
+  if !is_dir {
+    if let Some(stripped) = rest.strip_suffix(".literal") {
+      rest = stripped;
+    } else {
+      if attributes.encrypted {
+        rest = rest.strip_suffix(".age").or_else(|| rest.strip_suffix(".asc")).unwrap_or(rest);
+      }
+      if let Some(stripped) = rest.strip_suffix(".tmpl") {
+        attributes.template = true;
+        rest = stripped;
+      }
+    }
+  }
+  attributes.name = rest.to_string();
+  attributes
+}
+
+fn target_name(attributes: &Attributes) -> String {
+  if attributes.dot {
+    format!(".{}", attributes.name)
+  } else {
+    attributes.name.clone()
+  }
+}
+
+/// The mode chezmoi would give the target, only if an attribute changes the
+/// default.
+fn mode(attributes: &Attributes) -> Option<u32> {
+  if !(attributes.private || attributes.executable || attributes.readonly) {
+    return None;
+  }
+  let mut mode = if attributes.executable { 0o755 } else { 0o644 };
+  if attributes.private {
+    mode &= !0o077;
+  }
+  if attributes.readonly {
+    mode &= !0o222;
+  }
+  Some(mode)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  fn a_logger() -> Logger {
+    use slog::o;
+    use slog::Drain;
+    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+    let drain = slog_term::FullFormat::new(plain).build().fuse();
+    Logger::root(drain, o!())
+  }
+
+  #[test]
+  fn test_parse_name() {
+    let attributes = parse_name("private_executable_dot_script.sh.tmpl", false);
+    assert_that(&target_name(&attributes)).is_equal_to(".script.sh".to_string());
+    assert_that(&attributes.template).is_true();
+    assert_that(&mode(&attributes)).is_equal_to(Some(0o700));
+    assert_that(&target_name(&parse_name("literal_dot_x.tmpl", false))).is_equal_to("dot_x".to_string());
+    assert_that(&target_name(&parse_name("dot_private_x", false))).is_equal_to(".private_x".to_string());
+    assert_that(&parse_name("x.tmpl.literal", false).template).is_false();
+  }
+
+  #[test]
+  fn test_scan_source() {
+    let root = env::temp_dir().join(format!("dotcopter-chezmoi-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("private_dot_ssh")).unwrap();
+    fs::create_dir_all(root.join("dot_config/nvim")).unwrap();
+    fs::write(
+      root.join(".chezmoiignore"),
+      "README.md # docs\n.config/nvim/*\n!.config/nvim/init.vim\n{{ if ne .chezmoi.os \"darwin\" }}\n.hammerspoon\n{{ end }}\n",
+    )
+    .unwrap();
+    fs::create_dir_all(root.join("dot_hammerspoon")).unwrap();
+    fs::write(root.join("README.md"), "# my dotfiles\n").unwrap();
+    fs::write(root.join("dot_hammerspoon/init.lua"), "hs.alert('hi')\n").unwrap();
+    fs::write(root.join("dot_config/nvim/lazy-lock.json"), "{}\n").unwrap();
+    fs::write(root.join("dot_gitconfig.tmpl"), "[user]\n  name = {{ .name }}\n").unwrap();
+    fs::write(root.join("dot_zshrc.tmpl"), "{{ if eq .chezmoi.os \"darwin\" }}mac{{ end }}\n").unwrap();
+    fs::write(root.join("private_dot_ssh/private_config"), "Host *\n").unwrap();
+    fs::write(root.join("dot_config/nvim/symlink_init.vim"), "../vim/vimrc\n").unwrap();
+    fs::write(root.join("run_once_install.sh"), "#!/bin/sh\n").unwrap();
+
+    let import = scan_source(&a_logger(), &root).unwrap();
+    let source = |name: &str| root.canonicalize().unwrap().join(name).display().to_string();
+    assert_that(&import.dot_files).is_equal_to(vec![
+      DotFile {
+        source: "~/.config/vim/vimrc".to_string(),
+        target: "~/.config/nvim/init.vim".to_string(),
+        dot_file_type: DotFileType::LINK,
+        mode: None,
+      },
+      DotFile {
+        source: source("dot_gitconfig.tmpl"),
+        target: "~/.gitconfig".to_string(),
+        dot_file_type: DotFileType::TEMPLATE,
+        mode: None,
+      },
+      DotFile {
+        source: source("private_dot_ssh/private_config"),
+        target: "~/.ssh/config".to_string(),
+        dot_file_type: DotFileType::COPY,
+        mode: Some(0o600),
+      },
+    ]);
+    assert_that(&import.problems).is_equal_to(vec![
+      ".chezmoiignore: template line `{{ if ne .chezmoi.os \"darwin\" }}` is not evaluated".to_string(),
+      ".chezmoiignore: template line `{{ end }}` is not evaluated".to_string(),
+      "dot_zshrc.tmpl: template uses unsupported expressions: if eq .chezmoi.os \"darwin\", end".to_string(),
+      "private_dot_ssh: directory permissions are not supported".to_string(),
+      "run_once_install.sh: scripts, modify_ and remove_ files are not supported".to_string(),
+    ]);
+    fs::remove_dir_all(&root).unwrap();
+  }
+}
@@ -1,7 +1,37 @@
+use crate::marked::{self, Node, Value};
 use crate::model::*;
 use slog::Logger;
 use slog::{info, o, warn};
-use yaml_rust::Yaml;
+use yaml_rust::scanner::ScanError;
+use yaml_rust::{Yaml, YamlLoader};
+
+/// Loads the configuration. yaml-rust reads an unquoted mode like `0o644` as
+/// the number 420, which would then be read by its digits, so modes that are
+/// not plain digits keep the text they were written as.
+pub fn load(text: &str) -> Result<Vec<Yaml>, ScanError> {
+  let mut documents = YamlLoader::load_from_str(text)?;
+  let marked = marked::load(text)?;
+  let marked_files = marked.first().and_then(|document| document.get("files")).map(|files| &files.value);
+  let files = match documents.first_mut() {
+    Some(Yaml::Hash(root)) => root.get_mut(&Yaml::String("files".to_string())),
+    _ => None,
+  };
+  if let (Some(Yaml::Hash(files)), Some(Value::Mapping(entries))) = (files, marked_files) {
+    for (target, settings) in entries {
+      let (target, mode) = match (target.as_str(), settings.get("mode").and_then(Node::as_str)) {
+        (Some(target), Some(mode)) => (target, mode),
+        _ => continue,
+      };
+      if let Some(Yaml::Hash(settings)) = files.get_mut(&Yaml::String(target.to_string())) {
+        match settings.get_mut(&Yaml::String("mode".to_string())) {
+          Some(value) if *value == Yaml::from_str(mode) => *value = Yaml::String(mode.to_string()),
+          _ => {}
+        }
+      }
+    }
+  }
+  Ok(documents)
+}
 
 pub fn parse_dot_files(log: &Logger, dot_files: &Yaml) -> Vec<DotFile> {
   let mut parsed_dot_files = Vec::new();
@@ -13,6 +43,7 @@ pub fn parse_dot_files(log: &Logger, dot_files: &Yaml) -> Vec<DotFile> {
           source: source.to_string(),
           target: target.to_string(),
           dot_file_type: DotFileType::LINK,
+          mode: None,
         }),
         (Yaml::String(target), Yaml::Hash(settings)) => {
           parsed_dot_files.push(dot_file_from_settings(&log.new(o!("target" => target.clone())), &target, &settings))
@@ -31,23 +62,37 @@ fn dot_file_from_settings(log: &Logger, target: &str, settings: &yaml_rust::yaml
     source: "<todo>".to_string(),
     target: target.to_string(),
     dot_file_type: DotFileType::LINK,
+    mode: None,
   };
   for (key, value) in settings.clone() {
-    if let (Yaml::String(setting_key), Yaml::String(setting_value)) = (key, value) {
-      match setting_key.as_ref() {
+    match (key, value) {
+      (Yaml::String(setting_key), Yaml::String(setting_value)) => match setting_key.as_ref() {
         "src" => dot_file.source = setting_value.to_string(),
         "type" => dot_file.dot_file_type = dot_file_type_from_string(log, &setting_value),
+        "mode" => dot_file.mode = mode_from_string(log, &setting_value),
         _ => {}
-      }
+      },
+      (Yaml::String(setting_key), Yaml::Integer(setting_value)) if setting_key == "mode" => dot_file.mode = mode_from_string(log, &setting_value.to_string()),
+      _ => {}
     }
   }
   dot_file
 }
 
+fn mode_from_string(log: &Logger, s: &str) -> Option<u32> {
+  let mode = parse_mode(s);
+  if mode.is_none() {
+    warn!(log, "could not parse mode. ignoring it."; "mode" => s);
+  }
+  mode
+}
+
 fn dot_file_type_from_string(log: &Logger, s: &str) -> DotFileType {
   match s.to_lowercase().as_ref() {
     "copy" => DotFileType::COPY,
     "link" => DotFileType::LINK,
+    "template" => DotFileType::TEMPLATE,
+    "encrypted" => DotFileType::ENCRYPTED,
     x => {
       warn!(log, "could not parse file type. fallback to link."; "file_type" => x);
       DotFileType::LINK
@@ -73,6 +118,10 @@ files:
     ~/.vimrc:
         src: vimrc
         type: link
+    ~/.ssh/config:
+        src: ssh/config
+        type: template
+        mode: 0600
 ";
     let yaml_documents = YamlLoader::load_from_str(s).unwrap();
     let yaml_config = &yaml_documents[0];
@@ -80,22 +129,50 @@ files:
     let logger = a_logger();
     let parsed_dot_files: Vec<DotFile> = parse_dot_files(&logger, dot_files);
 
-    assert_that(&parsed_dot_files).has_length(3);
+    assert_that(&parsed_dot_files).has_length(4);
     assert_that(&parsed_dot_files).contains(&DotFile {
       source: "tpm".to_string(),
       target: "~/.tmux/plugins/tpm".to_string(),
       dot_file_type: DotFileType::LINK,
+      mode: None,
     });
     assert_that(&parsed_dot_files).contains(&DotFile {
       source: "tmux.conf".to_string(),
       target: "~/.tmux.conf".to_string(),
       dot_file_type: DotFileType::COPY,
+      mode: None,
     });
     assert_that(&parsed_dot_files).contains(&DotFile {
       source: "vimrc".to_string(),
       target: "~/.vimrc".to_string(),
       dot_file_type: DotFileType::LINK,
+      mode: None,
     });
+    assert_that(&parsed_dot_files).contains(&DotFile {
+      source: "ssh/config".to_string(),
+      target: "~/.ssh/config".to_string(),
+      dot_file_type: DotFileType::TEMPLATE,
+      mode: Some(0o600),
+    });
+  }
+
+  #[test]
+  fn test_load_keeps_non_decimal_modes() {
+    let s = "
+files:
+    ~/.ssh/config:
+        src: ssh/config
+        type: copy
+        mode: 0o644
+    ~/bin/tool:
+        src: tool
+        type: copy
+        mode: 0x1ed
+";
+    let yaml_documents = load(s).unwrap();
+    let parsed_dot_files = parse_dot_files(&a_logger(), &yaml_documents[0]["files"]);
+    assert_that(&parsed_dot_files[0].mode).is_equal_to(Some(0o644));
+    assert_that(&parsed_dot_files[1].mode).is_equal_to(None);
   }
 
   fn a_logger() -> Logger {
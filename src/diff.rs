@@ -0,0 +1,137 @@
+use crate::secret;
+
+/// Lines of unchanged context around every change.
+const CONTEXT: usize = 3;
+/// Above this many line pairs the files are not diffed, only reported.
+const MAX_CELLS: usize = 4_000_000;
+
+enum Edit {
+  Keep(usize, usize),
+  Delete(usize),
+  Insert(usize),
+}
+
+/// A unified diff of two texts, empty if they are equal. Secrets resolved so
+/// far are masked.
+pub fn unified(old_name: &str, old: &str, new_name: &str, new: &str) -> String {
+  if old == new {
+    return String::new();
+  }
+  let old_lines: Vec<&str> = old.lines().collect();
+  let new_lines: Vec<&str> = new.lines().collect();
+  let mut diff = format!("--- {}\n+++ {}\n", old_name, new_name);
+  if (old_lines.len() + 1) * (new_lines.len() + 1) > MAX_CELLS {
+    diff.push_str("Files are too large to diff\n");
+    return diff;
+  }
+  let edits = edits(&old_lines, &new_lines);
+  for (start, end) in hunks(&edits) {
+    let hunk = &edits[start..end];
+    let old_start = hunk.iter().find_map(|edit| match edit {
+      Edit::Keep(i, _) | Edit::Delete(i) => Some(*i),
+      Edit::Insert(_) => None,
+    });
+    let new_start = hunk.iter().find_map(|edit| match edit {
+      Edit::Keep(_, j) | Edit::Insert(j) => Some(*j),
+      Edit::Delete(_) => None,
+    });
+    let old_count = hunk.iter().filter(|edit| !matches!(edit, Edit::Insert(_))).count();
+    let new_count = hunk.iter().filter(|edit| !matches!(edit, Edit::Delete(_))).count();
+    diff.push_str(&format!(
+      "@@ -{} +{} @@\n",
+      range(old_start, old_count, &edits[..start], true),
+      range(new_start, new_count, &edits[..start], false)
+    ));
+    for edit in hunk {
+      match edit {
+        Edit::Keep(i, _) => diff.push_str(&format!(" {}\n", old_lines[*i])),
+        Edit::Delete(i) => diff.push_str(&format!("-{}\n", old_lines[*i])),
+        Edit::Insert(j) => diff.push_str(&format!("+{}\n", new_lines[*j])),
+      }
+    }
+  }
+  secret::mask(&diff)
+}
+
+/// Formats a hunk range like `diff -u`, an empty range starts at the line
+/// before it.
+fn range(start: Option<usize>, count: usize, before: &[Edit], old: bool) -> String {
+  let start = start.map(|start| start + 1).unwrap_or_else(|| {
+    before
+      .iter()
+      .filter(|edit| match edit {
+        Edit::Insert(_) => !old,
+        Edit::Delete(_) => old,
+        Edit::Keep(_, _) => true,
+      })
+      .count()
+  });
+  if count == 1 {
+    start.to_string()
+  } else {
+    format!("{},{}", start, count)
+  }
+}
+
+/// The shortest edit script from `old` to `new` based on the longest common
+/// subsequence of lines.
+fn edits(old: &[&str], new: &[&str]) -> Vec<Edit> {
+  let width = new.len() + 1;
+  let mut common = vec![0usize; (old.len() + 1) * width];
+  for i in (0..old.len()).rev() {
+    for j in (0..new.len()).rev() {
+      common[i * width + j] = if old[i] == new[j] {
+        common[(i + 1) * width + j + 1] + 1
+      } else {
+        common[(i + 1) * width + j].max(common[i * width + j + 1])
+      };
+    }
+  }
+  let mut edits = Vec::new();
+  let (mut i, mut j) = (0, 0);
+  while i < old.len() || j < new.len() {
+    if i < old.len() && j < new.len() && old[i] == new[j] {
+      edits.push(Edit::Keep(i, j));
+      i += 1;
+      j += 1;
+    } else if i < old.len() && (j == new.len() || common[(i + 1) * width + j] >= common[i * width + j + 1]) {
+      edits.push(Edit::Delete(i));
+      i += 1;
+    } else {
+      edits.push(Edit::Insert(j));
+      j += 1;
+    }
+  }
+  edits
+}
+
+/// Ranges of `edits` that contain changes with their surrounding context.
+/// Changes closer than twice the context share a hunk.
+fn hunks(edits: &[Edit]) -> Vec<(usize, usize)> {
+  let mut hunks: Vec<(usize, usize)> = Vec::new();
+  for (index, _) in edits.iter().enumerate().filter(|(_, edit)| !matches!(edit, Edit::Keep(_, _))) {
+    let start = index.saturating_sub(CONTEXT);
+    let end = (index + 1 + CONTEXT).min(edits.len());
+    match hunks.last_mut() {
+      Some(last) if start <= last.1 => last.1 = end,
+      _ => hunks.push((start, end)),
+    }
+  }
+  hunks
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[test]
+  fn test_unified() {
+    let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+    let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+    assert_that(&unified("old", old, "new", new))
+      .is_equal_to("--- old\n+++ new\n@@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n@@ -8,3 +8,4 @@\n h\n i\n j\n+k\n".to_string());
+    assert_that(&unified("old", "", "new", "x\n")).is_equal_to("--- old\n+++ new\n@@ -0,0 +1 @@\n+x\n".to_string());
+    assert_that(&unified("old", old, "new", old)).is_equal_to(String::new());
+  }
+}
@@ -0,0 +1,455 @@
+use crate::alternate;
+use crate::expand;
+use crate::files;
+use crate::model::{DotFile, DotFileType};
+use slog::Logger;
+use slog::{error, info, o, warn};
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use yaml_rust::yaml::Hash;
+use yaml_rust::{Yaml, YamlLoader};
+
+/// A dotbot directive in the order it appears in the configuration.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+  Link { dot_file: DotFile, relink: bool, force: bool },
+  Create(String),
+  Clean { path: String, force: bool, recursive: bool },
+  Shell(Shell),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Shell {
+  pub command: String,
+  pub description: Option<String>,
+  pub stdin: bool,
+  pub stdout: bool,
+  pub stderr: bool,
+  pub quiet: bool,
+}
+
+/// A dotbot configuration translated to dotcopter's model. Everything that
+/// could not be translated is reported in `problems`.
+pub struct DotbotConfig {
+  pub steps: Vec<Step>,
+  pub problems: Vec<String>,
+}
+
+impl DotbotConfig {
+  pub fn dot_files(&self) -> Vec<DotFile> {
+    self
+      .steps
+      .iter()
+      .filter_map(|step| match step {
+        Step::Link { dot_file, .. } => Some(DotFile {
+          source: dot_file.source.clone(),
+          target: dot_file.target.clone(),
+          dot_file_type: DotFileType::LINK,
+          mode: None,
+        }),
+        _ => None,
+      })
+      .collect()
+  }
+}
+
+#[derive(Clone, Default)]
+struct LinkDefaults {
+  relink: bool,
+  force: bool,
+}
+
+/// Dotbot configurations are a list of directives, dotcopter's are a mapping.
+pub fn is_dotbot(config: &str) -> bool {
+  matches!(YamlLoader::load_from_str(config).as_deref(), Ok([Yaml::Array(_), ..]))
+}
+
+pub fn parse(config: &str) -> Result<DotbotConfig, String> {
+  let documents = YamlLoader::load_from_str(config).map_err(|e| e.to_string())?;
+  let directives = match documents.first() {
+    Some(Yaml::Array(directives)) => directives,
+    None => {
+      return Ok(DotbotConfig {
+        steps: Vec::new(),
+        problems: Vec::new(),
+      })
+    }
+    Some(_) => return Err("a dotbot configuration must be a list of directives".to_string()),
+  };
+  let mut parsed = DotbotConfig {
+    steps: Vec::new(),
+    problems: Vec::new(),
+  };
+  let mut defaults = LinkDefaults::default();
+  for directive in directives {
+    let directive = match directive {
+      Yaml::Hash(directive) => directive,
+      other => {
+        parsed.problems.push(format!("expected a directive, found {:?}", other));
+        continue;
+      }
+    };
+    for (name, value) in directive {
+      match name.as_str() {
+        Some("defaults") => defaults = parse_defaults(value, &mut parsed.problems),
+        Some("link") => parse_links(value, &defaults, &mut parsed),
+        Some("create") => {
+          for (path, _) in entries(value) {
+            parsed.steps.push(Step::Create(path));
+          }
+        }
+        Some("clean") => {
+          for (path, options) in entries(value) {
+            parsed.steps.push(Step::Clean {
+              path,
+              force: flag(&options["force"], false),
+              recursive: flag(&options["recursive"], false),
+            });
+          }
+        }
+        Some("shell") => parse_shell(value, &mut parsed),
+        Some(other) => parsed.problems.push(format!("unsupported directive `{}`", other)),
+        None => parsed.problems.push(format!("expected a directive name, found {:?}", name)),
+      }
+    }
+  }
+  Ok(parsed)
+}
+
+/// Only the defaults of `link` are understood, the defaults of other
+/// directives are reported.
+fn parse_defaults(value: &Yaml, problems: &mut Vec<String>) -> LinkDefaults {
+  match value {
+    Yaml::Hash(defaults) => {
+      for key in defaults.keys() {
+        match key.as_str() {
+          Some("link") => {}
+          Some(key) => problems.push(format!("defaults: unsupported defaults for `{}`", key)),
+          None => problems.push(format!("defaults: unsupported defaults for {:?}", key)),
+        }
+      }
+    }
+    other => problems.push(format!("`defaults` must be a mapping, found {:?}", other)),
+  }
+  let link = &value["link"];
+  if let Yaml::Hash(options) = link {
+    report_unsupported(options, "defaults.link", problems);
+  }
+  LinkDefaults {
+    relink: flag(&link["relink"], false),
+    force: flag(&link["force"], false),
+  }
+}
+
+fn parse_links(value: &Yaml, defaults: &LinkDefaults, parsed: &mut DotbotConfig) {
+  let links = match value {
+    Yaml::Hash(links) => links,
+    _ => {
+      parsed.problems.push("`link` must be a mapping of targets".to_string());
+      return;
+    }
+  };
+  for (target, options) in links {
+    let target = match target.as_str() {
+      Some(target) => target.to_string(),
+      None => {
+        parsed.problems.push(format!("link target must be a string, found {:?}", target));
+        continue;
+      }
+    };
+    let (source, relink, force) = match options {
+      Yaml::String(source) => (Some(source.clone()), defaults.relink, defaults.force),
+      Yaml::Null => (None, defaults.relink, defaults.force),
+      Yaml::Hash(settings) => {
+        report_unsupported(settings, &target, &mut parsed.problems);
+        (
+          options["path"].as_str().map(|path| path.to_string()),
+          flag(&options["relink"], defaults.relink),
+          flag(&options["force"], defaults.force),
+        )
+      }
+      other => {
+        parsed.problems.push(format!("{}: unsupported link value {:?}", target, other));
+        continue;
+      }
+    };
+    let source = source.unwrap_or_else(|| inferred_source(&target));
+    parsed.steps.push(Step::Link {
+      dot_file: DotFile {
+        source,
+        target,
+        dot_file_type: DotFileType::LINK,
+        mode: None,
+      },
+      relink,
+      force,
+    });
+  }
+}
+
+fn parse_shell(value: &Yaml, parsed: &mut DotbotConfig) {
+  let commands = match value {
+    Yaml::Array(commands) => commands,
+    _ => {
+      parsed.problems.push("`shell` must be a list of commands".to_string());
+      return;
+    }
+  };
+  for command in commands {
+    let shell = match command {
+      Yaml::String(command) => Some(Shell {
+        command: command.clone(),
+        description: None,
+        stdin: false,
+        stdout: false,
+        stderr: false,
+        quiet: false,
+      }),
+      Yaml::Array(parts) => parts.first().and_then(Yaml::as_str).map(|command| Shell {
+        command: command.to_string(),
+        description: parts.get(1).and_then(Yaml::as_str).map(|description| description.to_string()),
+        stdin: false,
+        stdout: false,
+        stderr: false,
+        quiet: false,
+      }),
+      Yaml::Hash(_) => command["command"].as_str().map(|shell_command| Shell {
+        command: shell_command.to_string(),
+        description: command["description"].as_str().map(|description| description.to_string()),
+        stdin: flag(&command["stdin"], false),
+        stdout: flag(&command["stdout"], false),
+        stderr: flag(&command["stderr"], false),
+        quiet: flag(&command["quiet"], false),
+      }),
+      _ => None,
+    };
+    match shell {
+      Some(shell) => parsed.steps.push(Step::Shell(shell)),
+      None => parsed.problems.push(format!("unsupported shell command {:?}", command)),
+    }
+  }
+}
+
+/// Link options dotcopter always applies (`create`) or understands, everything
+/// else is reported.
+fn report_unsupported(settings: &Hash, context: &str, problems: &mut Vec<String>) {
+  for (key, value) in settings {
+    match key.as_str() {
+      Some("create") if !flag(value, true) => problems.push(format!("{}: `create: false` is not supported, parent directories are always created", context)),
+      Some("path") | Some("relink") | Some("force") | Some("create") => {}
+      Some(key) => problems.push(format!("{}: unsupported link option `{}`", context, key)),
+      None => problems.push(format!("{}: unsupported link option {:?}", context, key)),
+    }
+  }
+}
+
+/// Paths of a `create` or `clean` directive, which are either a list or a
+/// mapping of paths to options.
+fn entries(value: &Yaml) -> Vec<(String, Yaml)> {
+  match value {
+    Yaml::Array(paths) => paths
+      .iter()
+      .filter_map(|path| path.as_str().map(|path| (path.to_string(), Yaml::Null)))
+      .collect(),
+    Yaml::Hash(paths) => paths
+      .iter()
+      .filter_map(|(path, options)| path.as_str().map(|path| (path.to_string(), options.clone())))
+      .collect(),
+    _ => Vec::new(),
+  }
+}
+
+fn flag(value: &Yaml, default: bool) -> bool {
+  value.as_bool().unwrap_or(default)
+}
+
+/// Dotbot links `~/.vimrc` to `vimrc` if no path is given.
+fn inferred_source(target: &str) -> String {
+  let name = Path::new(target)
+    .file_name()
+    .map(|name| name.to_string_lossy().into_owned())
+    .unwrap_or_else(|| target.to_string());
+  name.strip_prefix('.').map(|name| name.to_string()).unwrap_or(name)
+}
+
+/// Runs the steps in order like dotbot would.
+pub fn apply(log: &Logger, steps: &[Step], force: bool) {
+  let facts = alternate::Facts::current();
+  for step in steps {
+    match step {
+      Step::Link {
+        dot_file,
+        relink,
+        force: link_force,
+      } => {
+        let is_link = expand::expand_target(&dot_file.target)
+          .map(|target| {
+            Path::new(&target)
+              .symlink_metadata()
+              .map(|metadata| metadata.file_type().is_symlink())
+              .unwrap_or(false)
+          })
+          .unwrap_or(false);
+        files::process_dot_file(log, dot_file, &Yaml::BadValue, &facts, force || *link_force || (*relink && is_link));
+      }
+      Step::Create(path) => create(&log.new(o!("create" => path.clone())), path),
+      Step::Clean { path, force, recursive } => clean(&log.new(o!("clean" => path.clone())), path, *force, *recursive),
+      Step::Shell(shell) => run_shell(log, shell),
+    }
+  }
+}
+
+fn create(log: &Logger, path: &str) {
+  match expand::expand_target(path)
+    .map_err(|e| e.to_string())
+    .and_then(|path| fs::create_dir_all(path).map_err(|e| e.to_string()))
+  {
+    Ok(_) => info!(log, "Created directory"),
+    Err(e) => error!(log, "Failed to create directory"; "error" => e),
+  }
+}
+
+/// Removes dead links in `path` that point into the repository, or all dead
+/// links if `force` is set.
+fn clean(log: &Logger, path: &str, force: bool, recursive: bool) {
+  let (dir, repo) = match (expand::expand_target(path), std::env::current_dir()) {
+    (Ok(dir), Ok(repo)) => (dir, repo),
+    (Err(e), _) => {
+      error!(log, "Failed to expand path"; "error" => e.to_string());
+      return;
+    }
+    (_, Err(e)) => {
+      error!(log, "Failed to determine current directory"; "error" => e.to_string());
+      return;
+    }
+  };
+  let mut pending = vec![Path::new(&dir).to_path_buf()];
+  while let Some(dir) = pending.pop() {
+    let entries = match fs::read_dir(&dir) {
+      Ok(entries) => entries,
+      Err(e) => {
+        warn!(log, "Failed to read directory"; "dir" => format!("{}", dir.display()), "error" => e.to_string());
+        continue;
+      }
+    };
+    for entry in entries.flatten() {
+      let entry = entry.path();
+      let metadata = match entry.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(_) => continue,
+      };
+      if metadata.is_dir() && recursive {
+        pending.push(entry);
+      } else if metadata.file_type().is_symlink() && !entry.exists() {
+        let points_into_repo = fs::read_link(&entry)
+          .map(|destination| expand::normalize(&dir.join(destination)).starts_with(&repo))
+          .unwrap_or(false);
+        if force || points_into_repo {
+          match fs::remove_file(&entry) {
+            Ok(_) => info!(log, "Removed dead link"; "link" => format!("{}", entry.display())),
+            Err(e) => error!(log, "Failed to remove dead link"; "link" => format!("{}", entry.display()), "error" => e.to_string()),
+          }
+        }
+      }
+    }
+  }
+}
+
+fn run_shell(log: &Logger, shell: &Shell) {
+  let log = log.new(o!("command" => shell.command.clone()));
+  if !shell.quiet {
+    info!(log, "{}", shell.description.as_deref().unwrap_or("Running command"));
+  }
+  let stdio = |inherit: bool| if inherit { Stdio::inherit() } else { Stdio::null() };
+  let status = Command::new("sh")
+    .arg("-c")
+    .arg(&shell.command)
+    .stdin(stdio(shell.stdin))
+    .stdout(stdio(shell.stdout))
+    .stderr(stdio(shell.stderr))
+    .status();
+  match status {
+    Ok(status) if status.success() => {}
+    Ok(status) => error!(log, "Command failed"; "status" => status.to_string()),
+    Err(e) => error!(log, "Failed to run command"; "error" => e.to_string()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[test]
+  fn test_parse() {
+    let config = "
+- defaults:
+    link:
+      relink: true
+      create: true
+    shell:
+      stdout: true
+- clean: ['~']
+- create:
+    - ~/downloads
+- link:
+    ~/.vimrc:
+    ~/.zshrc: zsh/zshrc
+    ~/.bashrc:
+      path: bash/bashrc
+      create: false
+    ~/.config/git:
+      path: git
+      force: true
+      glob: true
+- shell:
+    - git submodule update --init
+    - [./install.sh, Installing]
+- brew: [git]
+";
+    assert_that(&is_dotbot(config)).is_true();
+    assert_that(&is_dotbot("files:\n  ~/.vimrc: vimrc\n")).is_false();
+    let parsed = parse(config).unwrap();
+    let link = |source: &str, target: &str, relink: bool, force: bool| Step::Link {
+      dot_file: DotFile {
+        source: source.to_string(),
+        target: target.to_string(),
+        dot_file_type: DotFileType::LINK,
+        mode: None,
+      },
+      relink,
+      force,
+    };
+    let shell = |command: &str, description: Option<&str>| {
+      Step::Shell(Shell {
+        command: command.to_string(),
+        description: description.map(|description| description.to_string()),
+        stdin: false,
+        stdout: false,
+        stderr: false,
+        quiet: false,
+      })
+    };
+    assert_that(&parsed.steps).is_equal_to(vec![
+      Step::Clean {
+        path: "~".to_string(),
+        force: false,
+        recursive: false,
+      },
+      Step::Create("~/downloads".to_string()),
+      link("vimrc", "~/.vimrc", true, false),
+      link("zsh/zshrc", "~/.zshrc", true, false),
+      link("bash/bashrc", "~/.bashrc", true, false),
+      link("git", "~/.config/git", true, true),
+      shell("git submodule update --init", None),
+      shell("./install.sh", Some("Installing")),
+    ]);
+    assert_that(&parsed.problems).is_equal_to(vec![
+      "defaults: unsupported defaults for `shell`".to_string(),
+      "~/.bashrc: `create: false` is not supported, parent directories are always created".to_string(),
+      "~/.config/git: unsupported link option `glob`".to_string(),
+      "unsupported directive `brew`".to_string(),
+    ]);
+  }
+}
@@ -0,0 +1,305 @@
+use crate::adopt;
+use crate::errors::DotcopterError;
+use crate::expand;
+use crate::import;
+use crate::model::{DotFile, DotFileType};
+use crate::mutate;
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::scrypt::{scrypt, ScryptParams};
+use slog::info;
+use slog::Logger;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Start of every encrypted file, followed by the scrypt cost, salt, nonce,
+/// ciphertext and tag.
+const MAGIC: &[u8] = b"DOTCOPTER-AEAD1\n";
+const LOG_N: u8 = 15;
+/// Lowest scrypt cost accepted when decrypting.
+pub const MIN_LOG_N: u8 = 10;
+/// Highest scrypt cost accepted when decrypting, 2^20 rounds need 1 GiB.
+const MAX_LOG_N: u8 = 20;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 8;
+const TAG_LENGTH: usize = 16;
+const HEADER_LENGTH: usize = MAGIC.len() + 1 + SALT_LENGTH + NONCE_LENGTH;
+
+const KEY_FILE_VARIABLE: &str = "DOTCOPTER_KEY_FILE";
+const PASSPHRASE_VARIABLE: &str = "DOTCOPTER_PASSPHRASE";
+
+/// Set by `--key-file`.
+static KEY_FILE: OnceLock<PathBuf> = OnceLock::new();
+/// The passphrase or key file content, asked for at most once per run.
+static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Reads the key from `path` instead of asking for a passphrase. Only the
+/// first call has an effect.
+pub fn set_key_file(path: &str) {
+  let _ = KEY_FILE.set(PathBuf::from(path));
+}
+
+/// The content of the key file from `--key-file` or `DOTCOPTER_KEY_FILE`,
+/// otherwise the passphrase from `DOTCOPTER_PASSPHRASE` or the terminal.
+/// `confirm` asks twice for a new passphrase.
+fn secret(confirm: bool) -> Result<&'static [u8], DotcopterError> {
+  if let Some(secret) = SECRET.get() {
+    return Ok(secret);
+  }
+  let key_file = KEY_FILE.get().cloned().or_else(|| env::var_os(KEY_FILE_VARIABLE).map(PathBuf::from));
+  let secret = if let Some(key_file) = key_file {
+    fs::read(&key_file).map_err(|e| DotcopterError::Message(format!("failed to read key file {}: {}", key_file.display(), e)))?
+  } else if let Ok(passphrase) = env::var(PASSPHRASE_VARIABLE) {
+    passphrase.into_bytes()
+  } else {
+    let passphrase = prompt("Passphrase: ")?;
+    if confirm && prompt("Repeat passphrase: ")? != passphrase {
+      return Err(DotcopterError::Message("passphrases do not match".to_string()));
+    }
+    passphrase.into_bytes()
+  };
+  if secret.is_empty() {
+    return Err(DotcopterError::Message("the passphrase or key file is empty".to_string()));
+  }
+  Ok(SECRET.get_or_init(|| secret))
+}
+
+/// Reads a line from the terminal without echoing it.
+fn prompt(message: &str) -> Result<String, DotcopterError> {
+  let tty = fs::OpenOptions::new().read(true).write(true).open("/dev/tty").map_err(|_| {
+    DotcopterError::Message(format!(
+      "no terminal to ask for the passphrase, set {} or {}",
+      PASSPHRASE_VARIABLE, KEY_FILE_VARIABLE
+    ))
+  })?;
+  let fd = tty.as_raw_fd();
+  let mut original: libc::termios = unsafe { mem::zeroed() };
+  if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+    return Err(io::Error::last_os_error().into());
+  }
+  let mut silent = original;
+  silent.c_lflag &= !libc::ECHO;
+  unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) };
+  let mut line = String::new();
+  let result = (&tty).write_all(message.as_bytes()).and_then(|_| io::BufReader::new(&tty).read_line(&mut line));
+  unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+  let _ = (&tty).write_all(b"\n");
+  result?;
+  Ok(line.trim_end_matches(&['\n', '\r'][..]).to_string())
+}
+
+pub fn encrypt(plaintext: &[u8]) -> Result<Vec<u8>, DotcopterError> {
+  encrypt_with(plaintext, secret(true)?, LOG_N)
+}
+
+pub fn decrypt(encrypted: &[u8]) -> Result<Vec<u8>, DotcopterError> {
+  if !is_encrypted(encrypted) {
+    return Err(DotcopterError::Message("source is not encrypted by dotcopter".to_string()));
+  }
+  decrypt_with(encrypted, secret(false)?)
+}
+
+pub fn is_encrypted(content: &[u8]) -> bool {
+  content.starts_with(MAGIC)
+}
+
+/// Encrypts `file` into the repository and adds an encrypted entry with
+/// `file` as target. If an encrypted entry for `file` exists its source is
+/// encrypted again instead, giving another repository path for it is refused.
+/// The plaintext file is left in place. If the
+/// configuration has encrypted entries the passphrase has to decrypt one of
+/// them, so all sources share the same passphrase.
+pub fn encrypt_file(log: &Logger, config: &str, config_file: &str, dot_files: &[DotFile], file: &str, repo_path: Option<&str>) -> Result<(), DotcopterError> {
+  let target = PathBuf::from(expand::expand_path(file)?);
+  let plaintext = fs::read(&target).map_err(|e| DotcopterError::Message(format!("failed to read {}: {}", target.display(), e)))?;
+  let target_string = target
+    .to_str()
+    .ok_or_else(|| DotcopterError::Message("target is not valid unicode".to_string()))?;
+  let existing = dot_files
+    .iter()
+    .find(|dot_file| dot_file.dot_file_type == DotFileType::ENCRYPTED && expand::same_path(&dot_file.target, target_string));
+  if let (Some(dot_file), Some(repo_path)) = (existing, repo_path) {
+    return Err(DotcopterError::Message(format!(
+      "{} already has an encrypted entry with the source {}, leave out {} to encrypt it again",
+      dot_file.target, dot_file.source, repo_path
+    )));
+  }
+  verify_secret(dot_files, existing)?;
+  if let Some(dot_file) = existing {
+    let source = PathBuf::from(expand::expand_path(&dot_file.source)?);
+    let encrypted = encrypt(&plaintext)?;
+    let temporary = temporary_path(&source);
+    fs::write(&temporary, encrypted).and_then(|_| fs::rename(&temporary, &source))?;
+    info!(log, "Encrypted file again"; "source" => &dot_file.source);
+    return Ok(());
+  }
+
+  let source = match repo_path {
+    Some(repo_path) => PathBuf::from(repo_path),
+    None => {
+      let mut name = adopt::repo_name(&target, expand::home_dir().as_deref().map(Path::new)).into_os_string();
+      name.push(".enc");
+      PathBuf::from(name)
+    }
+  };
+  if source.symlink_metadata().is_ok() {
+    return Err(DotcopterError::Message(format!("{} already exists in the repository", source.display())));
+  }
+  let dot_file = DotFile {
+    source: source
+      .to_str()
+      .ok_or_else(|| DotcopterError::Message("repository path is not valid unicode".to_string()))?
+      .to_string(),
+    target: import::replace_home_with_tilde(log, target_string)?,
+    dot_file_type: DotFileType::ENCRYPTED,
+    mode: None,
+  };
+  let source_string = dot_file.source.clone();
+  let new_config = mutate::add_dotfiles_to_config(log, config, &[dot_file])?;
+  let encrypted = encrypt(&plaintext)?;
+  if let Some(parent) = source.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+    fs::create_dir_all(parent)?;
+  }
+  fs::write(&source, encrypted)?;
+  info!(log, "Encrypted file into repository"; "to" => &source_string);
+
+  if let Err(e) = fs::write(config_file, new_config) {
+    let _ = fs::remove_file(&source);
+    return Err(e.into());
+  }
+  info!(log, "Added entry to configuration");
+  Ok(())
+}
+
+/// Decrypts the source of `existing`, or of the first encrypted entry with a
+/// readable source, with the passphrase that is going to be used.
+fn verify_secret(dot_files: &[DotFile], existing: Option<&DotFile>) -> Result<(), DotcopterError> {
+  let encrypted_source = existing
+    .into_iter()
+    .chain(dot_files.iter().filter(|dot_file| dot_file.dot_file_type == DotFileType::ENCRYPTED))
+    .filter_map(|dot_file| expand::expand_path(&dot_file.source).ok())
+    .find_map(|source| fs::read(&source).ok().filter(|content| is_encrypted(content)).map(|content| (source, content)));
+  if let Some((source, content)) = encrypted_source {
+    decrypt(&content).map_err(|e| {
+      DotcopterError::Message(format!(
+        "the passphrase has to be the one of the other encrypted files, failed to decrypt {}: {}",
+        source, e
+      ))
+    })?;
+  }
+  Ok(())
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+  let mut temporary = path.as_os_str().to_os_string();
+  temporary.push(".dotcopter-tmp");
+  PathBuf::from(temporary)
+}
+
+pub fn encrypt_with(plaintext: &[u8], secret: &[u8], log_n: u8) -> Result<Vec<u8>, DotcopterError> {
+  let mut random = [0u8; SALT_LENGTH + NONCE_LENGTH];
+  fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+  let (salt, nonce) = random.split_at(SALT_LENGTH);
+  let mut encrypted = Vec::with_capacity(HEADER_LENGTH + plaintext.len() + TAG_LENGTH);
+  encrypted.extend_from_slice(MAGIC);
+  encrypted.push(log_n);
+  encrypted.extend_from_slice(salt);
+  encrypted.extend_from_slice(nonce);
+  let mut cipher = ChaCha20Poly1305::new(&derive_key(secret, salt, log_n), nonce, &encrypted);
+  let mut ciphertext = vec![0u8; plaintext.len()];
+  let mut tag = [0u8; TAG_LENGTH];
+  cipher.encrypt(plaintext, &mut ciphertext, &mut tag);
+  encrypted.extend_from_slice(&ciphertext);
+  encrypted.extend_from_slice(&tag);
+  Ok(encrypted)
+}
+
+fn decrypt_with(encrypted: &[u8], secret: &[u8]) -> Result<Vec<u8>, DotcopterError> {
+  if encrypted.len() < HEADER_LENGTH + TAG_LENGTH {
+    return Err(DotcopterError::Message("encrypted file is truncated".to_string()));
+  }
+  let (header, rest) = encrypted.split_at(HEADER_LENGTH);
+  let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LENGTH);
+  let log_n = header[MAGIC.len()];
+  if !(MIN_LOG_N..=MAX_LOG_N).contains(&log_n) {
+    return Err(DotcopterError::Message("encrypted file has an unsupported key cost".to_string()));
+  }
+  let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LENGTH];
+  let nonce = &header[MAGIC.len() + 1 + SALT_LENGTH..];
+  let mut cipher = ChaCha20Poly1305::new(&derive_key(secret, salt, log_n), nonce, header);
+  let mut plaintext = vec![0u8; ciphertext.len()];
+  if !cipher.decrypt(ciphertext, &mut plaintext, tag) {
+    return Err(DotcopterError::Message("wrong passphrase or key file, or the file was modified".to_string()));
+  }
+  Ok(plaintext)
+}
+
+fn derive_key(secret: &[u8], salt: &[u8], log_n: u8) -> [u8; 32] {
+  let mut key = [0u8; 32];
+  scrypt(secret, salt, &ScryptParams::new(log_n, 8, 1), &mut key);
+  key
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  fn a_logger() -> Logger {
+    use slog::o;
+    use slog::Drain;
+    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+    let drain = slog_term::FullFormat::new(plain).build().fuse();
+    Logger::root(drain, o!())
+  }
+
+  #[test]
+  fn test_encrypt_decrypt() {
+    let encrypted = encrypt_with(b"machine example.com password hunter2\n", b"secret", MIN_LOG_N).unwrap();
+    assert_that(&is_encrypted(&encrypted)).is_true();
+    assert_that(&encrypted.windows(7).any(|window| window == b"hunter2")).is_false();
+    assert_that(&decrypt_with(&encrypted, b"secret").unwrap()).is_equal_to(b"machine example.com password hunter2\n".to_vec());
+    assert_that(&decrypt_with(&encrypted, b"wrong").is_err()).is_true();
+
+    let mut tampered = encrypted.clone();
+    let last = tampered.len() - TAG_LENGTH - 1;
+    tampered[last] ^= 1;
+    assert_that(&decrypt_with(&tampered, b"secret").is_err()).is_true();
+    assert_that(&decrypt_with(&encrypted[..HEADER_LENGTH], b"secret").is_err()).is_true();
+    for log_n in [0, MIN_LOG_N - 1, MAX_LOG_N + 1] {
+      let mut cost = encrypted.clone();
+      cost[MAGIC.len()] = log_n;
+      assert_that(&decrypt_with(&cost, b"secret").unwrap_err().to_string()).contains("unsupported key cost");
+    }
+    assert_that(&(encrypt_with(b"x", b"secret", MIN_LOG_N).unwrap() != encrypt_with(b"x", b"secret", MIN_LOG_N).unwrap())).is_true();
+  }
+
+  #[test]
+  fn test_encrypt_file_refuses_second_entry() {
+    let log = a_logger();
+    let dir = env::temp_dir().join(format!("dotcopter-encrypt-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let netrc = dir.join("netrc");
+    fs::write(&netrc, "machine example.com password hunter2\n").unwrap();
+    let target = netrc.to_string_lossy().into_owned();
+    let dot_files = vec![DotFile {
+      source: "netrc.enc".to_string(),
+      target: target.clone(),
+      dot_file_type: DotFileType::ENCRYPTED,
+      mode: None,
+    }];
+    let config_file = dir.join("config.yml");
+    let config = format!("files:\n  {}:\n    src: netrc.enc\n    type: encrypted\n", target);
+    let result = encrypt_file(&log, &config, &config_file.to_string_lossy(), &dot_files, &target, Some("other.enc"));
+    assert_that(&result.unwrap_err().to_string()).contains("already has an encrypted entry");
+    assert_that(&config_file.exists()).is_false();
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -7,7 +7,10 @@ use std::path;
 pub enum DotcopterError {
   IO(io::Error),
   Regex(regex::Error),
+  Pattern(glob::PatternError),
   StripPrefix(path::StripPrefixError),
+  Expansion(String),
+  Message(String),
 }
 
 macro_rules! dotcopter_error_from {
@@ -25,7 +28,10 @@ impl fmt::Display for DotcopterError {
     match *self {
       DotcopterError::IO(ref err) => write!(f, "IO error: {}", err),
       DotcopterError::Regex(ref err) => write!(f, "Regex error: {}", err),
+      DotcopterError::Pattern(ref err) => write!(f, "Pattern error: {}", err),
       DotcopterError::StripPrefix(ref err) => write!(f, "Strip prefix error: {}", err),
+      DotcopterError::Expansion(ref message) => write!(f, "Expansion error: {}", message),
+      DotcopterError::Message(ref message) => write!(f, "{}", message),
     }
   }
 }
@@ -36,7 +42,10 @@ impl error::Error for DotcopterError {
     match *self {
       DotcopterError::IO(ref err) => err.description(),
       DotcopterError::Regex(ref err) => err.description(),
+      DotcopterError::Pattern(ref err) => err.msg,
       DotcopterError::StripPrefix(ref err) => err.description(),
+      DotcopterError::Expansion(ref message) => message,
+      DotcopterError::Message(ref message) => message,
     }
   }
 
@@ -44,11 +53,15 @@ impl error::Error for DotcopterError {
     match *self {
       DotcopterError::IO(ref err) => Some(err),
       DotcopterError::Regex(ref err) => Some(err),
+      DotcopterError::Pattern(ref err) => Some(err),
       DotcopterError::StripPrefix(ref err) => Some(err),
+      DotcopterError::Expansion(_) => None,
+      DotcopterError::Message(_) => None,
     }
   }
 }
 
 dotcopter_error_from!(io::Error, IO);
 dotcopter_error_from!(regex::Error, Regex);
+dotcopter_error_from!(glob::PatternError, Pattern);
 dotcopter_error_from!(path::StripPrefixError, StripPrefix);
@@ -0,0 +1,374 @@
+use crate::errors::DotcopterError;
+use std::env;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+use std::ptr;
+use std::sync::OnceLock;
+
+const XDG_DEFAULTS: [(&str, &str); 5] = [
+  ("XDG_CONFIG_HOME", ".config"),
+  ("XDG_DATA_HOME", ".local/share"),
+  ("XDG_STATE_HOME", ".local/state"),
+  ("XDG_CACHE_HOME", ".cache"),
+  ("XDG_BIN_HOME", ".local/bin"),
+];
+
+/// Set by `--home`, replaces the home directory of the current user.
+static HOME: OnceLock<String> = OnceLock::new();
+/// Set by `--root`, prefixes every target like `DESTDIR`.
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Uses `home` instead of the current user's home directory for `~`, `$HOME`
+/// and the XDG defaults. Only the first call has an effect.
+pub fn set_home(home: &str) {
+  let _ = HOME.set(home.to_string());
+}
+
+/// Places every target below `root`. Only the first call has an effect.
+pub fn set_root(root: &str) {
+  let _ = ROOT.set(PathBuf::from(root));
+}
+
+pub fn expand_path(path: &str) -> Result<String, DotcopterError> {
+  match HOME.get() {
+    Some(home) => expand_path_for_home(path, home),
+    None => {
+      let home = home_dir().ok_or_else(|| DotcopterError::Expansion("home dir not set".to_string()))?;
+      expand_with(path, &home, &lookup_env)
+    }
+  }
+}
+
+/// Expands `path` with the current user's home directory even if `--home` is
+/// set, for sources that are read on this host while targets are not.
+pub fn expand_path_on_host(path: &str) -> Result<String, DotcopterError> {
+  let home = dirs::home_dir()
+    .and_then(|home| home.into_os_string().into_string().ok())
+    .ok_or_else(|| DotcopterError::Expansion("home dir not set".to_string()))?;
+  expand_with(path, &home, &lookup_env)
+}
+
+/// Expands a target, which is placed below the `--root` directory if one is
+/// set.
+pub fn expand_target(path: &str) -> Result<String, DotcopterError> {
+  let expanded = expand_path(path)?;
+  match ROOT.get() {
+    Some(root) => {
+      let current_dir = env::current_dir()?;
+      Ok(under_root(&current_dir.join(root), &current_dir.join(expanded)).to_string_lossy().into_owned())
+    }
+    None => Ok(expanded),
+  }
+}
+
+pub(crate) fn under_root(root: &Path, path: &Path) -> PathBuf {
+  root.join(normalize(path).strip_prefix("/").unwrap_or(path))
+}
+
+/// Expands `path` for a home directory that is not the current one. `$HOME`
+/// and the XDG base directories point into `home`, other variables come from
+/// the environment.
+pub fn expand_path_for_home(path: &str, home: &str) -> Result<String, DotcopterError> {
+  expand_with(path, home, &|name| {
+    if name == "HOME" || name.starts_with("XDG_") {
+      None
+    } else {
+      lookup_env(name)
+    }
+  })
+}
+
+/// Whether two configured paths name the same file once expanded, e.g. `~/x`
+/// and `$HOME/x`.
+pub fn same_path(a: &str, b: &str) -> bool {
+  match (expand_path(a), expand_path(b)) {
+    (Ok(a), Ok(b)) => normalize(Path::new(&a)) == normalize(Path::new(&b)),
+    _ => a == b,
+  }
+}
+
+/// Removes `.` and resolves `..` components without touching the file system.
+pub fn normalize(path: &Path) -> PathBuf {
+  let mut normalized = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::CurDir => {}
+      Component::ParentDir => {
+        normalized.pop();
+      }
+      other => normalized.push(other.as_os_str()),
+    }
+  }
+  normalized
+}
+
+pub fn home_dir() -> Option<String> {
+  if let Some(home) = HOME.get() {
+    return Some(home.clone());
+  }
+  dirs::home_dir().and_then(|home| home.into_os_string().into_string().ok())
+}
+
+fn lookup_env(name: &str) -> Option<String> {
+  env::var(name).ok()
+}
+
+fn expand_with(path: &str, home: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, DotcopterError> {
+  let with_variables = expand_variables(path, home, lookup)?;
+  expand_tilde(&with_variables, home)
+}
+
+fn expand_tilde(path: &str, home: &str) -> Result<String, DotcopterError> {
+  let rest = match path.strip_prefix('~') {
+    Some(rest) => rest,
+    None => return Ok(path.to_string()),
+  };
+  let (user, remainder) = match rest.find('/') {
+    Some(index) => rest.split_at(index),
+    None => (rest, ""),
+  };
+  let user_home = if user.is_empty() {
+    home.to_string()
+  } else {
+    user_home_dir(user).ok_or_else(|| DotcopterError::Expansion(format!("unknown user '{}'", user)))?
+  };
+  Ok(format!("{}{}", user_home.trim_end_matches('/'), remainder))
+}
+
+fn expand_variables(path: &str, home: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, DotcopterError> {
+  let mut expanded = String::new();
+  let mut rest = path;
+  while let Some(index) = rest.find('$') {
+    expanded.push_str(&rest[..index]);
+    rest = &rest[index + 1..];
+    if let Some(braced) = rest.strip_prefix('{') {
+      let end = closing_brace(braced).ok_or_else(|| DotcopterError::Expansion(format!("unterminated variable in '{}'", path)))?;
+      let expression = &braced[..end];
+      rest = &braced[end + 1..];
+      let (name, default) = match expression.find(":-") {
+        Some(split) => (&expression[..split], Some(&expression[split + 2..])),
+        None => (expression, None),
+      };
+      if !is_variable_name(name) {
+        return Err(DotcopterError::Expansion(format!("invalid variable name '{}'", name)));
+      }
+      match (lookup_variable(name, home, lookup), default) {
+        (Some(value), _) => expanded.push_str(&value),
+        (None, Some(default)) => expanded.push_str(&expand_with(default, home, lookup)?),
+        (None, None) => return Err(DotcopterError::Expansion(format!("variable '{}' is not set", name))),
+      }
+    } else {
+      let length = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+      let name = &rest[..length];
+      if is_variable_name(name) {
+        let value = lookup_variable(name, home, lookup).ok_or_else(|| DotcopterError::Expansion(format!("variable '{}' is not set", name)))?;
+        expanded.push_str(&value);
+        rest = &rest[length..];
+      } else {
+        expanded.push('$');
+      }
+    }
+  }
+  expanded.push_str(rest);
+  Ok(expanded)
+}
+
+/// Translates a configured path into a double quoted shell word that expands
+/// the same way when a shell evaluates it, e.g. `~/x` becomes `"$HOME/x"` and
+/// `$XDG_CONFIG_HOME` keeps its default. Only `~user` is expanded right away.
+pub fn shell_word(path: &str) -> Result<String, DotcopterError> {
+  Ok(format!("\"{}\"", shell_word_body(path)?))
+}
+
+fn shell_word_body(path: &str) -> Result<String, DotcopterError> {
+  let mut word = String::new();
+  let mut rest = path;
+  if let Some(after_tilde) = path.strip_prefix('~') {
+    let (user, remainder) = match after_tilde.find('/') {
+      Some(index) => after_tilde.split_at(index),
+      None => (after_tilde, ""),
+    };
+    if user.is_empty() {
+      word.push_str("$HOME");
+    } else {
+      let user_home = user_home_dir(user).ok_or_else(|| DotcopterError::Expansion(format!("unknown user '{}'", user)))?;
+      word.push_str(&escape_double_quoted(user_home.trim_end_matches('/')));
+    }
+    rest = remainder;
+  }
+  while let Some(index) = rest.find('$') {
+    word.push_str(&escape_double_quoted(&rest[..index]));
+    rest = &rest[index + 1..];
+    let (name, default) = if let Some(braced) = rest.strip_prefix('{') {
+      let end = closing_brace(braced).ok_or_else(|| DotcopterError::Expansion(format!("unterminated variable in '{}'", path)))?;
+      let expression = &braced[..end];
+      rest = &braced[end + 1..];
+      match expression.find(":-") {
+        Some(split) => (&expression[..split], Some(shell_word_body(&expression[split + 2..])?)),
+        None => (expression, None),
+      }
+    } else {
+      let length = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+      let name = &rest[..length];
+      if !is_variable_name(name) {
+        word.push_str("\\$");
+        continue;
+      }
+      rest = &rest[length..];
+      (name, None)
+    };
+    if !is_variable_name(name) {
+      return Err(DotcopterError::Expansion(format!("invalid variable name '{}'", name)));
+    }
+    let default = default.or_else(|| {
+      XDG_DEFAULTS
+        .iter()
+        .find(|(xdg_name, _)| *xdg_name == name)
+        .map(|(_, relative)| format!("$HOME/{}", relative))
+    });
+    match default {
+      Some(default) => word.push_str(&format!("${{{}:-{}}}", name, default)),
+      None => word.push_str(&format!("${{{}}}", name)),
+    }
+  }
+  word.push_str(&escape_double_quoted(rest));
+  Ok(word)
+}
+
+fn escape_double_quoted(s: &str) -> String {
+  let mut escaped = String::new();
+  for c in s.chars() {
+    if matches!(c, '\\' | '"' | '`' | '$') {
+      escaped.push('\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+fn closing_brace(s: &str) -> Option<usize> {
+  let mut depth = 0;
+  for (index, c) in s.char_indices() {
+    match c {
+      '{' => depth += 1,
+      '}' if depth == 0 => return Some(index),
+      '}' => depth -= 1,
+      _ => {}
+    }
+  }
+  None
+}
+
+fn is_variable_name(name: &str) -> bool {
+  let mut chars = name.chars();
+  match chars.next() {
+    Some(first) if first.is_ascii_alphabetic() || first == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+    _ => false,
+  }
+}
+
+fn lookup_variable(name: &str, home: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+  let value = lookup(name).filter(|value| !value.is_empty());
+  if value.is_some() {
+    return value;
+  }
+  if name == "HOME" {
+    return Some(home.to_string());
+  }
+  XDG_DEFAULTS
+    .iter()
+    .find(|(xdg_name, _)| *xdg_name == name)
+    .map(|(_, relative)| format!("{}/{}", home.trim_end_matches('/'), relative))
+}
+
+fn user_home_dir(user: &str) -> Option<String> {
+  let name = CString::new(user).ok()?;
+  let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+  loop {
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut result: *mut libc::passwd = ptr::null_mut();
+    let rc = unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+    if rc == libc::ERANGE {
+      let new_length = buffer.len() * 2;
+      buffer.resize(new_length, 0);
+      continue;
+    }
+    if rc != 0 || result.is_null() || passwd.pw_dir.is_null() {
+      return None;
+    }
+    let dir = unsafe { CStr::from_ptr(passwd.pw_dir) };
+    return dir.to_str().ok().map(|dir| dir.to_string());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  fn lookup(name: &str) -> Option<String> {
+    match name {
+      "EDITOR" => Some("nvim".to_string()),
+      "EMPTY" => Some("".to_string()),
+      "XDG_DATA_HOME" => Some("/data".to_string()),
+      _ => None,
+    }
+  }
+
+  fn expand(path: &str) -> Result<String, DotcopterError> {
+    expand_with(path, "/home/blubb", &lookup)
+  }
+
+  /// Name and home directory of the user running the tests.
+  fn current_user() -> (String, String) {
+    let passwd = unsafe { libc::getpwuid(libc::getuid()) };
+    assert!(!passwd.is_null(), "the current user should have a passwd entry");
+    let field = |field: *const libc::c_char| unsafe { CStr::from_ptr(field) }.to_string_lossy().into_owned();
+    unsafe { (field((*passwd).pw_name), field((*passwd).pw_dir)) }
+  }
+
+  #[test]
+  fn test_expand_tilde() {
+    assert_that(&expand("~").unwrap()).is_equal_to("/home/blubb".to_string());
+    assert_that(&expand("~/.vimrc").unwrap()).is_equal_to("/home/blubb/.vimrc".to_string());
+    assert_that(&expand("/etc/~/x").unwrap()).is_equal_to("/etc/~/x".to_string());
+    let (user, user_home) = current_user();
+    assert_that(&expand(&format!("~{}/.vimrc", user)).unwrap()).is_equal_to(format!("{}/.vimrc", user_home));
+    assert_that(&expand("~no_such_user_hopefully/x").is_err()).is_true();
+  }
+
+  #[test]
+  fn test_expand_variables() {
+    assert_that(&expand("~/.config/$EDITOR").unwrap()).is_equal_to("/home/blubb/.config/nvim".to_string());
+    assert_that(&expand("${EDITOR}rc").unwrap()).is_equal_to("nvimrc".to_string());
+    assert_that(&expand("${UNSET:-vim}/x").unwrap()).is_equal_to("vim/x".to_string());
+    assert_that(&expand("${EMPTY:-~/fallback}").unwrap()).is_equal_to("/home/blubb/fallback".to_string());
+    assert_that(&expand("$HOME/x").unwrap()).is_equal_to("/home/blubb/x".to_string());
+    assert_that(&expand("costs $5").unwrap()).is_equal_to("costs $5".to_string());
+    assert_that(&expand("$UNSET/x").is_err()).is_true();
+    assert_that(&expand("${EDITOR").is_err()).is_true();
+  }
+
+  #[test]
+  fn test_under_root() {
+    assert_that(&under_root(Path::new("/mnt/image"), Path::new("/etc/./motd"))).is_equal_to(PathBuf::from("/mnt/image/etc/motd"));
+    assert_that(&under_root(Path::new("stage"), Path::new("/home/blubb/.vimrc"))).is_equal_to(PathBuf::from("stage/home/blubb/.vimrc"));
+  }
+
+  #[test]
+  fn test_shell_word() {
+    assert_that(&shell_word("~/.vimrc").unwrap()).is_equal_to("\"$HOME/.vimrc\"".to_string());
+    assert_that(&shell_word("$XDG_CONFIG_HOME/nvim").unwrap()).is_equal_to("\"${XDG_CONFIG_HOME:-$HOME/.config}/nvim\"".to_string());
+    assert_that(&shell_word("${EDITOR:-~/vim}rc").unwrap()).is_equal_to("\"${EDITOR:-$HOME/vim}rc\"".to_string());
+    assert_that(&shell_word("/tmp/say \"hi\" $5").unwrap()).is_equal_to("\"/tmp/say \\\"hi\\\" \\$5\"".to_string());
+    assert_that(&shell_word("${1x}").is_err()).is_true();
+  }
+
+  #[test]
+  fn test_expand_xdg_defaults() {
+    assert_that(&expand("${XDG_CONFIG_HOME}/nvim").unwrap()).is_equal_to("/home/blubb/.config/nvim".to_string());
+    assert_that(&expand("$XDG_DATA_HOME/fonts").unwrap()).is_equal_to("/data/fonts".to_string());
+    assert_that(&expand("$XDG_CACHE_HOME").unwrap()).is_equal_to("/home/blubb/.cache".to_string());
+  }
+}
@@ -0,0 +1,747 @@
+use crate::alternate;
+use crate::errors::DotcopterError;
+use crate::expand;
+use crate::model::{format_mode, DotFile, DotFileType};
+use crate::template;
+use slog::Logger;
+use slog::{info, o, warn};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use yaml_rust::{Yaml, YamlEmitter};
+
+const SHELL_PRELUDE: &str = r#"#!/bin/sh
+# Installs dotfiles like `dotcopter apply` does. Generated by dotcopter export.
+#
+# Usage: install.sh [-f|--force]
+#
+# Templates were rendered and alternates selected for the host that exported
+# this script, run it on hosts that should get the same files.
+#
+# Relative sources are looked up in DOTCOPTER_REPO, the directory of this
+# script by default. Existing targets are only replaced with --force, the
+# first replaced file is kept as <target>.dotcopter-backup. A target that
+# differs from an existing backup is not replaced.
+
+force=0
+case "${1:-}" in
+  -f|--force) force=1 ;;
+esac
+repo="${DOTCOPTER_REPO:-$(cd "$(dirname "$0")" && pwd -P)}"
+status=0
+
+info() {
+  printf '%s: %s\n' "$1" "$2"
+}
+
+warn() {
+  printf '%s: %s\n' "$1" "$2" >&2
+}
+
+fail() {
+  printf '%s: %s\n' "$1" "$2" >&2
+  status=1
+}
+
+exists() {
+  [ -e "$1" ] || [ -L "$1" ]
+}
+
+# Moves an existing target to its backup path. A target is only removed if
+# the backup already has the same content.
+move_out_of_the_way() {
+  if ! exists "$1"; then
+    return 0
+  elif ! exists "$1.dotcopter-backup"; then
+    mv "$1" "$1.dotcopter-backup"
+  elif [ -L "$1" ] && [ -L "$1.dotcopter-backup" ] && [ "$(readlink "$1")" = "$(readlink "$1.dotcopter-backup")" ]; then
+    rm -f "$1"
+  elif [ ! -L "$1" ] && [ -f "$1" ] && [ -f "$1.dotcopter-backup" ] && cmp -s "$1" "$1.dotcopter-backup"; then
+    rm -f "$1"
+  else
+    warn "$1" "differs from $1.dotcopter-backup, move one of them away"
+    return 1
+  fi
+}
+
+prepare() {
+  mkdir -p "$(dirname "$1")" && move_out_of_the_way "$1"
+}
+
+canonical() {
+  (cd -P "$(dirname "$1")" && printf '%s/%s' "$(pwd -P)" "$(basename "$1")")
+}
+
+apply_mode() {
+  if [ -n "$2" ]; then
+    chmod "$2" "$1" || fail "$1" "failed to change mode"
+  fi
+}
+
+# link SOURCE TARGET
+link() {
+  if [ ! -e "$1" ]; then
+    warn "$2" "source $1 does not exist"
+  elif [ -L "$2" ] && [ "$(readlink "$2")" = "$(canonical "$1")" ]; then
+    info "$2" "link already exists"
+  elif [ "$force" = 0 ] && [ -e "$2" ]; then
+    fail "$2" "target exists but does not point to source"
+  elif prepare "$2" && ln -s "$(canonical "$1")" "$2"; then
+    info "$2" "link created successfully"
+  else
+    fail "$2" "failed to create link"
+  fi
+}
+
+# copy SOURCE TARGET [MODE]
+copy() {
+  if [ ! -e "$1" ]; then
+    warn "$2" "source $1 does not exist"
+    return
+  elif [ -f "$1" ] && [ -f "$2" ] && cmp -s "$1" "$2"; then
+    info "$2" "file already there"
+  elif [ "$force" = 0 ] && [ -e "$2" ]; then
+    fail "$2" "target already exists but has different content"
+    return
+  elif prepare "$2" && cp "$1" "$2"; then
+    info "$2" "copied file successfully"
+  else
+    fail "$2" "failed to copy file"
+    return
+  fi
+  apply_mode "$2" "${3:-}"
+}
+
+# write_file TARGET MODE CONTENT
+write_file() {
+  if [ -f "$1" ] && printf '%s' "$3" | cmp -s - "$1"; then
+    info "$1" "file already there"
+  elif [ "$force" = 0 ] && [ -e "$1" ]; then
+    fail "$1" "target already exists but has different content"
+    return
+  elif prepare "$1" && (if [ -n "$2" ]; then umask 077; fi && printf '%s' "$3" > "$1"); then
+    info "$1" "rendered template successfully"
+  else
+    fail "$1" "failed to write rendered template"
+    return
+  fi
+  apply_mode "$1" "$2"
+}
+
+"#;
+
+/// Generates a POSIX shell script that applies `dot_files` without dotcopter.
+/// Templates are rendered and alternates are selected for this host, entries
+/// that cannot be exported are left out with a comment.
+pub fn shell_script(log: &Logger, dot_files: &[DotFile], vars: &Yaml, repo: &Path) -> String {
+  let mut script = SHELL_PRELUDE.to_string();
+  let facts = alternate::Facts::current();
+  for dot_file in dot_files {
+    let log = log.new(o!("target" => dot_file.target.clone(), "source" => dot_file.source.clone()));
+    match shell_command(dot_file, vars, repo, &facts) {
+      Ok(command) => {
+        info!(log, "Exported entry");
+        script.push_str(&command);
+      }
+      Err(e) => {
+        warn!(log, "Leaving out entry"; "error" => e.to_string());
+        script.push_str(&format!(
+          "# {}: left out, {}\n",
+          dot_file.target.replace('\n', " "),
+          e.to_string().replace('\n', " ")
+        ));
+      }
+    }
+    script.push('\n');
+  }
+  script.push_str("exit \"$status\"\n");
+  script
+}
+
+fn shell_command(dot_file: &DotFile, vars: &Yaml, repo: &Path, facts: &alternate::Facts) -> Result<String, DotcopterError> {
+  let target = expand::shell_word(&dot_file.target)?;
+  let expanded_source = expand::expand_path(&dot_file.source)?;
+  let alternate = alternate::select(Path::new(&expanded_source), repo, facts);
+  let mode = dot_file.mode.map(format_mode).unwrap_or_default();
+  match dot_file.dot_file_type {
+    DotFileType::LINK | DotFileType::COPY => {
+      let source = match &alternate {
+        Some(alternate) if alternate.is_relative() => format!("\"$repo\"/{}", quote(&alternate.to_string_lossy())),
+        Some(alternate) => quote(&alternate.to_string_lossy()),
+        None if Path::new(&expanded_source).is_relative() => format!("\"$repo\"/{}", expand::shell_word(&dot_file.source)?),
+        None => expand::shell_word(&dot_file.source)?,
+      };
+      if dot_file.dot_file_type == DotFileType::LINK {
+        Ok(format!("link {} {}\n", source, target))
+      } else if mode.is_empty() {
+        Ok(format!("copy {} {}\n", source, target))
+      } else {
+        Ok(format!("copy {} {} {}\n", source, target, mode))
+      }
+    }
+    DotFileType::TEMPLATE => {
+      let source = alternate.unwrap_or_else(|| Path::new(&expanded_source).to_path_buf());
+      let rendered = template::render_without_secrets(&fs::read_to_string(repo.join(source))?, vars)?;
+      Ok(format!("write_file {} {} {}\n", target, quote(&mode), quote(&rendered)))
+    }
+    DotFileType::ENCRYPTED => Err(not_exported()),
+  }
+}
+
+/// Generates a home-manager module with `home.file` and `xdg.configFile`
+/// entries. Links become out-of-store symlinks into `repo`, copies are read
+/// relative to the module, which belongs into the repository. Attributes are
+/// sorted, entries that cannot be exported are left out with a comment.
+pub fn home_manager_module(log: &Logger, dot_files: &[DotFile], vars: &Yaml, repo: &Path) -> Result<String, DotcopterError> {
+  let home = expand::home_dir().ok_or_else(|| DotcopterError::Expansion("home dir not set".to_string()))?;
+  let facts = alternate::Facts::current();
+  let mut attributes = BTreeMap::new();
+  let mut left_out = Vec::new();
+  for dot_file in dot_files {
+    let log = log.new(o!("target" => dot_file.target.clone(), "source" => dot_file.source.clone()));
+    let result = home_manager_attribute(&log, dot_file, vars, repo, &home, &facts).and_then(|(name, value)| match attributes.entry(name) {
+      Entry::Occupied(_) => Err(DotcopterError::Message("another entry has the same target".to_string())),
+      Entry::Vacant(entry) => {
+        entry.insert(value);
+        Ok(())
+      }
+    });
+    match result {
+      Ok(_) => info!(log, "Exported entry"),
+      Err(e) => {
+        warn!(log, "Leaving out entry"; "error" => e.to_string());
+        left_out.push(format!(
+          "  # {}: left out, {}\n",
+          dot_file.target.replace('\n', " "),
+          e.to_string().replace('\n', " ")
+        ));
+      }
+    }
+  }
+  let mut module = String::new();
+  module.push_str("# Generated by dotcopter export. Copies are read relative to this file.\n");
+  module.push_str("{ config, ... }:\n\n");
+  module.push_str("let\n");
+  module.push_str(&format!("  repo = {};\n", nix_string(&repo.canonicalize()?.to_string_lossy())));
+  module.push_str("  link = path: config.lib.file.mkOutOfStoreSymlink \"${repo}/${path}\";\n");
+  module.push_str("in\n{\n");
+  for (name, value) in &attributes {
+    module.push_str(&format!("  {} = {{\n", name));
+    for (key, value) in value {
+      module.push_str(&format!("    {} = {};\n", key, value));
+    }
+    module.push_str("  };\n");
+  }
+  for comment in left_out {
+    module.push_str(&comment);
+  }
+  module.push_str("}\n");
+  Ok(module)
+}
+
+type NixSettings = Vec<(&'static str, String)>;
+
+/// The attribute path and settings of one entry, e.g. `home.file.".vimrc"`.
+fn home_manager_attribute(
+  log: &Logger,
+  dot_file: &DotFile,
+  vars: &Yaml,
+  repo: &Path,
+  home: &str,
+  facts: &alternate::Facts,
+) -> Result<(String, NixSettings), DotcopterError> {
+  let target = PathBuf::from(expand::expand_path_for_home(&dot_file.target, home)?);
+  let relative = expand::normalize(&target)
+    .strip_prefix(home)
+    .map(Path::to_path_buf)
+    .map_err(|_| DotcopterError::Message("targets outside the home directory are not supported".to_string()))?;
+  let name = match relative.strip_prefix(".config") {
+    Ok(config) if !config.as_os_str().is_empty() => format!("xdg.configFile.{}", nix_string(&config.to_string_lossy())),
+    _ => format!("home.file.{}", nix_string(&relative.to_string_lossy())),
+  };
+  let source = resolve_source(dot_file, repo, facts)?;
+  let mut settings = Vec::new();
+  match dot_file.dot_file_type {
+    DotFileType::LINK => {
+      let canonical_repo = repo.canonicalize()?;
+      let canonical_source = canonical_repo.join(&source).canonicalize()?;
+      let value = match canonical_source.strip_prefix(&canonical_repo) {
+        Ok(relative_source) => format!("link {}", nix_string(&relative_source.to_string_lossy())),
+        Err(_) => format!("config.lib.file.mkOutOfStoreSymlink {}", nix_string(&canonical_source.to_string_lossy())),
+      };
+      settings.push(("source", value));
+    }
+    DotFileType::COPY => settings.push(("source", nix_path(&source))),
+    DotFileType::TEMPLATE => settings.push((
+      "text",
+      nix_string(&template::render_without_secrets(&fs::read_to_string(repo.join(&source))?, vars)?),
+    )),
+    DotFileType::ENCRYPTED => return Err(not_exported()),
+  }
+  if let Some(mode) = dot_file.mode {
+    if mode & 0o077 == 0 {
+      return Err(DotcopterError::Message(format!(
+        "mode {} cannot be exported, home-manager files are readable by everyone",
+        format_mode(mode)
+      )));
+    }
+    if mode & 0o111 != 0 {
+      settings.push(("executable", "true".to_string()));
+    }
+    if mode & 0o666 != 0o644 {
+      warn!(log, "Mode cannot be exported, home-manager files are readable by everyone"; "mode" => format_mode(mode));
+    }
+  }
+  Ok((name, settings))
+}
+
+/// Generates an Ansible playbook. Parent directories are created first, links
+/// become `file` tasks and copies and rendered templates `copy` tasks. Existing
+/// targets are only replaced if `dotfiles_force` is set, links need the
+/// repository at `dotfiles_repo` on the managed host as well.
+pub fn ansible_playbook(log: &Logger, dot_files: &[DotFile], vars: &Yaml, repo: &Path) -> Result<String, DotcopterError> {
+  let home = expand::home_dir().ok_or_else(|| DotcopterError::Expansion("home dir not set".to_string()))?;
+  let facts = alternate::Facts::current();
+  let mut directories = BTreeMap::new();
+  let mut tasks = Vec::new();
+  let mut left_out = Vec::new();
+  for dot_file in dot_files {
+    let log = log.new(o!("target" => dot_file.target.clone(), "source" => dot_file.source.clone()));
+    match ansible_task(dot_file, vars, repo, &home, &facts) {
+      Ok((task, parent)) => {
+        info!(log, "Exported entry");
+        if let Some((parent, needs_root)) = parent {
+          *directories.entry(parent).or_insert(false) |= needs_root;
+        }
+        tasks.push(task);
+      }
+      Err(e) => {
+        warn!(log, "Leaving out entry"; "error" => e.to_string());
+        left_out.push(format!(
+          "# {}: left out, {}\n",
+          dot_file.target.replace('\n', " "),
+          e.to_string().replace('\n', " ")
+        ));
+      }
+    }
+  }
+  let directory_tasks = directories.into_iter().map(|(directory, needs_root)| {
+    ansible_mapping(vec![
+      ("name", Yaml::String(format!("Create {}", directory))),
+      (
+        "ansible.builtin.file",
+        ansible_mapping(vec![("path", jinja_literal(&directory)), ("state", string("directory"))]),
+      ),
+      ("become", Yaml::Boolean(needs_root)),
+    ])
+  });
+  let play = ansible_mapping(vec![
+    ("name", string("Install dotfiles")),
+    ("hosts", string("all")),
+    (
+      "vars",
+      ansible_mapping(vec![
+        ("dotfiles_repo", jinja_literal(&repo.canonicalize()?.to_string_lossy())),
+        ("dotfiles_force", Yaml::Boolean(false)),
+      ]),
+    ),
+    ("tasks", Yaml::Array(directory_tasks.chain(tasks).collect())),
+  ]);
+  let mut playbook = String::from("# Generated by dotcopter export.\n");
+  YamlEmitter::new(&mut playbook)
+    .dump(&Yaml::Array(vec![play]))
+    .map_err(|e| DotcopterError::Message(format!("{:?}", e)))?;
+  playbook.push('\n');
+  for comment in left_out {
+    playbook.push_str(&comment);
+  }
+  Ok(playbook)
+}
+
+/// The task of one entry and the directory it needs, with whether creating it
+/// needs root.
+fn ansible_task(dot_file: &DotFile, vars: &Yaml, repo: &Path, home: &str, facts: &alternate::Facts) -> Result<(Yaml, Option<(String, bool)>), DotcopterError> {
+  let target = expand::normalize(Path::new(&expand::expand_path_for_home(&dot_file.target, home)?));
+  if !target.is_absolute() {
+    return Err(DotcopterError::Message("relative targets are not supported".to_string()));
+  }
+  let in_home = |path: &Path| {
+    path
+      .strip_prefix(home)
+      .ok()
+      .map(|relative| Path::new("~").join(relative).to_string_lossy().into_owned())
+  };
+  let needs_root = in_home(&target).is_none();
+  let dest = in_home(&target).unwrap_or_else(|| target.to_string_lossy().into_owned());
+  let parent = target
+    .parent()
+    .filter(|parent| *parent != Path::new("/") && *parent != Path::new(home))
+    .map(|parent| (in_home(parent).unwrap_or_else(|| parent.to_string_lossy().into_owned()), needs_root));
+  let source = resolve_source(dot_file, repo, facts)?;
+  let src = if source.is_relative() {
+    Yaml::String(format!("{{{{ dotfiles_repo }}}}/{}", jinja_escape(&source.to_string_lossy())))
+  } else {
+    jinja_literal(&source.to_string_lossy())
+  };
+  let mode = dot_file.mode.map(|mode| ("mode", string(&format_mode(mode))));
+  let force = ("force", string("{{ dotfiles_force }}"));
+  let (name, module, arguments) = match dot_file.dot_file_type {
+    DotFileType::LINK => (
+      "Link",
+      "ansible.builtin.file",
+      vec![("src", src), ("dest", jinja_literal(&dest)), ("state", string("link")), force],
+    ),
+    DotFileType::COPY => (
+      "Copy",
+      "ansible.builtin.copy",
+      vec![("src", src), ("dest", jinja_literal(&dest)), force, ("backup", Yaml::Boolean(true))],
+    ),
+    DotFileType::TEMPLATE => {
+      let rendered = template::render_without_secrets(&fs::read_to_string(repo.join(&source))?, vars)?;
+      (
+        "Render",
+        "ansible.builtin.copy",
+        vec![
+          ("content", jinja_literal(&rendered)),
+          ("dest", jinja_literal(&dest)),
+          force,
+          ("backup", Yaml::Boolean(true)),
+        ],
+      )
+    }
+    DotFileType::ENCRYPTED => return Err(not_exported()),
+  };
+  let task = ansible_mapping(vec![
+    ("name", Yaml::String(format!("{} {}", name, dest))),
+    (module, ansible_mapping(arguments.into_iter().chain(mode).collect())),
+    ("become", Yaml::Boolean(needs_root)),
+  ]);
+  Ok((task, parent))
+}
+
+fn ansible_mapping(entries: Vec<(&str, Yaml)>) -> Yaml {
+  Yaml::Hash(entries.into_iter().map(|(key, value)| (string(key), value)).collect())
+}
+
+fn string(s: &str) -> Yaml {
+  Yaml::String(s.to_string())
+}
+
+/// A value Ansible does not template.
+fn jinja_literal(s: &str) -> Yaml {
+  Yaml::String(jinja_escape(s))
+}
+
+fn jinja_escape(s: &str) -> String {
+  if s.contains("{{") || s.contains("{%") || s.contains("{#") {
+    format!("{{% raw %}}{}{{% endraw %}}", s)
+  } else {
+    s.to_string()
+  }
+}
+
+/// Decrypted secrets would end up readable in the generated file.
+fn not_exported() -> DotcopterError {
+  DotcopterError::Message("encrypted entries are not exported".to_string())
+}
+
+/// The source of an entry with the alternate for this host, relative to
+/// `repo` unless it is outside of it.
+fn resolve_source(dot_file: &DotFile, repo: &Path, facts: &alternate::Facts) -> Result<PathBuf, DotcopterError> {
+  let source = PathBuf::from(expand::expand_path(&dot_file.source)?);
+  let source = alternate::select(&source, repo, facts).unwrap_or(source);
+  if !repo.join(&source).exists() {
+    return Err(DotcopterError::Message(format!("source {} does not exist", source.display())));
+  }
+  Ok(source)
+}
+
+fn nix_string(s: &str) -> String {
+  let mut quoted = String::from("\"");
+  let mut chars = s.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '"' => quoted.push_str("\\\""),
+      '\\' => quoted.push_str("\\\\"),
+      '\n' => quoted.push_str("\\n"),
+      '\r' => quoted.push_str("\\r"),
+      '\t' => quoted.push_str("\\t"),
+      '$' if chars.peek() == Some(&'{') => quoted.push_str("\\$"),
+      c => quoted.push(c),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// A Nix path literal, relative paths are resolved against the module file.
+fn nix_path(path: &Path) -> String {
+  let path = path.to_string_lossy();
+  let simple = !path.is_empty() && !path.contains("//") && !path.ends_with('/') && path.chars().all(|c| c.is_ascii_alphanumeric() || "._-+/".contains(c));
+  match (path.starts_with('/'), simple) {
+    (true, true) => path.to_string(),
+    (false, true) => format!("./{}", path),
+    (true, false) => format!("(/. + {})", nix_string(&path)),
+    (false, false) => format!("(./. + {})", nix_string(&format!("/{}", path))),
+  }
+}
+
+/// Quotes `s` as a single shell word that is taken literally.
+fn quote(s: &str) -> String {
+  format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::a_dot_file;
+  use spectral::prelude::*;
+  use std::env;
+  use std::process::Command;
+  use yaml_rust::YamlLoader;
+
+  fn a_logger() -> Logger {
+    use slog::Drain;
+    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+    let drain = slog_term::FullFormat::new(plain).build().fuse();
+    Logger::root(drain, o!())
+  }
+
+  fn a_root(name: &str) -> PathBuf {
+    let root = env::temp_dir().join(format!("dotcopter-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    root
+  }
+
+  #[test]
+  fn test_shell_script() {
+    let root = a_root("export");
+    let repo = root.join("repo");
+    let home = root.join("home");
+    fs::create_dir_all(&repo).unwrap();
+    fs::create_dir_all(home.join(".ssh")).unwrap();
+    fs::write(repo.join("vimrc"), "set nocompatible\n").unwrap();
+    fs::write(repo.join("it's config"), "Host *\n").unwrap();
+    fs::write(repo.join("gitconfig"), "name = {{ name }}").unwrap();
+    fs::write(home.join(".ssh/config"), "old\n").unwrap();
+    let dot_files = vec![
+      a_dot_file("vimrc", "$TEST_HOME/.vimrc", DotFileType::LINK, None),
+      a_dot_file("it's config", "$TEST_HOME/.ssh/config", DotFileType::COPY, Some(0o600)),
+      a_dot_file("gitconfig", "$TEST_HOME/.gitconfig", DotFileType::TEMPLATE, None),
+      a_dot_file("missing.tmpl", "$TEST_HOME/.missing", DotFileType::TEMPLATE, None),
+    ];
+    let vars = &YamlLoader::load_from_str("name: o'brien").unwrap()[0];
+    let script = shell_script(&a_logger(), &dot_files, vars, &repo);
+    assert_that(&script).contains("# $TEST_HOME/.missing: left out");
+    fs::write(repo.join("install.sh"), &script).unwrap();
+
+    let install = |force: bool| {
+      let mut command = Command::new("sh");
+      command.arg(repo.join("install.sh")).env("TEST_HOME", &home);
+      if force {
+        command.arg("--force");
+      }
+      command.status().unwrap().code()
+    };
+    assert_that(&install(false)).is_equal_to(Some(1));
+    assert_that(&fs::read_to_string(home.join(".ssh/config")).unwrap()).is_equal_to("old\n".to_string());
+    assert_that(&install(true)).is_equal_to(Some(0));
+    assert_that(&install(false)).is_equal_to(Some(0));
+    assert_that(&fs::read_link(home.join(".vimrc")).unwrap()).is_equal_to(repo.canonicalize().unwrap().join("vimrc"));
+    assert_that(&fs::read_to_string(home.join(".ssh/config")).unwrap()).is_equal_to("Host *\n".to_string());
+    assert_that(&fs::read_to_string(home.join(".ssh/config.dotcopter-backup")).unwrap()).is_equal_to("old\n".to_string());
+    assert_that(&fs::read_to_string(home.join(".gitconfig")).unwrap()).is_equal_to("name = o'brien".to_string());
+    {
+      use std::os::unix::fs::PermissionsExt;
+      assert_that(&(fs::metadata(home.join(".ssh/config")).unwrap().permissions().mode() & 0o777)).is_equal_to(0o600);
+    }
+    fs::remove_dir_all(&root).unwrap();
+  }
+
+  /// A rough stand in for `nix-instantiate --parse`: strings, interpolations
+  /// and brackets have to be balanced.
+  fn check_nix_syntax(source: &str) -> Result<(), String> {
+    #[derive(PartialEq)]
+    enum Context {
+      Bracket(char),
+      String,
+      IndentedString,
+      Interpolation,
+    }
+    let chars: Vec<char> = source.chars().collect();
+    let mut stack = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+      let c = chars[i];
+      let next = chars.get(i + 1).copied();
+      match stack.last() {
+        Some(Context::String) => match (c, next) {
+          ('\\', _) => i += 1,
+          ('"', _) => {
+            stack.pop();
+          }
+          ('$', Some('{')) => {
+            stack.push(Context::Interpolation);
+            i += 1;
+          }
+          _ => {}
+        },
+        Some(Context::IndentedString) => match (c, next) {
+          ('\'', Some('\'')) => match chars.get(i + 2) {
+            Some('$') | Some('\'') | Some('\\') => i += 2,
+            _ => {
+              stack.pop();
+              i += 1;
+            }
+          },
+          ('$', Some('{')) => {
+            stack.push(Context::Interpolation);
+            i += 1;
+          }
+          _ => {}
+        },
+        _ => match (c, next) {
+          ('#', _) => {
+            while i < chars.len() && chars[i] != '\n' {
+              i += 1;
+            }
+          }
+          ('"', _) => stack.push(Context::String),
+          ('\'', Some('\'')) => {
+            stack.push(Context::IndentedString);
+            i += 1;
+          }
+          ('{', _) | ('[', _) | ('(', _) => stack.push(Context::Bracket(c)),
+          ('}', _) if stack.last() == Some(&Context::Interpolation) => {
+            stack.pop();
+          }
+          ('}', _) | (']', _) | (')', _) => {
+            let open = match c {
+              '}' => '{',
+              ']' => '[',
+              _ => '(',
+            };
+            if stack.pop() != Some(Context::Bracket(open)) {
+              return Err(format!("unbalanced `{}` at offset {}", c, i));
+            }
+          }
+          _ => {}
+        },
+      }
+      i += 1;
+    }
+    if stack.is_empty() {
+      Ok(())
+    } else {
+      Err("unterminated string or bracket".to_string())
+    }
+  }
+
+  #[test]
+  fn test_home_manager_module() {
+    let root = a_root("export-nix");
+    fs::create_dir_all(root.join("nvim")).unwrap();
+    fs::write(root.join("vimrc"), "set nocompatible\n").unwrap();
+    fs::write(root.join("it's netrc"), "machine x\n").unwrap();
+    fs::write(root.join("deploy.sh"), "#!/bin/sh\n").unwrap();
+    fs::write(root.join("gitconfig"), "[user]\n\tname = \"{{ name }}\" ${HOME}\n").unwrap();
+    let dot_files = vec![
+      a_dot_file("vimrc", "~/.vimrc", DotFileType::LINK, None),
+      a_dot_file("nvim", "$XDG_CONFIG_HOME/nvim", DotFileType::LINK, None),
+      a_dot_file("it's netrc", "~/.netrc", DotFileType::COPY, Some(0o600)),
+      a_dot_file("it's netrc", "~/.config/it's", DotFileType::COPY, None),
+      a_dot_file("deploy.sh", "~/bin/deploy", DotFileType::COPY, Some(0o755)),
+      a_dot_file("gitconfig", "~/.gitconfig", DotFileType::TEMPLATE, None),
+      a_dot_file("vimrc", "/etc/vimrc", DotFileType::COPY, None),
+      a_dot_file("vimrc", "~/.vimrc", DotFileType::COPY, None),
+    ];
+    let vars = &YamlLoader::load_from_str("name: blubb").unwrap()[0];
+    let module = home_manager_module(&a_logger(), &dot_files, vars, &root).unwrap();
+    assert_that(&module).is_equal_to(format!(
+      r#"# Generated by dotcopter export. Copies are read relative to this file.
+{{ config, ... }}:
+
+let
+  repo = "{}";
+  link = path: config.lib.file.mkOutOfStoreSymlink "${{repo}}/${{path}}";
+in
+{{
+  home.file.".gitconfig" = {{
+    text = "[user]\n\tname = \"blubb\" \${{HOME}}\n";
+  }};
+  home.file.".vimrc" = {{
+    source = link "vimrc";
+  }};
+  home.file."bin/deploy" = {{
+    source = ./deploy.sh;
+    executable = true;
+  }};
+  xdg.configFile."it's" = {{
+    source = (./. + "/it's netrc");
+  }};
+  xdg.configFile."nvim" = {{
+    source = link "nvim";
+  }};
+  # ~/.netrc: left out, mode 0600 cannot be exported, home-manager files are readable by everyone
+  # /etc/vimrc: left out, targets outside the home directory are not supported
+  # ~/.vimrc: left out, another entry has the same target
+}}
+"#,
+      root.canonicalize().unwrap().display()
+    ));
+    assert_that(&check_nix_syntax(&module)).is_ok();
+    assert_that(&check_nix_syntax("{ a = \"${b\"; }")).is_err();
+    if let Ok(mut nix) = Command::new("nix-instantiate")
+      .args(["--parse", "-"])
+      .stdin(std::process::Stdio::piped())
+      .spawn()
+    {
+      use std::io::Write;
+      nix.stdin.take().unwrap().write_all(module.as_bytes()).unwrap();
+      assert_that(&nix.wait().unwrap().success()).is_true();
+    }
+    fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[test]
+  fn test_ansible_playbook() {
+    let root = a_root("export-ansible");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("vimrc"), "set nocompatible\n").unwrap();
+    fs::write(root.join("motd"), "hello\n").unwrap();
+    fs::write(root.join("gitconfig"), "name = {{ name }}\n").unwrap();
+    let dot_files = vec![
+      a_dot_file("vimrc", "~/.vimrc", DotFileType::LINK, None),
+      a_dot_file("motd", "/etc/motd", DotFileType::COPY, Some(0o644)),
+      a_dot_file("gitconfig", "$XDG_CONFIG_HOME/git/config", DotFileType::TEMPLATE, Some(0o600)),
+      a_dot_file("missing", "~/.missing", DotFileType::LINK, None),
+    ];
+    let vars = &YamlLoader::load_from_str("name: \"{{ blubb }}\"").unwrap()[0];
+    let playbook = ansible_playbook(&a_logger(), &dot_files, vars, &root).unwrap();
+    assert_that(&playbook).contains("# ~/.missing: left out");
+
+    let documents = YamlLoader::load_from_str(&playbook).unwrap();
+    assert_that(&documents.len()).is_equal_to(1);
+    let mut emitted = String::new();
+    YamlEmitter::new(&mut emitted).dump(&documents[0]).unwrap();
+    assert_that(&playbook).contains(emitted.as_str());
+
+    let tasks = &documents[0][0]["tasks"];
+    let names: Vec<&str> = tasks.as_vec().unwrap().iter().map(|task| task["name"].as_str().unwrap()).collect();
+    assert_that(&names).is_equal_to(vec![
+      "Create /etc",
+      "Create ~/.config/git",
+      "Link ~/.vimrc",
+      "Copy /etc/motd",
+      "Render ~/.config/git/config",
+    ]);
+    assert_that(&tasks[2]["ansible.builtin.file"]["src"].as_str()).is_equal_to(Some("{{ dotfiles_repo }}/vimrc"));
+    assert_that(&tasks[2]["ansible.builtin.file"]["state"].as_str()).is_equal_to(Some("link"));
+    assert_that(&tasks[3]["ansible.builtin.copy"]["mode"].as_str()).is_equal_to(Some("0644"));
+    assert_that(&tasks[3]["become"].as_bool()).is_equal_to(Some(true));
+    assert_that(&tasks[4]["ansible.builtin.copy"]["content"].as_str()).is_equal_to(Some("{% raw %}name = {{ blubb }}\n{% endraw %}"));
+    assert_that(&tasks[4]["ansible.builtin.copy"]["mode"].as_str()).is_equal_to(Some("0600"));
+    fs::remove_dir_all(&root).unwrap();
+  }
+}
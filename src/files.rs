@@ -1,56 +1,170 @@
+use crate::alternate;
 use crate::checksum;
 use crate::config;
+use crate::encryption;
 use crate::errors::DotcopterError;
+use crate::expand;
 use crate::model::*;
+use crate::template;
 use slog::Logger;
 use slog::{debug, error, info, o, warn};
+use std::env;
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use yaml_rust::Yaml;
 
-pub fn process_dot_files(log: &Logger, dot_files: &Yaml, force: bool) {
+/// Set once from `main` if replaced targets are kept as backup.
+static BACKUPS: OnceLock<()> = OnceLock::new();
+
+/// Keeps targets replaced with `--force` as `<target>.dotcopter-backup`
+/// instead of removing them.
+pub fn enable_backups() {
+  let _ = BACKUPS.set(());
+}
+
+pub fn process_dot_files(log: &Logger, dot_files: &Yaml, vars: &Yaml, force: bool) {
   if dot_files.is_badvalue() {
     warn!(log, "Empty files list");
   } else {
+    let facts = alternate::Facts::current();
     for dot_file in config::parse_dot_files(log, dot_files) {
-      process_dot_file(log, &dot_file, force);
+      process_dot_file(log, &dot_file, vars, &facts, force);
     }
   }
 }
 
-fn process_dot_file(log: &Logger, dot_file: &DotFile, force: bool) {
+pub fn process_dot_file(log: &Logger, dot_file: &DotFile, vars: &Yaml, facts: &alternate::Facts, force: bool) {
   let log = &log.new(o!("target" => dot_file.target.clone(), "source" => dot_file.source.clone(), "type" => format!("{:?}", dot_file.dot_file_type)));
   debug!(log, "Process entry");
-  let source_with_resolved_home = resolve_home(log, &dot_file.source);
-  let target_with_resolved_home = resolve_home(log, &dot_file.target);
-  let source_path = Path::new(&source_with_resolved_home);
-  let target_path = Path::new(&target_with_resolved_home);
+  let (source, target) = match (expand::expand_path(&dot_file.source), expand::expand_target(&dot_file.target)) {
+    (Ok(source), Ok(target)) => (source, target),
+    (Err(e), _) | (_, Err(e)) => {
+      error!(log, "Failed to expand path"; "error" => e.to_string());
+      return;
+    }
+  };
+  let source_path = &select_alternate(log, &source, facts);
+  let target_path = Path::new(&target);
   if !source_path.exists() {
     warn!(log, "Source path does not exist");
     return;
   }
   match dot_file.dot_file_type {
-    DotFileType::LINK => process_link(log, source_path, target_path, force),
-    DotFileType::COPY => process_copy(log, source_path, target_path, force),
+    DotFileType::LINK => {
+      if dot_file.mode.is_some() {
+        warn!(log, "Mode is ignored for links");
+      }
+      process_link(log, source_path, target_path, force)
+    }
+    DotFileType::COPY => process_copy(log, source_path, target_path, dot_file.mode, force),
+    DotFileType::TEMPLATE => process_template(log, source_path, target_path, vars, dot_file.mode, force),
+    DotFileType::ENCRYPTED => process_encrypted(log, source_path, target_path, dot_file.mode, force),
   }
 }
 
-fn resolve_home(log: &Logger, path: &str) -> String {
-  if let Some(home_dir) = dirs::home_dir() {
-    if let Some(stripped_path) = path.strip_prefix('~') {
-      let mut home_string = home_dir.into_os_string().into_string().expect("home_dir should be a valid string");
-      home_string.push_str(&stripped_path);
-      home_string
-    } else {
-      path.to_string()
+fn select_alternate(log: &Logger, source: &str, facts: &alternate::Facts) -> PathBuf {
+  let repo = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+  alternate::resolve(log, Path::new(source), &repo, facts)
+}
+
+fn process_template(log: &Logger, source_path: &Path, target_path: &Path, vars: &Yaml, mode: Option<u32>, force: bool) {
+  match render_template(source_path, vars) {
+    Ok(rendered) => install_content(log, target_path, rendered.as_bytes(), mode, force),
+    Err(e) => error!(log, "Failed to render template"; "error" => e.to_string()),
+  }
+}
+
+fn render_template(source_path: &Path, vars: &Yaml) -> Result<String, DotcopterError> {
+  template::render(&fs::read_to_string(source_path)?, vars)
+}
+
+/// Decrypts the source straight into the target, which is only readable by
+/// the owner unless the entry sets a mode. Targets inside the repository are
+/// refused so the plaintext never ends up next to the encrypted file.
+fn process_encrypted(log: &Logger, source_path: &Path, target_path: &Path, mode: Option<u32>, force: bool) {
+  let repo = env::current_dir().and_then(fs::canonicalize).unwrap_or_else(|_| PathBuf::from("."));
+  if resolve_existing(&expand::normalize(&repo.join(target_path))).starts_with(&repo) {
+    error!(log, "Refusing to decrypt into the repository");
+    return;
+  }
+  match fs::read(source_path)
+    .map_err(DotcopterError::from)
+    .and_then(|source| encryption::decrypt(&source))
+  {
+    Ok(plaintext) => install_content(log, target_path, &plaintext, Some(mode.unwrap_or(0o600)), force),
+    Err(e) => error!(log, "Failed to decrypt source"; "error" => e.to_string()),
+  }
+}
+
+/// Resolves links in the part of `path` that exists, so a target below a
+/// linked directory is found where it would really be written.
+pub(crate) fn resolve_existing(path: &Path) -> PathBuf {
+  for ancestor in path.ancestors() {
+    if let Ok(resolved) = fs::canonicalize(ancestor) {
+      return resolved.join(path.strip_prefix(ancestor).unwrap_or(Path::new("")));
     }
+  }
+  path.to_path_buf()
+}
+
+/// Writes generated content to the target unless it is already there.
+fn install_content(log: &Logger, target_path: &Path, content: &[u8], mode: Option<u32>, force: bool) {
+  if fs::read(target_path).ok().as_deref() == Some(content) {
+    info!(log, "File already there");
+  } else if !force && target_path.exists() {
+    error!(log, "Target already exists but has different content.");
+    return;
   } else {
-    warn!(log, "Home dir not set");
-    path.to_string()
+    match write_dot_file(target_path, content, mode) {
+      Ok(_) => info!(log, "Wrote file successfully"),
+      Err(e) => {
+        error!(log, "Failed to write file"; "error" => e.to_string());
+        return;
+      }
+    }
   }
+  apply_mode(log, target_path, mode);
 }
 
-fn process_copy(log: &Logger, source_path: &Path, target_path: &Path, force: bool) {
+/// Writes a file that is created with `mode` right away, so its content is
+/// never readable with wider permissions.
+fn write_dot_file(target: &Path, content: &[u8], mode: Option<u32>) -> Result<(), DotcopterError> {
+  use std::io::Write;
+  use std::os::unix::fs::OpenOptionsExt;
+  if let Some(parent) = target.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  move_out_of_the_way(target)?;
+  let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(mode.unwrap_or(0o666)).open(target)?;
+  file.write_all(content)?;
+  Ok(())
+}
+
+/// Sets the permissions of a copied or rendered file if they differ from `mode`.
+fn apply_mode(log: &Logger, target: &Path, mode: Option<u32>) {
+  use std::os::unix::fs::PermissionsExt;
+  let mode = match mode {
+    Some(mode) => mode,
+    None => return,
+  };
+  let current = match target.metadata() {
+    Ok(metadata) => metadata.permissions().mode() & 0o7777,
+    Err(e) => {
+      error!(log, "Failed to read permissions"; "error" => e.to_string());
+      return;
+    }
+  };
+  if current != mode {
+    match fs::set_permissions(target, fs::Permissions::from_mode(mode)) {
+      Ok(_) => info!(log, "Changed mode"; "mode" => format_mode(mode)),
+      Err(e) => error!(log, "Failed to change mode"; "error" => e.to_string()),
+    }
+  }
+}
+
+fn process_copy(log: &Logger, source_path: &Path, target_path: &Path, mode: Option<u32>, force: bool) {
   match has_same_content(log, source_path, target_path) {
     Ok(true) => info!(log, "File already there"),
     Ok(false) => {
@@ -60,29 +174,30 @@ fn process_copy(log: &Logger, source_path: &Path, target_path: &Path, force: boo
       }
       match copy_dot_file(source_path, target_path) {
         Ok(_) => info!(log, "Copied file successfully"),
-        Err(e) => error!(log, "Failed to copy file"; "error" => e.to_string()),
+        Err(e) => {
+          error!(log, "Failed to copy file"; "error" => e.to_string());
+          return;
+        }
       }
     }
-    Err(e) => error!(log, "Failed to copy dotfile"; "error" => e.to_string()),
+    Err(e) => {
+      error!(log, "Failed to copy dotfile"; "error" => e.to_string());
+      return;
+    }
   }
+  apply_mode(log, target_path, mode);
 }
 
 fn copy_dot_file(source: &Path, target: &Path) -> Result<(), DotcopterError> {
   if let Some(parent) = target.parent() {
     fs::create_dir_all(parent)?;
   }
-  if target.exists() {
-    if target.is_file() {
-      fs::remove_file(target)?
-    } else if target.is_dir() {
-      fs::remove_dir_all(target)?
-    }
-  }
+  move_out_of_the_way(target)?;
   fs::copy(source, target)?;
   Ok(())
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(clippy::if_same_then_else))]
+#[allow(clippy::if_same_then_else)]
 fn has_same_content(log: &Logger, source: &Path, target: &Path) -> Result<bool, DotcopterError> {
   if !target.exists() {
     Ok(false)
@@ -124,18 +239,236 @@ fn already_linked(source: &Path, target: &Path) -> Result<bool, DotcopterError>
   }
 }
 
-fn link_dot_file(source: &Path, target: &Path) -> Result<(), DotcopterError> {
+pub fn link_dot_file(source: &Path, target: &Path) -> Result<(), DotcopterError> {
   if let Some(parent) = target.parent() {
     fs::create_dir_all(parent)?;
   }
-  if target.exists() {
-    if target.is_file() {
-      fs::remove_file(target)?
-    } else if target.is_dir() {
-      fs::remove_dir_all(target)?
-    }
-  }
+  move_out_of_the_way(target)?;
   let canonicalized_source = fs::canonicalize(source)?;
   std::os::unix::fs::symlink(canonicalized_source, target)?;
   Ok(())
 }
+
+/// Removes an existing target. With backups enabled it is moved to its backup
+/// path so it can be restored later, and only removed if the existing backup
+/// has the same content.
+fn move_out_of_the_way(target: &Path) -> Result<(), DotcopterError> {
+  let metadata = match target.symlink_metadata() {
+    Ok(metadata) => metadata,
+    Err(_) => return Ok(()),
+  };
+  let backup = backup_path(target);
+  if BACKUPS.get().is_some() && backup.symlink_metadata().is_err() {
+    fs::rename(target, backup)?;
+    return Ok(());
+  }
+  if BACKUPS.get().is_some() && !same_as_backup(target, &backup) {
+    return Err(DotcopterError::Message(format!(
+      "target differs from the existing backup {}, move one of them away",
+      backup.display()
+    )));
+  }
+  if metadata.is_dir() {
+    fs::remove_dir_all(target)?;
+  } else {
+    fs::remove_file(target)?;
+  }
+  Ok(())
+}
+
+/// Links are the same if they point to the same path, files if they have the
+/// same content. Directories are never considered the same.
+fn same_as_backup(target: &Path, backup: &Path) -> bool {
+  match (fs::read_link(target), fs::read_link(backup)) {
+    (Ok(target), Ok(backup)) => target == backup,
+    (Err(_), Err(_)) if target.is_file() && backup.is_file() => matches!((fs::read(target), fs::read(backup)), (Ok(a), Ok(b)) if a == b),
+    _ => false,
+  }
+}
+
+pub fn backup_path(target: &Path) -> PathBuf {
+  let mut backup = target.as_os_str().to_os_string();
+  backup.push(".dotcopter-backup");
+  PathBuf::from(backup)
+}
+
+/// Removes an installed target if it still matches its source and optionally
+/// puts the backup of the original file back in place. Returns false if the
+/// target was left in place or the backup could not be restored.
+pub fn uninstall_dot_file(log: &Logger, dot_file: &DotFile, vars: &Yaml, facts: &alternate::Facts, restore: bool) -> bool {
+  let log = &log.new(o!("target" => dot_file.target.clone(), "source" => dot_file.source.clone(), "type" => format!("{:?}", dot_file.dot_file_type)));
+  let (source, target) = match (expand::expand_path(&dot_file.source), expand::expand_target(&dot_file.target)) {
+    (Ok(source), Ok(target)) => (source, target),
+    (Err(e), _) | (_, Err(e)) => {
+      error!(log, "Failed to expand path"; "error" => e.to_string());
+      return false;
+    }
+  };
+  let source_path = &select_alternate(log, &source, facts);
+  let target_path = Path::new(&target);
+  if target_path.symlink_metadata().is_ok() {
+    let installed = match dot_file.dot_file_type {
+      DotFileType::LINK => is_symlink(target_path) && source_path.exists() && already_linked(source_path, target_path).unwrap_or(false),
+      DotFileType::COPY => source_path.exists() && has_same_content(log, source_path, target_path).unwrap_or(false),
+      DotFileType::TEMPLATE => match (render_template(source_path, vars), fs::read(target_path)) {
+        (Ok(rendered), Ok(content)) => rendered.as_bytes() == content.as_slice(),
+        _ => false,
+      },
+      DotFileType::ENCRYPTED => {
+        let plaintext = fs::read(source_path)
+          .map_err(DotcopterError::from)
+          .and_then(|source| encryption::decrypt(&source));
+        match (plaintext, fs::read(target_path)) {
+          (Ok(plaintext), Ok(content)) => plaintext == content,
+          _ => false,
+        }
+      }
+    };
+    if !installed {
+      warn!(log, "Target does not match source. Leaving it in place.");
+      return false;
+    }
+    match fs::remove_file(target_path) {
+      Ok(_) => info!(log, "Removed target"),
+      Err(e) => {
+        error!(log, "Failed to remove target"; "error" => e.to_string());
+        return false;
+      }
+    }
+  } else {
+    info!(log, "Target is not installed");
+  }
+  if restore {
+    let backup = backup_path(target_path);
+    if backup.symlink_metadata().is_err() {
+      info!(log, "No backup to restore");
+    } else {
+      match fs::rename(&backup, target_path) {
+        Ok(_) => info!(log, "Restored backup"; "backup" => format!("{}", backup.display())),
+        Err(e) => {
+          error!(log, "Failed to restore backup"; "error" => e.to_string());
+          return false;
+        }
+      }
+    }
+  }
+  true
+}
+
+fn is_symlink(path: &Path) -> bool {
+  path.symlink_metadata().map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false)
+}
+
+/// Renames a file or directory, falling back to copy and delete when the
+/// destination is on another file system.
+pub fn move_path(from: &Path, to: &Path) -> Result<(), DotcopterError> {
+  if let Some(parent) = to.parent() {
+    if !parent.as_os_str().is_empty() {
+      fs::create_dir_all(parent)?;
+    }
+  }
+  match fs::rename(from, to) {
+    Ok(_) => Ok(()),
+    Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+      copy_recursively(from, to)?;
+      if from.is_dir() {
+        fs::remove_dir_all(from)?;
+      } else {
+        fs::remove_file(from)?;
+      }
+      Ok(())
+    }
+    Err(e) => Err(e.into()),
+  }
+}
+
+fn copy_recursively(from: &Path, to: &Path) -> io::Result<()> {
+  let metadata = from.symlink_metadata()?;
+  if metadata.file_type().is_symlink() {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)
+  } else if metadata.is_dir() {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+      let entry = entry?;
+      copy_recursively(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    fs::set_permissions(to, metadata.permissions())
+  } else {
+    fs::copy(from, to).map(|_| ())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+  use std::os::unix::fs::PermissionsExt;
+
+  fn a_logger() -> Logger {
+    use slog::Drain;
+    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+    let drain = slog_term::FullFormat::new(plain).build().fuse();
+    Logger::root(drain, o!())
+  }
+
+  #[test]
+  fn test_process_encrypted() {
+    let dir = env::temp_dir().join(format!("dotcopter-files-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("key"), "secret\n").unwrap();
+    encryption::set_key_file(&dir.join("key").to_string_lossy());
+    let plaintext = b"machine example.com password hunter2\n";
+    let encrypted = encryption::encrypt_with(plaintext, b"secret\n", encryption::MIN_LOG_N).unwrap();
+    fs::write(dir.join("netrc.enc"), encrypted).unwrap();
+
+    process_encrypted(&a_logger(), &dir.join("netrc.enc"), &dir.join(".netrc"), None, false);
+    assert_that(&fs::read(dir.join(".netrc")).unwrap()).is_equal_to(plaintext.to_vec());
+    assert_that(&(fs::metadata(dir.join(".netrc")).unwrap().permissions().mode() & 0o777)).is_equal_to(0o600);
+
+    let inside_repo = env::current_dir().unwrap().join(format!("dotcopter-files-{}", std::process::id()));
+    process_encrypted(&a_logger(), &dir.join("netrc.enc"), &inside_repo, None, false);
+    assert_that(&inside_repo.exists()).is_false();
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_process_below_root() {
+    let dir = env::temp_dir().join(format!("dotcopter-root-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("repo")).unwrap();
+    fs::write(dir.join("repo/vimrc"), "set number\n").unwrap();
+    fs::write(dir.join("repo/gitconfig"), "[user]\n").unwrap();
+    let staged = |target: &str| expand::under_root(&dir.join("staging"), Path::new(&expand::expand_path_for_home(target, "/home/dev").unwrap()));
+
+    process_link(&a_logger(), &dir.join("repo/vimrc"), &staged("~/.vimrc"), false);
+    process_copy(&a_logger(), &dir.join("repo/gitconfig"), &staged("$XDG_CONFIG_HOME/git/config"), None, false);
+    let home = dir.join("staging/home/dev");
+    assert_that(&fs::read_link(home.join(".vimrc")).unwrap()).is_equal_to(dir.join("repo/vimrc"));
+    assert_that(&fs::read_to_string(home.join(".config/git/config")).unwrap()).is_equal_to("[user]\n".to_string());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_same_as_backup() {
+    let dir = env::temp_dir().join(format!("dotcopter-files-backup-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("directory")).unwrap();
+    fs::create_dir_all(dir.join("directory.dotcopter-backup")).unwrap();
+    fs::write(dir.join("vimrc"), "set nu\n").unwrap();
+    fs::write(dir.join("vimrc.dotcopter-backup"), "set nu\n").unwrap();
+    fs::write(dir.join("zshrc"), "export EDITOR=vim\n").unwrap();
+    fs::write(dir.join("zshrc.dotcopter-backup"), "export EDITOR=nano\n").unwrap();
+    std::os::unix::fs::symlink("vimrc", dir.join("link")).unwrap();
+    std::os::unix::fs::symlink("vimrc", dir.join("link.dotcopter-backup")).unwrap();
+    std::os::unix::fs::symlink("zshrc", dir.join("other")).unwrap();
+    std::os::unix::fs::symlink("vimrc", dir.join("other.dotcopter-backup")).unwrap();
+    let same = |name: &str| same_as_backup(&dir.join(name), &backup_path(&dir.join(name)));
+    assert_that(&same("vimrc")).is_true();
+    assert_that(&same("zshrc")).is_false();
+    assert_that(&same("link")).is_true();
+    assert_that(&same("other")).is_false();
+    assert_that(&same("directory")).is_false();
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,37 @@
+use std::env;
+use std::ffi::CStr;
+
+/// The short host name, without the domain part.
+pub fn hostname() -> Option<String> {
+  let mut buffer: Vec<libc::c_char> = vec![0; 256];
+  if unsafe { libc::gethostname(buffer.as_mut_ptr(), buffer.len()) } != 0 {
+    return None;
+  }
+  let last = buffer.len() - 1;
+  buffer[last] = 0;
+  let name = unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_str().ok()?;
+  name.split('.').next().filter(|name| !name.is_empty()).map(|name| name.to_string())
+}
+
+/// The operating system as reported by rust, e.g. `linux` or `macos`.
+pub fn os() -> &'static str {
+  env::consts::OS
+}
+
+pub fn arch() -> &'static str {
+  env::consts::ARCH
+}
+
+pub fn username() -> Option<String> {
+  env::var("USER").or_else(|_| env::var("LOGNAME")).ok().filter(|user| !user.is_empty())
+}
+
+/// The `ID` from `/etc/os-release`, e.g. `ubuntu` or `arch`.
+pub fn distro() -> Option<String> {
+  let os_release = std::fs::read_to_string("/etc/os-release").ok()?;
+  os_release
+    .lines()
+    .find_map(|line| line.strip_prefix("ID="))
+    .map(|id| id.trim_matches('"').to_string())
+    .filter(|id| !id.is_empty())
+}
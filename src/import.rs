@@ -1,62 +1,220 @@
+use crate::checksum;
+use crate::diff;
 use crate::errors::DotcopterError;
+use crate::expand;
 use crate::model::{DotFile, DotFileType};
+use glob::Pattern;
 use regex::Regex;
 use slog::Logger;
 use slog::{debug, error, info, o, warn};
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
 
-pub fn scan_dir(log: &Logger, dir: &str) -> Vec<DotFile> {
+pub struct ScanOptions {
+  pub max_depth: usize,
+  pub excludes: Vec<Pattern>,
+  pub cross_mounts: bool,
+  pub include_broken: bool,
+  pub copies: bool,
+}
+
+/// Entries found below a directory. Files that only share the name with a
+/// repository file are not imported, their diffs are kept in `diffs` for
+/// manual review.
+#[derive(Default)]
+pub struct Import {
+  pub dot_files: Vec<DotFile>,
+  pub diffs: Vec<String>,
+}
+
+pub fn scan_dir(log: &Logger, dir: &str, options: &ScanOptions) -> Import {
   let path = Path::new(dir);
   if path.is_dir() {
-    match get_dot_files(log, path) {
-      Ok(links) => links,
+    match get_dot_files(log, path, options) {
+      Ok(import) => import,
       Err(e) => {
         error!(log, "Failed to get symlinks"; "error" => e.to_string());
-        Vec::new()
+        Import::default()
       }
     }
   } else {
     error!(log, "Parameter is not a directory");
-    Vec::new()
+    Import::default()
   }
 }
 
-fn get_dot_files(log: &Logger, dir: &Path) -> Result<Vec<DotFile>, DotcopterError> {
+fn get_dot_files(log: &Logger, dir: &Path, options: &ScanOptions) -> Result<Import, DotcopterError> {
   let current_dir = env::current_dir()?;
-  let links = get_links(log, dir)?;
+  let links = get_links(log, dir, options)?;
   let mut dot_files: Vec<DotFile> = Vec::new();
   for link in links {
     let log = log.new(o!("link" => format!("{}", link.display())));
-    if link.exists() {
-      debug!(log, "Analyzing link");
-      if link_points_into_dir(&log, &link, &current_dir)? {
-        info!(log, "Found dotfile");
-        if let Ok(target) = link.clone().into_os_string().into_string() {
-          let source_path = link_target_to_relative_path(&link, &current_dir)?;
-          if let Ok(source) = source_path.into_os_string().into_string() {
-            let dot_file = DotFile {
-              source,
-              target: replace_home_with_tilde(&log, &target)?,
-              dot_file_type: DotFileType::LINK,
-            };
-            dot_files.push(dot_file);
-          }
+    let source_path = match fs::metadata(&link) {
+      Ok(_) => {
+        debug!(log, "Analyzing link");
+        if link_points_into_dir(&log, &link, &current_dir)? {
+          link_target_to_relative_path(&link, &current_dir)?
+        } else {
+          debug!(log, "Skip link");
+          continue;
         }
-      } else {
-        debug!(log, "Skip link")
+      }
+      Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+        warn!(log, "Symlink loop");
+        continue;
+      }
+      Err(_) => match broken_link_into_dir(&link, &current_dir) {
+        Some(source_path) if options.include_broken => {
+          warn!(log, "Importing broken link"; "source" => format!("{}", source_path.display()));
+          source_path
+        }
+        Some(_) => {
+          warn!(log, "Link broken but points into repository. Use --include-broken to import it.");
+          continue;
+        }
+        None => {
+          warn!(log, "Link broken");
+          continue;
+        }
+      },
+    };
+    info!(log, "Found dotfile");
+    if let (Ok(target), Ok(source)) = (link.into_os_string().into_string(), source_path.into_os_string().into_string()) {
+      dot_files.push(DotFile {
+        source,
+        target: replace_home_with_tilde(&log, &target)?,
+        dot_file_type: DotFileType::LINK,
+        mode: None,
+      });
+    }
+  }
+  let mut import = Import { dot_files, diffs: Vec::new() };
+  if options.copies {
+    let copies = find_copies(log, dir, &current_dir, options)?;
+    import.dot_files.extend(copies.dot_files);
+    import.diffs = copies.diffs;
+  }
+  Ok(import)
+}
+
+/// Proposes copy entries for regular files below `dir` whose content matches a
+/// file in `repo`. Files that only share the name with a repository file are
+/// returned as a diff for manual review. Empty files are skipped, they would
+/// match every empty repository file.
+fn find_copies(log: &Logger, dir: &Path, repo: &Path, options: &ScanOptions) -> Result<Import, DotcopterError> {
+  let repo_options = ScanOptions {
+    max_depth: usize::MAX,
+    excludes: vec![Pattern::new(".git")?],
+    cross_mounts: true,
+    include_broken: false,
+    copies: false,
+  };
+  let mut repo_files: Vec<(PathBuf, u64)> = Vec::new();
+  walk(log, repo, &repo_options, |path, metadata| {
+    if metadata.is_file() {
+      repo_files.push((path, metadata.len()));
+    }
+  })?;
+  let mut files: Vec<(PathBuf, u64)> = Vec::new();
+  walk(log, dir, options, |path, metadata| {
+    if metadata.is_file() && metadata.len() > 0 {
+      files.push((path, metadata.len()));
+    }
+  })?;
+  files.sort();
+
+  let mut repo_hashes: HashMap<PathBuf, Option<String>> = HashMap::new();
+  let mut import = Import::default();
+  for (file, size) in files {
+    let log = log.new(o!("file" => format!("{}", file.display())));
+    let same_size: Vec<&PathBuf> = repo_files.iter().filter(|(_, len)| *len == size).map(|(path, _)| path).collect();
+    let same_name: Vec<&PathBuf> = repo_files
+      .iter()
+      .filter(|(path, _)| comparable_name(path) == comparable_name(&file))
+      .map(|(path, _)| path)
+      .collect();
+    if same_size.is_empty() && same_name.is_empty() {
+      continue;
+    }
+    if file.canonicalize().map(|file| file.starts_with(repo)).unwrap_or(true) {
+      continue;
+    }
+    let hash = match checksum::hash(&file) {
+      Ok(hash) => hash,
+      Err(e) => {
+        warn!(log, "Failed to hash file"; "error" => e.to_string());
+        continue;
+      }
+    };
+    let mut matching = None;
+    for repo_file in same_size {
+      let repo_hash = repo_hashes.entry(repo_file.clone()).or_insert_with(|| match checksum::hash(repo_file) {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+          warn!(log, "Failed to hash repository file"; "source" => format!("{}", repo_file.display()), "error" => e.to_string());
+          None
+        }
+      });
+      if repo_hash.as_ref() == Some(&hash) {
+        matching = Some(repo_file);
+        break;
+      }
+    }
+    if let Some(repo_file) = matching {
+      info!(log, "Found copy"; "source" => format!("{}", repo_file.display()));
+      if let (Some(target), Ok(source)) = (file.to_str(), repo_file.strip_prefix(repo)) {
+        import.dot_files.push(DotFile {
+          source: source.to_string_lossy().into_owned(),
+          target: replace_home_with_tilde(&log, target)?,
+          dot_file_type: DotFileType::COPY,
+          mode: None,
+        });
       }
     } else {
-      warn!(log, "Link broken");
+      for repo_file in same_name {
+        warn!(log, "Same name but different content. Review manually."; "source" => format!("{}", repo_file.display()));
+        import.diffs.push(file_diff(repo_file, &file));
+      }
     }
   }
-  Ok(dot_files)
+  Ok(import)
 }
 
-fn replace_home_with_tilde(log: &Logger, path: &str) -> Result<String, DotcopterError> {
-  if let Some(home_dir) = dirs::home_dir() {
+/// File name without a leading dot, `.vimrc` in home and `vimrc` in the
+/// repository are considered the same file.
+fn comparable_name(path: &Path) -> Option<String> {
+  path
+    .file_name()
+    .map(|name| name.to_string_lossy())
+    .map(|name| name.strip_prefix('.').unwrap_or(&name).to_string())
+}
+
+fn file_diff(old: &Path, new: &Path) -> String {
+  let old_name = old.display().to_string();
+  let new_name = new.display().to_string();
+  match (fs::read(old), fs::read(new)) {
+    (Ok(old), Ok(new)) => match (String::from_utf8(old), String::from_utf8(new)) {
+      (Ok(old), Ok(new)) => diff::unified(&old_name, &old, &new_name, &new),
+      _ => format!("Binary files {} and {} differ\n", old_name, new_name),
+    },
+    (Err(e), _) | (_, Err(e)) => format!("Failed to diff {} and {}: {}\n", old_name, new_name, e),
+  }
+}
+
+/// The path relative to `dir` that a dangling link would point to, if it
+/// points into `dir` at all.
+fn broken_link_into_dir(link: &Path, dir: &Path) -> Option<PathBuf> {
+  let destination = fs::read_link(link).ok()?;
+  let absolute = link.parent().map(|parent| parent.join(&destination)).unwrap_or(destination);
+  expand::normalize(&absolute).strip_prefix(dir).ok().map(|relative| relative.to_path_buf())
+}
+
+pub fn replace_home_with_tilde(log: &Logger, path: &str) -> Result<String, DotcopterError> {
+  if let Some(home_dir) = expand::home_dir().map(PathBuf::from) {
     replace_path_with_tilde(path, home_dir)
   } else {
     warn!(log, "Home dir not set");
@@ -85,27 +243,192 @@ fn link_points_into_dir(log: &Logger, link: &Path, dir: &Path) -> Result<bool, D
   Ok(canonicalized_link.starts_with(dir))
 }
 
-fn get_links(log: &Logger, path: &Path) -> Result<Vec<PathBuf>, DotcopterError> {
+fn get_links(log: &Logger, root: &Path, options: &ScanOptions) -> Result<Vec<PathBuf>, DotcopterError> {
   let mut symlinks: Vec<PathBuf> = Vec::new();
-  let entries = fs::read_dir(path)?;
-  for dir_entry_result in entries {
-    let dir_entry: fs::DirEntry = dir_entry_result?;
-    let entry_path = dir_entry.path();
-    debug!(log, "Analyzing"; "dir_entry" => format!("{}", entry_path.display()));
-    let metadata: fs::Metadata = entry_path.symlink_metadata()?;
+  walk(log, root, options, |path, metadata| {
     if metadata.file_type().is_symlink() {
-      symlinks.push(entry_path.to_path_buf());
+      symlinks.push(path);
     }
-  }
+  })?;
+  symlinks.sort();
   debug!(log, "Found symlinks"; "count" => symlinks.len());
   Ok(symlinks)
 }
 
+/// Calls `visit` for every entry below `root` that is not a directory, without
+/// following links to directories. Directories on other file systems are
+/// skipped unless `cross_mounts` is set, pseudo file systems like `/proc` are
+/// always skipped.
+fn walk<F: FnMut(PathBuf, &fs::Metadata)>(log: &Logger, root: &Path, options: &ScanOptions, mut visit: F) -> Result<(), DotcopterError> {
+  let root_metadata = fs::metadata(root)?;
+  let mut visited = HashSet::new();
+  let mut pending = vec![(root.to_path_buf(), root_metadata.clone(), 1)];
+  while let Some((dir, dir_metadata, depth)) = pending.pop() {
+    if !visited.insert((dir_metadata.dev(), dir_metadata.ino())) {
+      warn!(log, "Directory already visited. Skipping loop."; "dir" => format!("{}", dir.display()));
+      continue;
+    }
+    let entries = match fs::read_dir(&dir) {
+      Ok(entries) => entries,
+      Err(e) => {
+        warn!(log, "Failed to read directory"; "dir" => format!("{}", dir.display()), "error" => e.to_string());
+        continue;
+      }
+    };
+    for dir_entry_result in entries {
+      let dir_entry: fs::DirEntry = dir_entry_result?;
+      let entry_path = dir_entry.path();
+      if is_excluded(root, &entry_path, &options.excludes) {
+        debug!(log, "Excluded"; "dir_entry" => format!("{}", entry_path.display()));
+        continue;
+      }
+      debug!(log, "Analyzing"; "dir_entry" => format!("{}", entry_path.display()));
+      let metadata: fs::Metadata = match entry_path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(e) => {
+          warn!(log, "Failed to read metadata"; "dir_entry" => format!("{}", entry_path.display()), "error" => e.to_string());
+          continue;
+        }
+      };
+      if !metadata.is_dir() {
+        visit(entry_path, &metadata);
+      } else if depth < options.max_depth {
+        if metadata.dev() != root_metadata.dev() && (!options.cross_mounts || is_pseudo_file_system(&entry_path)) {
+          debug!(log, "Skip mount point"; "dir_entry" => format!("{}", entry_path.display()));
+        } else {
+          pending.push((entry_path, metadata, depth + 1));
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+fn is_excluded(root: &Path, path: &Path, excludes: &[Pattern]) -> bool {
+  let relative = path.strip_prefix(root).unwrap_or(path);
+  let file_name = path.file_name().map(Path::new).unwrap_or(path);
+  excludes
+    .iter()
+    .any(|pattern| pattern.matches_path(relative) || pattern.matches_path(file_name) || pattern.matches_path(path))
+}
+
+#[cfg(target_os = "linux")]
+fn is_pseudo_file_system(path: &Path) -> bool {
+  use std::ffi::CString;
+  use std::os::unix::ffi::OsStrExt;
+  const PSEUDO_FILE_SYSTEMS: [libc::c_long; 11] = [
+    0x9fa0,      // proc
+    0x6265_6572, // sysfs
+    0x1cd1,      // devpts
+    0x0027_e0eb, // cgroup
+    0x6367_7270, // cgroup2
+    0x6462_6720, // debugfs
+    0x7472_6163, // tracefs
+    0x7363_6673, // securityfs
+    0xcafe_4a11, // bpf
+    0x6265_6570, // configfs
+    0x6573_5543, // fusectl
+  ];
+  let path = match CString::new(path.as_os_str().as_bytes()) {
+    Ok(path) => path,
+    Err(_) => return false,
+  };
+  let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+  if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+    return false;
+  }
+  PSEUDO_FILE_SYSTEMS.contains(&(stat.f_type as libc::c_long))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_pseudo_file_system(path: &Path) -> bool {
+  path.starts_with("/dev")
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
   use spectral::prelude::*;
 
+  fn a_logger() -> Logger {
+    use slog::Drain;
+    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+    let drain = slog_term::FullFormat::new(plain).build().fuse();
+    Logger::root(drain, o!())
+  }
+
+  #[test]
+  fn test_get_links_recursively() {
+    let root = std::env::temp_dir().join(format!("dotcopter-import-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("a/b/c")).unwrap();
+    fs::create_dir_all(root.join("node_modules")).unwrap();
+    for link in &["top", "a/b/middle", "a/b/c/deep", "node_modules/skipped"] {
+      std::os::unix::fs::symlink("/nowhere", root.join(link)).unwrap();
+    }
+    std::os::unix::fs::symlink(&root, root.join("a/loop")).unwrap();
+    let options = ScanOptions {
+      max_depth: 3,
+      excludes: vec![Pattern::new("node_modules").unwrap()],
+      cross_mounts: false,
+      include_broken: false,
+      copies: false,
+    };
+
+    let links = get_links(&a_logger(), &root, &options).unwrap();
+    let relative: Vec<&Path> = links.iter().map(|link| link.strip_prefix(&root).unwrap()).collect();
+    assert_that(&relative).is_equal_to(vec![Path::new("a/b/middle"), Path::new("a/loop"), Path::new("top")]);
+    fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[test]
+  fn test_find_copies() {
+    let root = std::env::temp_dir().join(format!("dotcopter-import-copies-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("repo/git")).unwrap();
+    fs::create_dir_all(root.join("home")).unwrap();
+    fs::write(root.join("repo/git/gitconfig"), "[user]\n").unwrap();
+    fs::write(root.join("repo/vimrc"), "set nu\n").unwrap();
+    fs::write(root.join("home/.gitconfig"), "[user]\n").unwrap();
+    fs::write(root.join("home/.vimrc"), "set rnu\n").unwrap();
+    fs::write(root.join("home/unrelated"), "nothing\n").unwrap();
+    fs::write(root.join("repo/empty"), "").unwrap();
+    fs::write(root.join("home/.hushlogin"), "").unwrap();
+    let options = ScanOptions {
+      max_depth: 3,
+      excludes: Vec::new(),
+      cross_mounts: false,
+      include_broken: false,
+      copies: true,
+    };
+
+    let repo = root.join("repo").canonicalize().unwrap();
+    let copies = find_copies(&a_logger(), &root.join("home"), &repo, &options).unwrap();
+    assert_that(&copies.dot_files).is_equal_to(vec![DotFile {
+      source: "git/gitconfig".to_string(),
+      target: root.join("home/.gitconfig").to_string_lossy().into_owned(),
+      dot_file_type: DotFileType::COPY,
+      mode: None,
+    }]);
+    assert_that(&copies.diffs).is_equal_to(vec![file_diff(&repo.join("vimrc"), &root.join("home/.vimrc"))]);
+    assert_that(&copies.diffs[0]).contains("+set rnu");
+    fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[test]
+  fn test_broken_link_into_dir() {
+    let root = std::env::temp_dir().join(format!("dotcopter-import-broken-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("home")).unwrap();
+    std::os::unix::fs::symlink("../repo/gone", root.join("home/inside")).unwrap();
+    std::os::unix::fs::symlink("/elsewhere/gone", root.join("home/outside")).unwrap();
+    let repo = root.join("repo");
+
+    assert_that(&broken_link_into_dir(&root.join("home/inside"), &repo)).is_equal_to(Some(PathBuf::from("gone")));
+    assert_that(&broken_link_into_dir(&root.join("home/outside"), &repo)).is_equal_to(None);
+    fs::remove_dir_all(&root).unwrap();
+  }
+
   #[test]
   fn test_replace_path_with_tilde() {
     let home_dir = Path::new("/home/blubb").to_path_buf();
@@ -0,0 +1,28 @@
+/// Quotes a string as a JSON string literal.
+pub fn string(value: &str) -> String {
+  let mut quoted = String::from("\"");
+  for c in value.chars() {
+    match c {
+      '"' => quoted.push_str("\\\""),
+      '\\' => quoted.push_str("\\\\"),
+      '\n' => quoted.push_str("\\n"),
+      '\r' => quoted.push_str("\\r"),
+      '\t' => quoted.push_str("\\t"),
+      c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+      c => quoted.push(c),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// Formats `(key, already encoded value)` pairs as a JSON object.
+pub fn object(fields: &[(&str, String)]) -> String {
+  let fields: Vec<String> = fields.iter().map(|(key, value)| format!("{}:{}", string(key), value)).collect();
+  format!("{{{}}}", fields.join(","))
+}
+
+/// Formats already encoded values as a JSON array.
+pub fn array(values: &[String]) -> String {
+  format!("[{}]", values.join(","))
+}
@@ -0,0 +1,194 @@
+use crate::alternate;
+use crate::expand;
+use crate::files::resolve_existing;
+use crate::marked::{self, Node, Value};
+use crate::validate::{self, problem, Problem};
+use std::path::{Path, PathBuf};
+
+struct Entry<'a> {
+  target: &'a Node,
+  target_path: PathBuf,
+  source: Option<&'a Node>,
+}
+
+/// Looks for entries that are valid on their own but conflict with each other
+/// or with the dotfiles repository in `repo`.
+pub fn lint(text: &str, repo: &Path) -> Vec<Problem> {
+  let documents = match marked::load(text) {
+    Ok(documents) => documents,
+    Err(e) => return vec![validate::syntax_problem(&e)],
+  };
+  let mut problems = Vec::new();
+  let files = match documents.first().and_then(|document| document.get("files")) {
+    Some(Node {
+      value: Value::Mapping(ref entries),
+      ..
+    }) => entries,
+    _ => return problems,
+  };
+  let mut entries = Vec::new();
+  for (target, settings) in files {
+    let source = match settings.value {
+      Value::Mapping(_) => settings.get("src"),
+      _ => Some(settings),
+    };
+    match target.as_str().map(expand::expand_path) {
+      Some(Ok(expanded)) => entries.push(Entry {
+        target,
+        target_path: expand::normalize(Path::new(&expanded)),
+        source,
+      }),
+      Some(Err(e)) => problems.push(problem(target, format!("cannot resolve target: {}", e))),
+      None => {}
+    }
+  }
+  check_duplicates(&entries, &mut problems);
+  check_nesting(&entries, &mut problems);
+  for entry in &entries {
+    check_target_outside_repo(entry, repo, &mut problems);
+    if let Some(source) = entry.source {
+      check_source(source, repo, &mut problems);
+    }
+  }
+  problems
+}
+
+fn check_duplicates(entries: &[Entry], problems: &mut Vec<Problem>) {
+  for (index, entry) in entries.iter().enumerate() {
+    if let Some(first) = entries[..index].iter().find(|other| other.target_path == entry.target_path) {
+      problems.push(problem(
+        entry.target,
+        format!(
+          "duplicate target `{}`, already defined at {}:{}",
+          display(entry.target),
+          first.target.line,
+          first.target.column
+        ),
+      ));
+    }
+  }
+}
+
+fn check_nesting(entries: &[Entry], problems: &mut Vec<Problem>) {
+  for entry in entries {
+    for other in entries {
+      if entry.target_path != other.target_path && entry.target_path.starts_with(&other.target_path) {
+        problems.push(problem(
+          entry.target,
+          format!(
+            "target `{}` is inside target `{}` defined at {}:{}",
+            display(entry.target),
+            display(other.target),
+            other.target.line,
+            other.target.column
+          ),
+        ));
+      }
+    }
+  }
+}
+
+fn check_target_outside_repo(entry: &Entry, repo: &Path, problems: &mut Vec<Problem>) {
+  let parent = match (entry.target_path.parent(), entry.target_path.file_name()) {
+    (Some(parent), Some(file_name)) => resolve_existing(parent).join(file_name),
+    _ => return,
+  };
+  if parent.starts_with(repo) {
+    problems.push(problem(
+      entry.target,
+      format!("target resolves inside the dotfiles repository ({})", parent.display()),
+    ));
+  }
+}
+
+fn check_source(source: &Node, repo: &Path, problems: &mut Vec<Problem>) {
+  let name = match source.as_str() {
+    Some(name) => name,
+    None => return,
+  };
+  let expanded = match expand::expand_path(name) {
+    Ok(expanded) => expanded,
+    Err(e) => {
+      problems.push(problem(source, format!("cannot resolve source: {}", e)));
+      return;
+    }
+  };
+  match repo.join(&expanded).canonicalize() {
+    Ok(path) if !path.starts_with(repo) => problems.push(problem(
+      source,
+      format!("source `{}` is outside the dotfiles repository ({})", name, path.display()),
+    )),
+    Ok(_) => {}
+    Err(_) if alternate::has_alternates(Path::new(&expanded), repo) => {}
+    Err(_) => problems.push(problem(source, format!("source `{}` does not exist", name))),
+  }
+}
+
+fn display(node: &Node) -> &str {
+  node.as_str().unwrap_or("?")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+  use std::fs;
+
+  fn a_repo(name: &str) -> PathBuf {
+    let repo = std::env::temp_dir().join(format!("dotcopter-lint-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&repo);
+    fs::create_dir_all(repo.join("nvim")).unwrap();
+    fs::write(repo.join("vimrc"), "").unwrap();
+    repo.canonicalize().unwrap()
+  }
+
+  fn messages(text: &str, repo: &Path) -> Vec<String> {
+    lint(text, repo).iter().map(|p| p.to_string()).collect()
+  }
+
+  #[test]
+  fn test_duplicate_and_nested_targets() {
+    let repo = a_repo("targets");
+    let s = "
+files:
+    ~/.vimrc: vimrc
+    $HOME/.vimrc: vimrc
+    /opt/dots/.config/nvim: nvim
+    /opt/dots/.config/nvim/./init.lua: vimrc
+";
+    assert_that(&messages(s, &repo)).is_equal_to(vec![
+      "4:5: duplicate target `$HOME/.vimrc`, already defined at 3:5".to_string(),
+      "6:5: target `/opt/dots/.config/nvim/./init.lua` is inside target `/opt/dots/.config/nvim` defined at 5:5".to_string(),
+    ]);
+    fs::remove_dir_all(&repo).unwrap();
+  }
+
+  #[test]
+  fn test_targets_inside_repo_and_bad_sources() {
+    let repo = a_repo("repo");
+    let link = std::env::temp_dir().join(format!("dotcopter-lint-link-{}", std::process::id()));
+    let outside = std::env::temp_dir().join(format!("dotcopter-lint-outside-{}", std::process::id()));
+    let _ = fs::remove_file(&link);
+    std::os::unix::fs::symlink(repo.join("nvim"), &link).unwrap();
+    fs::write(&outside, "").unwrap();
+    let outside = outside.canonicalize().unwrap();
+    let s = format!(
+      "
+files:
+    {}/init.lua: vimrc
+    ~/x: missing
+    ~/y: {}
+",
+      link.display(),
+      outside.display()
+    );
+    assert_that(&messages(&s, &repo)).is_equal_to(vec![
+      format!("3:5: target resolves inside the dotfiles repository ({}/nvim/init.lua)", repo.display()),
+      "4:10: source `missing` does not exist".to_string(),
+      format!("5:10: source `{0}` is outside the dotfiles repository ({0})", outside.display()),
+    ]);
+    fs::remove_file(&link).unwrap();
+    fs::remove_file(&outside).unwrap();
+    fs::remove_dir_all(&repo).unwrap();
+  }
+}
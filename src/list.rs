@@ -0,0 +1,195 @@
+use crate::alternate;
+use crate::expand;
+use crate::json;
+use crate::model::DotFile;
+use std::path::{Path, PathBuf};
+
+pub enum Format {
+  Table,
+  Json,
+  Nul,
+}
+
+/// An entry with its paths resolved for this host.
+pub struct ResolvedEntry<'a> {
+  pub dot_file: &'a DotFile,
+  pub target: String,
+  pub source: String,
+  /// The alternate used instead of the plain source, as found in `repo`.
+  pub alternate: Option<String>,
+  /// The tags from `DOTCOPTER_TAGS` that selected the alternate.
+  pub tags: Vec<String>,
+  pub active: bool,
+}
+
+/// Expands the paths of every entry. Sources are made absolute relative to
+/// `repo`, alternates matching this host replace the plain source. Entries
+/// have no tags of their own, their tags are the ones that selected the
+/// alternate. An entry is active if it would be applied, i.e. its paths
+/// expand and its source exists.
+pub fn resolve<'a>(dot_files: &'a [DotFile], repo: &Path) -> Vec<ResolvedEntry<'a>> {
+  let facts = alternate::Facts::current();
+  dot_files
+    .iter()
+    .map(|dot_file| {
+      let target = expand::expand_target(&dot_file.target);
+      let source = expand::expand_path(&dot_file.source);
+      let selected = source
+        .as_ref()
+        .ok()
+        .and_then(|source| alternate::select_with_tags(Path::new(source), repo, &facts));
+      let source = source.map(|source| {
+        let source = selected
+          .as_ref()
+          .map(|(alternate, _)| alternate.clone())
+          .unwrap_or_else(|| PathBuf::from(source));
+        expand::normalize(&repo.join(source))
+      });
+      let (alternate, tags) = match selected {
+        Some((alternate, tags)) => (Some(alternate.to_string_lossy().into_owned()), tags),
+        None => (None, Vec::new()),
+      };
+      let active = target.is_ok() && source.as_ref().map(|source| source.exists()).unwrap_or(false);
+      ResolvedEntry {
+        dot_file,
+        target: target.unwrap_or_else(|_| dot_file.target.clone()),
+        source: source
+          .map(|source| source.to_string_lossy().into_owned())
+          .unwrap_or_else(|_| dot_file.source.clone()),
+        alternate,
+        tags,
+        active,
+      }
+    })
+    .collect()
+}
+
+pub fn render(entries: &[ResolvedEntry], format: &Format, sources: bool) -> String {
+  match format {
+    Format::Table => render_table(entries),
+    Format::Json => render_json(entries),
+    Format::Nul => entries
+      .iter()
+      .map(|entry| format!("{}\0", if sources { &entry.source } else { &entry.target }))
+      .collect(),
+  }
+}
+
+fn render_table(entries: &[ResolvedEntry]) -> String {
+  let header = ["TARGET", "SOURCE", "TYPE", "TAGS", "ACTIVE"];
+  let rows: Vec<[String; 5]> = entries
+    .iter()
+    .map(|entry| {
+      [
+        entry.target.clone(),
+        entry.source.clone(),
+        entry.dot_file.dot_file_type.to_string(),
+        entry.tags.join(","),
+        if entry.active { "yes" } else { "no" }.to_string(),
+      ]
+    })
+    .collect();
+  let mut widths = header.map(|title| title.len());
+  for row in &rows {
+    for (width, cell) in widths.iter_mut().zip(row.iter()) {
+      *width = (*width).max(cell.chars().count());
+    }
+  }
+  let mut table = String::new();
+  for row in std::iter::once(header.map(|title| title.to_string())).chain(rows) {
+    let cells: Vec<String> = row
+      .iter()
+      .zip(widths.iter())
+      .map(|(cell, width)| format!("{:width$}", cell, width = width))
+      .collect();
+    table.push_str(cells.join("  ").trim_end());
+    table.push('\n');
+  }
+  table
+}
+
+fn render_json(entries: &[ResolvedEntry]) -> String {
+  let objects: Vec<String> = entries
+    .iter()
+    .map(|entry| {
+      json::object(&[
+        ("target", json::string(&entry.target)),
+        ("source", json::string(&entry.source)),
+        ("alternate", entry.alternate.as_deref().map(json::string).unwrap_or_else(|| "null".to_string())),
+        ("type", json::string(&entry.dot_file.dot_file_type.to_string())),
+        ("tags", json::array(&entry.tags.iter().map(|tag| json::string(tag)).collect::<Vec<String>>())),
+        ("active", entry.active.to_string()),
+      ])
+    })
+    .collect();
+  let mut rendered = json::array(&objects);
+  rendered.push('\n');
+  rendered
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::DotFileType;
+  use spectral::prelude::*;
+  use std::env;
+  use std::fs;
+
+  fn entries() -> Vec<DotFile> {
+    vec![
+      DotFile {
+        source: "Cargo.toml".to_string(),
+        target: "/tmp/x".to_string(),
+        dot_file_type: DotFileType::LINK,
+        mode: None,
+      },
+      DotFile {
+        source: "missing".to_string(),
+        target: "/tmp/\"y\"".to_string(),
+        dot_file_type: DotFileType::COPY,
+        mode: None,
+      },
+    ]
+  }
+
+  #[test]
+  fn test_render_formats() {
+    let repo = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dot_files = entries();
+    let resolved = resolve(&dot_files, repo);
+    let root = repo.display();
+
+    assert_that(&render(&resolved, &Format::Table, true)).is_equal_to(format!(
+      "TARGET    SOURCE{pad}  TYPE  TAGS  ACTIVE\n/tmp/x    {root}/Cargo.toml  link        yes\n/tmp/\"y\"  {root}/missing{short}  copy        no\n",
+      root = root,
+      pad = " ".repeat(root.to_string().len() + "/Cargo.toml".len() - "SOURCE".len()),
+      short = " ".repeat("/Cargo.toml".len() - "/missing".len())
+    ));
+    assert_that(&render(&resolved, &Format::Json, true)).is_equal_to(format!(
+      "[{{\"target\":\"/tmp/x\",\"source\":\"{root}/Cargo.toml\",\"alternate\":null,\"type\":\"link\",\"tags\":[],\"active\":true}},\
+{{\"target\":\"/tmp/\\\"y\\\"\",\"source\":\"{root}/missing\",\"alternate\":null,\"type\":\"copy\",\"tags\":[],\"active\":false}}]\n",
+      root = root
+    ));
+    assert_that(&render(&resolved, &Format::Nul, false)).is_equal_to("/tmp/x\0/tmp/\"y\"\0".to_string());
+  }
+
+  #[test]
+  fn test_resolve_alternate() {
+    let repo = env::temp_dir().join(format!("dotcopter-list-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&repo);
+    fs::create_dir_all(repo.join("git")).unwrap();
+    fs::write(repo.join("git/gitconfig"), "").unwrap();
+    fs::write(repo.join("git/gitconfig##default"), "").unwrap();
+    let dot_files = vec![DotFile {
+      source: "git/gitconfig".to_string(),
+      target: "/tmp/gitconfig".to_string(),
+      dot_file_type: DotFileType::LINK,
+      mode: None,
+    }];
+    let resolved = resolve(&dot_files, &repo);
+    assert_that(&resolved[0].alternate).is_equal_to(Some("git/gitconfig##default".to_string()));
+    assert_that(&resolved[0].source).is_equal_to(repo.join("git/gitconfig##default").display().to_string());
+    assert_that(&render(&resolved, &Format::Json, true)).contains("\"alternate\":\"git/gitconfig##default\"");
+    fs::remove_dir_all(&repo).unwrap();
+  }
+}
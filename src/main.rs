@@ -1,12 +1,15 @@
 use crate::errors::DotcopterError;
-use clap::{Arg, Command};
+use clap::{Arg, ArgMatches, Command};
 use slog::{Drain, Level, LevelFilter, Logger};
+use std::env;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::path::Path;
 use std::process::exit;
+use yaml_rust::Yaml;
 use yaml_rust::YamlLoader;
-use yaml_rust::{Yaml, YamlEmitter};
 
 #[cfg(test)]
 extern crate spectral;
@@ -14,13 +17,35 @@ extern crate spectral;
 use clap::crate_version;
 use slog::{error, info, o, warn};
 
+mod adopt;
+mod alternate;
+mod audit;
+mod bundle;
 mod checksum;
+mod chezmoi;
 mod config;
+mod diff;
+mod dotbot;
+mod encryption;
 mod errors;
+mod expand;
+mod export;
 mod files;
+mod host;
 mod import;
+mod json;
+mod lint;
+mod list;
+mod marked;
 mod model;
 mod mutate;
+mod rename;
+mod render;
+mod scan;
+mod secret;
+mod stow;
+mod template;
+mod validate;
 
 fn main() {
   let return_code = _main();
@@ -34,13 +59,37 @@ fn _main() -> i32 {
   let decorator = slog_term::TermDecorator::new().build();
   let drain = slog_term::FullFormat::new(decorator).use_original_order().build().fuse();
   let drain = slog_async::Async::new(drain).chan_size(10_000).build().fuse();
+  let drain = secret::MaskingDrain::new(drain);
   let log = if verbose {
     slog::Logger::root(drain, o!())
   } else {
     slog::Logger::root(LevelFilter::new(drain, Level::Info).fuse(), o!())
   };
 
-  let config_file = matches.value_of("config_file").unwrap();
+  if let Some(home) = matches.value_of("home") {
+    expand::set_home(home);
+  }
+  if let Some(root) = matches.value_of("root") {
+    expand::set_root(root);
+  }
+  if let Some(key_file) = matches.value_of("key_file") {
+    encryption::set_key_file(key_file);
+  }
+  if matches.is_present("backup") {
+    files::enable_backups();
+  }
+
+  if let Some(bundle_apply_matches) = matches.subcommand_matches("bundle-apply") {
+    return apply_bundle(&log, &matches, bundle_apply_matches, force);
+  }
+
+  let config_file = match matches.value_of("config_file") {
+    Some(config_file) => config_file,
+    None => {
+      error!(log, "A configuration file is required for this subcommand.");
+      return 1;
+    }
+  };
   info!(log, "Starting engine"; "config_file" => config_file);
 
   let config = match load_config_file(&log, config_file) {
@@ -51,7 +100,57 @@ fn _main() -> i32 {
     }
   };
 
-  let mut yaml_documents = match YamlLoader::load_from_str(&config) {
+  if is_dotbot(&matches, &config) {
+    return run_dotbot(&log, &matches, &config, force);
+  }
+
+  if matches.subcommand_matches("validate").is_some() {
+    return if report_problems(&log, config_file, &config) {
+      5
+    } else {
+      info!(log, "Configuration is valid");
+      0
+    };
+  }
+
+  if let Some(scan_matches) = matches.subcommand_matches("scan") {
+    let paths: Vec<&str> = scan_matches.values_of("paths").map(|paths| paths.collect()).unwrap_or_else(|| vec!["."]);
+    let mut found = false;
+    for path in paths {
+      for finding in scan::scan(Path::new(path)) {
+        warn!(log, "{}", finding);
+        found = true;
+      }
+    }
+    return if found {
+      9
+    } else {
+      info!(log, "No secrets found");
+      0
+    };
+  }
+
+  if matches.subcommand_matches("lint").is_some() {
+    let repo = match env::current_dir().and_then(|dir| dir.canonicalize()) {
+      Ok(repo) => repo,
+      Err(e) => {
+        error!(log, "Failed to determine current directory."; "error" => e.to_string());
+        return 1;
+      }
+    };
+    let problems = lint::lint(&config, &repo);
+    for problem in &problems {
+      warn!(log, "{}:{}", config_file, problem);
+    }
+    return if problems.is_empty() {
+      info!(log, "No problems found");
+      0
+    } else {
+      6
+    };
+  }
+
+  let mut yaml_documents = match config::load(&config) {
     Ok(yaml) => yaml,
     Err(e) => {
       error!(log, "Failed to parse config file."; "error" => e.to_string());
@@ -62,76 +161,557 @@ fn _main() -> i32 {
     let s = "files:";
     yaml_documents = YamlLoader::load_from_str(s).unwrap();
   }
+  secret::configure(&yaml_documents[0]["secrets"]);
 
   let maybe_ln_matches = matches.subcommand_matches("ln");
   let maybe_cp_matches = matches.subcommand_matches("cp");
   let maybe_apply_matches = matches.subcommand_matches("apply");
   let maybe_import_matches = matches.subcommand_matches("import");
-  if maybe_apply_matches.is_some() {
+  let maybe_rm_matches = matches.subcommand_matches("rm");
+  let maybe_adopt_matches = matches.subcommand_matches("adopt");
+  let maybe_mv_matches = matches.subcommand_matches("mv");
+  let maybe_list_matches = matches.subcommand_matches("list");
+  let maybe_fmt_matches = matches.subcommand_matches("fmt");
+  let maybe_export_matches = matches.subcommand_matches("export");
+  let maybe_render_matches = matches.subcommand_matches("render");
+  let maybe_bundle_matches = matches.subcommand_matches("bundle");
+  let maybe_encrypt_matches = matches.subcommand_matches("encrypt");
+  let maybe_decrypt_matches = matches.subcommand_matches("decrypt");
+  let maybe_audit_matches = matches.subcommand_matches("audit");
+  if let Some(apply_matches) = maybe_apply_matches {
+    if report_problems(&log, config_file, &config) {
+      if apply_matches.is_present("lenient") {
+        warn!(log, "Configuration is invalid. Applying anyway.");
+      } else {
+        error!(log, "Configuration is invalid. Refusing to apply. Use --lenient to apply anyway.");
+        return 5;
+      }
+    }
     let yaml_config = &yaml_documents[0];
     let dot_files: &Yaml = &yaml_config["files"];
     info!(log, "Liftoff! Applying configuration.");
-    files::process_dot_files(&log, dot_files, force);
+    files::process_dot_files(&log, dot_files, &yaml_config["vars"], force);
   } else if let Some(ln_matches) = maybe_ln_matches {
-    let yaml_config = &yaml_documents[0];
     let link_target = ln_matches.value_of("link_target").unwrap();
     let link_name = ln_matches.value_of("link_name").unwrap();
     let log = log.new(o!("link_target" => link_target.to_string(), "link_name" => link_name.to_string()));
     info!(log, "Liftoff! Adding new link to configuration");
     let new_config = mutate::add_dotfiles_to_config(
       &log,
-      yaml_config,
+      &config,
       &[model::DotFile {
         target: link_name.to_string(),
         source: link_target.to_string(),
         dot_file_type: model::DotFileType::LINK,
+        mode: None,
       }],
     );
-    return write_new_yaml(&log, &new_config, config_file);
+    return write_edited_yaml(&log, new_config, config_file);
   } else if let Some(cp_matches) = maybe_cp_matches {
-    let yaml_config = &yaml_documents[0];
     let target = cp_matches.value_of("target").unwrap();
     let source = cp_matches.value_of("source").unwrap();
     let log = log.new(o!("target" => target.to_string(), "source" => source.to_string()));
     info!(log, "Liftoff! Adding new copy to configuration");
-    let new_config = mutate::add_dotfiles_to_config(
-      &log,
-      yaml_config,
-      &[model::DotFile {
-        target: target.to_string(),
-        source: source.to_string(),
-        dot_file_type: model::DotFileType::COPY,
-      }],
-    );
-    return write_new_yaml(&log, &new_config, config_file);
+    let dot_file = model::DotFile {
+      target: target.to_string(),
+      source: source.to_string(),
+      dot_file_type: model::DotFileType::COPY,
+      mode: None,
+    };
+    let dot_files = scan::refuse_secrets(&log, vec![dot_file], cp_matches.is_present("allow_secrets"));
+    if dot_files.is_empty() {
+      return 9;
+    }
+    let new_config = mutate::add_dotfiles_to_config(&log, &config, &dot_files);
+    return write_edited_yaml(&log, new_config, config_file);
+  } else if let Some(rm_matches) = maybe_rm_matches {
+    let yaml_config = &yaml_documents[0];
+    let uninstall = rm_matches.is_present("uninstall");
+    let restore = rm_matches.is_present("restore");
+    info!(log, "Liftoff! Removing entries from configuration");
+    let dot_files = config::parse_dot_files(&log, &yaml_config["files"]);
+    let mut targets = Vec::new();
+    let mut kept_entries = false;
+    let facts = alternate::Facts::current();
+    for requested_target in rm_matches.values_of("targets").unwrap() {
+      let matching: Vec<&model::DotFile> = dot_files
+        .iter()
+        .filter(|dot_file| expand::same_path(&dot_file.target, requested_target))
+        .collect();
+      if matching.is_empty() {
+        warn!(log, "No entry for target"; "target" => requested_target);
+      }
+      for dot_file in matching {
+        if uninstall && !files::uninstall_dot_file(&log, dot_file, &yaml_config["vars"], &facts, restore) {
+          error!(log, "Keeping entry in configuration, it was not uninstalled"; "target" => &dot_file.target);
+          kept_entries = true;
+          continue;
+        }
+        targets.push(dot_file.target.clone());
+      }
+    }
+    if !targets.is_empty() {
+      let new_config = mutate::remove_dotfiles_from_config(&log, &config, &targets);
+      let return_code = write_edited_yaml(&log, new_config, config_file);
+      if return_code != 0 {
+        return return_code;
+      }
+    }
+    if kept_entries {
+      return 1;
+    }
+  } else if let Some(adopt_matches) = maybe_adopt_matches {
+    let file = adopt_matches.value_of("file").unwrap();
+    let log = log.new(o!("file" => file.to_string()));
+    info!(log, "Liftoff! Adopting file into repository");
+    let allow_secrets = adopt_matches.is_present("allow_secrets");
+    if let Err(e) = adopt::adopt(&log, &config, config_file, file, adopt_matches.value_of("repo_path"), allow_secrets) {
+      error!(log, "Failed to adopt file"; "error" => e.to_string());
+      return 7;
+    }
+  } else if let Some(mv_matches) = maybe_mv_matches {
+    let yaml_config = &yaml_documents[0];
+    let old = mv_matches.value_of("old").unwrap();
+    let new = mv_matches.value_of("new").unwrap();
+    let log = log.new(o!("old" => old.to_string(), "new" => new.to_string()));
+    let dot_files = config::parse_dot_files(&log, &yaml_config["files"]);
+    if dot_files.iter().any(|dot_file| expand::same_path(&dot_file.target, old)) {
+      info!(log, "Liftoff! Retargeting entry");
+      if let Err(e) = rename::retarget(&log, &config, config_file, &dot_files, old, new) {
+        error!(log, "Failed to retarget entry"; "error" => e.to_string());
+        return 7;
+      }
+    } else {
+      info!(log, "Liftoff! Renaming file in repository");
+      if let Err(e) = rename::rename(&log, &config, config_file, &dot_files, old, new) {
+        error!(log, "Failed to rename file"; "error" => e.to_string());
+        return 7;
+      }
+    }
+  } else if let Some(list_matches) = maybe_list_matches {
+    let yaml_config = &yaml_documents[0];
+    let dot_files = config::parse_dot_files(&log, &yaml_config["files"]);
+    return print_list(&log, list_matches, &dot_files);
+  } else if let Some(fmt_matches) = maybe_fmt_matches {
+    if !Path::new(config_file).exists() {
+      error!(log, "Cannot format a configuration that does not exist");
+      return 8;
+    }
+    let formatted = match mutate::format_config(&config) {
+      Some(formatted) => formatted,
+      None => {
+        error!(log, "Cannot format this configuration. Only block style with a single document is supported.");
+        return 8;
+      }
+    };
+    if formatted == config {
+      info!(log, "Configuration is already formatted");
+    } else if fmt_matches.is_present("check") {
+      warn!(log, "Configuration is not formatted");
+      return 8;
+    } else {
+      return write_new_yaml(&log, &formatted, config_file);
+    }
+  } else if let Some(export_matches) = maybe_export_matches {
+    let yaml_config = &yaml_documents[0];
+    let repo = match env::current_dir() {
+      Ok(repo) => repo,
+      Err(e) => {
+        error!(log, "Failed to determine current directory."; "error" => e.to_string());
+        return 1;
+      }
+    };
+    info!(log, "Liftoff! Exporting configuration");
+    let dot_files = config::parse_dot_files(&log, &yaml_config["files"]);
+    let exported = match export_matches.value_of("format") {
+      Some("home-manager") => export::home_manager_module(&log, &dot_files, &yaml_config["vars"], &repo),
+      Some("ansible") => export::ansible_playbook(&log, &dot_files, &yaml_config["vars"], &repo),
+      _ => Ok(export::shell_script(&log, &dot_files, &yaml_config["vars"], &repo)),
+    };
+    match exported {
+      Ok(exported) => print!("{}", exported),
+      Err(e) => {
+        error!(log, "Failed to export configuration"; "error" => e.to_string());
+        return 1;
+      }
+    }
+  } else if let Some(bundle_matches) = maybe_bundle_matches {
+    let yaml_config = &yaml_documents[0];
+    let out = bundle_matches.value_of("out").unwrap();
+    let log = log.new(o!("bundle" => out.to_string()));
+    info!(log, "Liftoff! Bundling configuration and sources");
+    let dot_files = config::parse_dot_files(&log, &yaml_config["files"]);
+    let result = env::current_dir()
+      .map_err(DotcopterError::from)
+      .and_then(|repo| bundle::create(&log, &config, &dot_files, &repo, Path::new(out)));
+    if let Err(e) = result {
+      error!(log, "Failed to write bundle"; "error" => e.to_string());
+      return 4;
+    }
+    info!(log, "Successfully written bundle");
+  } else if let Some(encrypt_matches) = maybe_encrypt_matches {
+    let yaml_config = &yaml_documents[0];
+    let file = encrypt_matches.value_of("file").unwrap();
+    let log = log.new(o!("file" => file.to_string()));
+    info!(log, "Liftoff! Encrypting file into repository");
+    let dot_files = config::parse_dot_files(&log, &yaml_config["files"]);
+    if let Err(e) = encryption::encrypt_file(&log, &config, config_file, &dot_files, file, encrypt_matches.value_of("repo_path")) {
+      error!(log, "Failed to encrypt file"; "error" => e.to_string());
+      return 7;
+    }
+  } else if let Some(decrypt_matches) = maybe_decrypt_matches {
+    let source = decrypt_matches.value_of("source").unwrap();
+    let result = fs::read(source)
+      .map_err(DotcopterError::from)
+      .and_then(|encrypted| encryption::decrypt(&encrypted))
+      .and_then(|plaintext| io::stdout().write_all(&plaintext).map_err(DotcopterError::from));
+    if let Err(e) = result {
+      error!(log, "Failed to decrypt file"; "source" => source, "error" => e.to_string());
+      return 7;
+    }
+  } else if let Some(audit_matches) = maybe_audit_matches {
+    let yaml_config = &yaml_documents[0];
+    let repo = match env::current_dir() {
+      Ok(repo) => repo,
+      Err(e) => {
+        error!(log, "Failed to determine current directory."; "error" => e.to_string());
+        return 1;
+      }
+    };
+    let dot_files = config::parse_dot_files(&log, &yaml_config["files"]);
+    let home = expand::home_dir();
+    let findings = audit::audit(&dot_files, &repo, home.as_deref().map(Path::new));
+    let format = match audit_matches.value_of("format") {
+      Some("json") => audit::Format::Json,
+      _ => audit::Format::Text,
+    };
+    print!("{}", audit::render(&findings, &format));
+    if !findings.is_empty() {
+      return 10;
+    }
+    info!(log, "No risky permissions found");
+  } else if let Some(render_matches) = maybe_render_matches {
+    let yaml_config = &yaml_documents[0];
+    return render_home(&log, render_matches, yaml_config);
+  } else if let Some(stow_values) = maybe_import_matches.and_then(|import_matches| import_matches.values_of("from_stow")) {
+    let import_matches = maybe_import_matches.unwrap();
+    let stow_values: Vec<String> = stow_values.map(|value| value.to_string()).collect();
+    let stow_dir = Path::new(&stow_values[0]);
+    let log = log.new(o!("stow_directory" => stow_values[0].clone()));
+    let target_dir = match import_matches.value_of("stow_target") {
+      Some(target_dir) => Path::new(target_dir).to_path_buf(),
+      None => match stow_dir.canonicalize().ok().and_then(|stow_dir| stow_dir.parent().map(Path::to_path_buf)) {
+        Some(target_dir) => target_dir,
+        None => {
+          error!(log, "Failed to determine the stow target directory");
+          return 1;
+        }
+      },
+    };
+    info!(log, "Liftoff! Importing stow packages to configuration"; "target_directory" => format!("{}", target_dir.display()));
+    let dot_files = match stow::scan_packages(&log, stow_dir, &target_dir, &stow_values[1..]) {
+      Ok(dot_files) => scan::refuse_secrets(&log, dot_files, import_matches.is_present("allow_secrets")),
+      Err(e) => {
+        error!(log, "Failed to read stow packages"; "error" => e.to_string());
+        return 1;
+      }
+    };
+    if !dot_files.is_empty() {
+      let new_config = mutate::add_dotfiles_to_config(&log, &config, &dot_files);
+      let return_code = write_edited_yaml(&log, new_config, config_file);
+      if return_code == 0 && import_matches.is_present("unstow") {
+        stow::relink(&log, &dot_files);
+      }
+      return return_code;
+    }
+  } else if let Some(chezmoi_dir) = maybe_import_matches.and_then(|import_matches| import_matches.value_of("from_chezmoi")) {
+    let allow_secrets = maybe_import_matches.unwrap().is_present("allow_secrets");
+    let log = log.new(o!("chezmoi_directory" => chezmoi_dir.to_string()));
+    info!(log, "Liftoff! Importing chezmoi source state to configuration");
+    let import = match chezmoi::scan_source(&log, Path::new(chezmoi_dir)) {
+      Ok(import) => import,
+      Err(e) => {
+        error!(log, "Failed to read chezmoi source directory"; "error" => e.to_string());
+        return 1;
+      }
+    };
+    for problem in &import.problems {
+      warn!(log, "Not imported as is: {}", problem);
+    }
+    let dot_files = scan::refuse_secrets(&log, import.dot_files, allow_secrets);
+    if !dot_files.is_empty() {
+      let new_config = mutate::add_dotfiles_to_config(&log, &config, &dot_files);
+      return write_edited_yaml(&log, new_config, config_file);
+    }
   } else if let Some(import_matches) = maybe_import_matches {
     let dir = import_matches.value_of("dir").unwrap();
     let log = log.new(o!("import_directory" => dir.to_string()));
-    let yaml_config = &yaml_documents[0];
+    let options = match scan_options(import_matches) {
+      Ok(options) => options,
+      Err(e) => {
+        error!(log, "Invalid import options"; "error" => e.to_string());
+        return 1;
+      }
+    };
     info!(log, "Liftoff! Importing to configuration");
-    let dot_files = import::scan_dir(&log, dir);
+    let import = import::scan_dir(&log, dir, &options);
+    for diff in &import.diffs {
+      print!("{}", diff);
+    }
+    let dot_files = scan::refuse_secrets(&log, import.dot_files, import_matches.is_present("allow_secrets"));
     if !dot_files.is_empty() {
-      let new_config = mutate::add_dotfiles_to_config(&log, yaml_config, &dot_files);
-      return write_new_yaml(&log, &new_config, config_file);
+      let new_config = mutate::add_dotfiles_to_config(&log, &config, &dot_files);
+      return write_edited_yaml(&log, new_config, config_file);
     }
   }
   0
 }
 
-fn write_new_yaml(log: &Logger, document: &Yaml, config_file: &str) -> i32 {
-  let mut out_str = String::new();
-  {
-    let mut emitter = YamlEmitter::new(&mut out_str);
-    match emitter.dump(document) {
-      Ok(_) => {}
+fn print_list(log: &Logger, list_matches: &ArgMatches, dot_files: &[model::DotFile]) -> i32 {
+  let repo = match env::current_dir() {
+    Ok(repo) => repo,
+    Err(e) => {
+      error!(log, "Failed to determine current directory."; "error" => e.to_string());
+      return 1;
+    }
+  };
+  let format = match list_matches.value_of("format") {
+    Some("json") => list::Format::Json,
+    Some("nul") => list::Format::Nul,
+    _ => list::Format::Table,
+  };
+  let entries = list::resolve(dot_files, &repo);
+  print!("{}", list::render(&entries, &format, list_matches.value_of("paths") == Some("source")));
+  0
+}
+
+fn render_home(log: &Logger, render_matches: &ArgMatches, yaml_config: &Yaml) -> i32 {
+  let options = match render_options(render_matches) {
+    Ok(options) => options,
+    Err(e) => {
+      error!(log, "Invalid render options"; "error" => e.to_string());
+      return 1;
+    }
+  };
+  let repo = match env::current_dir() {
+    Ok(repo) => repo,
+    Err(e) => {
+      error!(log, "Failed to determine current directory."; "error" => e.to_string());
+      return 1;
+    }
+  };
+  info!(log, "Liftoff! Rendering configuration"; "home" => &options.home);
+  let dot_files = config::parse_dot_files(log, &yaml_config["files"]);
+  let tree = render::build(log, &dot_files, &yaml_config["vars"], &repo, &options);
+  let result = if let Some(out) = render_matches.value_of("out") {
+    File::create(out)
+      .map_err(DotcopterError::from)
+      .and_then(|file| tree.write_tar(std::io::BufWriter::new(file), &options))
+  } else {
+    tree.write_dir(log, Path::new(render_matches.value_of("out_dir").unwrap()), &options)
+  };
+  match result {
+    Ok(_) => {
+      info!(log, "Successfully rendered configuration");
+      0
+    }
+    Err(e) => {
+      error!(log, "Failed to write rendered files"; "error" => e.to_string());
+      4
+    }
+  }
+}
+
+fn render_options(matches: &ArgMatches) -> Result<render::RenderOptions, DotcopterError> {
+  let home = expand::home_dir().ok_or_else(|| DotcopterError::Expansion("home dir not set".to_string()))?;
+  if !Path::new(&home).is_absolute() {
+    return Err(DotcopterError::Message("--home must be an absolute path".to_string()));
+  }
+  let repo_dir = match matches.value_of("repo_dir") {
+    Some(repo_dir) => repo_dir.to_string(),
+    None => format!("{}/.dotfiles", home.trim_end_matches('/')),
+  };
+  let owner = matches.value_of("owner").unwrap();
+  let (uid, gid) = match owner.split_once(':').map(|(uid, gid)| (uid.parse(), gid.parse())) {
+    Some((Ok(uid), Ok(gid))) => (uid, gid),
+    _ => return Err(DotcopterError::Message(format!("--owner must be uid:gid, got {}", owner))),
+  };
+  let mtime = match env::var("SOURCE_DATE_EPOCH") {
+    Ok(epoch) => epoch
+      .parse()
+      .map_err(|_| DotcopterError::Message("SOURCE_DATE_EPOCH must be a number".to_string()))?,
+    Err(_) => 0,
+  };
+  Ok(render::RenderOptions {
+    home,
+    repo_dir,
+    uid,
+    gid,
+    mtime,
+  })
+}
+
+/// Whether `config` is a dotbot configuration, as given with
+/// `--config-format` or detected from its content.
+fn is_dotbot(matches: &ArgMatches, config: &str) -> bool {
+  match matches.value_of("config_format") {
+    Some("dotbot") => true,
+    Some(_) => false,
+    None => dotbot::is_dotbot(config),
+  }
+}
+
+/// Unpacks a bundle into the cache and applies its configuration from there,
+/// so links point into the cache directory.
+fn apply_bundle(log: &Logger, matches: &ArgMatches, bundle_apply_matches: &ArgMatches, force: bool) -> i32 {
+  let bundle_file = bundle_apply_matches.value_of("bundle").unwrap();
+  let log = log.new(o!("bundle" => bundle_file.to_string()));
+  let dir = match bundle::unpack(&log, Path::new(bundle_file)) {
+    Ok(dir) => dir,
+    Err(e) => {
+      error!(log, "Failed to unpack bundle"; "error" => e.to_string());
+      return 1;
+    }
+  };
+  if let Err(e) = env::set_current_dir(&dir) {
+    error!(log, "Failed to change to bundle directory"; "error" => e.to_string());
+    return 1;
+  }
+  let config = match load_config_file(&log, bundle::CONFIG) {
+    Ok(config) => config,
+    Err(e) => {
+      error!(log, "Failed to load config file."; "error" => e.to_string());
+      return 1;
+    }
+  };
+  if is_dotbot(matches, &config) {
+    let log = log.new(o!("format" => "dotbot"));
+    return match dotbot::parse(&config) {
+      Ok(parsed) => apply_dotbot(&log, &parsed, bundle_apply_matches.is_present("lenient"), force),
       Err(e) => {
-        error!(log, "Failed to write config file."; "error" => format!("{:?}", e));
-        return 3;
+        error!(log, "Failed to parse dotbot configuration."; "error" => e);
+        2
       }
+    };
+  }
+  if report_problems(&log, bundle::CONFIG, &config) {
+    if bundle_apply_matches.is_present("lenient") {
+      warn!(log, "Configuration is invalid. Applying anyway.");
+    } else {
+      error!(log, "Configuration is invalid. Refusing to apply. Use --lenient to apply anyway.");
+      return 5;
+    }
+  }
+  let yaml_documents = match config::load(&config) {
+    Ok(yaml) => yaml,
+    Err(e) => {
+      error!(log, "Failed to parse config file."; "error" => e.to_string());
+      return 2;
+    }
+  };
+  if let Some(yaml_config) = yaml_documents.first() {
+    secret::configure(&yaml_config["secrets"]);
+    info!(log, "Liftoff! Applying bundled configuration.");
+    files::process_dot_files(&log, &yaml_config["files"], &yaml_config["vars"], force);
+  }
+  0
+}
+
+/// Applies a dotbot configuration, refusing an invalid one unless `lenient`.
+fn apply_dotbot(log: &Logger, parsed: &dotbot::DotbotConfig, lenient: bool, force: bool) -> i32 {
+  if !parsed.problems.is_empty() {
+    for problem in &parsed.problems {
+      error!(log, "{}", problem);
+    }
+    if lenient {
+      warn!(log, "Configuration is invalid. Applying anyway.");
+    } else {
+      error!(log, "Configuration is invalid. Refusing to apply. Use --lenient to apply anyway.");
+      return 5;
+    }
+  }
+  info!(log, "Liftoff! Applying dotbot configuration.");
+  dotbot::apply(log, &parsed.steps, force);
+  0
+}
+
+/// Dotbot configurations can be applied, listed and validated. They are never
+/// rewritten, the other subcommands refuse to work on them.
+fn run_dotbot(log: &Logger, matches: &ArgMatches, config: &str, force: bool) -> i32 {
+  let log = log.new(o!("format" => "dotbot"));
+  let parsed = match dotbot::parse(config) {
+    Ok(parsed) => parsed,
+    Err(e) => {
+      error!(log, "Failed to parse dotbot configuration."; "error" => e);
+      return 2;
+    }
+  };
+  if matches.subcommand_matches("validate").is_some() {
+    for problem in &parsed.problems {
+      error!(log, "{}", problem);
+    }
+    return if parsed.problems.is_empty() {
+      info!(log, "Configuration is valid");
+      0
+    } else {
+      5
+    };
+  }
+  if let Some(apply_matches) = matches.subcommand_matches("apply") {
+    return apply_dotbot(&log, &parsed, apply_matches.is_present("lenient"), force);
+  }
+  for problem in &parsed.problems {
+    warn!(log, "{}", problem);
+  }
+  if let Some(list_matches) = matches.subcommand_matches("list") {
+    print_list(&log, list_matches, &parsed.dot_files())
+  } else {
+    error!(log, "Only apply, list and validate support dotbot configurations.");
+    1
+  }
+}
+
+fn scan_options(matches: &ArgMatches) -> Result<import::ScanOptions, DotcopterError> {
+  let max_depth = match matches.value_of("max_depth") {
+    Some(max_depth) => max_depth
+      .parse()
+      .map_err(|_| DotcopterError::Message("--max-depth must be a number".to_string()))?,
+    None => usize::MAX,
+  };
+  let excludes = matches
+    .values_of("exclude")
+    .map(|excludes| excludes.map(glob::Pattern::new).collect::<Result<Vec<_>, _>>())
+    .unwrap_or_else(|| Ok(Vec::new()))?;
+  Ok(import::ScanOptions {
+    max_depth,
+    excludes,
+    cross_mounts: matches.is_present("cross_mounts"),
+    include_broken: matches.is_present("include_broken"),
+    copies: matches.is_present("copies"),
+  })
+}
+
+fn report_problems(log: &Logger, config_file: &str, config: &str) -> bool {
+  let problems = validate::validate(config);
+  for problem in &problems {
+    error!(log, "{}:{}", config_file, problem);
+  }
+  !problems.is_empty()
+}
+
+/// Writes the edited configuration, or reports why it could not be edited.
+fn write_edited_yaml(log: &Logger, new_config: Result<String, DotcopterError>, config_file: &str) -> i32 {
+  match new_config {
+    Ok(new_config) => write_new_yaml(log, &new_config, config_file),
+    Err(e) => {
+      error!(log, "Failed to edit configuration"; "error" => e.to_string());
+      4
     }
   }
-  out_str.push('\n');
-  match write_config_file(config_file, &out_str) {
+}
+
+fn write_new_yaml(log: &Logger, content: &str, config_file: &str) -> i32 {
+  match write_config_file(config_file, content) {
     Ok(_) => info!(log, "Successfully written configuration"),
     Err(e) => {
       error!(log, "Failed to write config file."; "error" => e.to_string());
@@ -167,6 +747,12 @@ fn create_app<'a>() -> Command<'a> {
     .about("manages dotfiles installation")
     .subcommand_required(true)
     .arg(Arg::new("force").short('f').long("force").takes_value(false))
+    .arg(
+      Arg::new("backup")
+        .long("backup")
+        .help("keep targets replaced with --force as <target>.dotcopter-backup")
+        .takes_value(false),
+    )
     .arg(
       Arg::new("verbose")
         .long("verbose")
@@ -175,8 +761,45 @@ fn create_app<'a>() -> Command<'a> {
         .required(false)
         .takes_value(false),
     )
-    .arg(Arg::new("config_file").required(true))
-    .subcommand(Command::new("apply").about("applies a dotfile configuration"))
+    .arg(
+      Arg::new("config_format")
+        .long("config-format")
+        .help("format of the configuration file, detected from its content by default")
+        .possible_values(["dotcopter", "dotbot"])
+        .takes_value(true),
+    )
+    .arg(
+      Arg::new("home")
+        .long("home")
+        .help("home directory to use for ~, $HOME and the XDG directories")
+        .takes_value(true)
+        .global(true),
+    )
+    .arg(
+      Arg::new("root")
+        .long("root")
+        .help("directory every target is placed below, like DESTDIR")
+        .takes_value(true)
+        .global(true),
+    )
+    .arg(
+      Arg::new("key_file")
+        .long("key-file")
+        .help("file to derive the key of encrypted entries from instead of a passphrase")
+        .takes_value(true)
+        .global(true),
+    )
+    .arg(Arg::new("config_file").help("required by every subcommand except bundle-apply"))
+    .subcommand(
+      Command::new("apply").about("applies a dotfile configuration").arg(
+        Arg::new("lenient")
+          .long("lenient")
+          .help("apply even if the configuration is invalid")
+          .takes_value(false),
+      ),
+    )
+    .subcommand(Command::new("validate").about("checks a dotfile configuration for mistakes"))
+    .subcommand(Command::new("lint").about("checks for conflicting targets and sources outside the repository"))
     .subcommand(
       Command::new("ln")
         .about("adds new link to configuration")
@@ -187,11 +810,236 @@ fn create_app<'a>() -> Command<'a> {
       Command::new("cp")
         .about("adds new copy to configuration")
         .arg(Arg::new("source").required(true))
-        .arg(Arg::new("target").required(true)),
+        .arg(Arg::new("target").required(true))
+        .arg(allow_secrets_arg().help("add the entry even if the source looks like it contains secrets, the source is in the repository either way")),
+    )
+    .subcommand(
+      Command::new("rm")
+        .about("removes entries from configuration")
+        .arg(Arg::new("targets").required(true).multiple_values(true))
+        .arg(
+          Arg::new("uninstall")
+            .long("uninstall")
+            .help("also remove installed links and copies that still match their source")
+            .takes_value(false),
+        )
+        .arg(
+          Arg::new("restore")
+            .long("restore")
+            .requires("uninstall")
+            .help("put back the file that was replaced on apply --backup")
+            .takes_value(false),
+        ),
+    )
+    .subcommand(
+      Command::new("adopt")
+        .about("moves a file into the repository, adds it to configuration and links it")
+        .arg(Arg::new("file").required(true))
+        .arg(Arg::new("repo_path").required(false))
+        .arg(allow_secrets_arg()),
+    )
+    .subcommand(
+      Command::new("encrypt")
+        .about("encrypts a file into the repository and adds it to configuration")
+        .arg(Arg::new("file").required(true))
+        .arg(Arg::new("repo_path").required(false)),
+    )
+    .subcommand(
+      Command::new("decrypt")
+        .about("prints the content of an encrypted file")
+        .arg(Arg::new("source").required(true)),
+    )
+    .subcommand(
+      Command::new("mv")
+        .about("renames a file in the repository or the target of an entry and updates configuration and links")
+        .arg(Arg::new("old").required(true))
+        .arg(Arg::new("new").required(true)),
+    )
+    .subcommand(
+      Command::new("list")
+        .about("prints the resolved entries of the configuration with the tags that selected their alternate")
+        .arg(
+          Arg::new("format")
+            .long("format")
+            .possible_values(["table", "json", "nul"])
+            .default_value("table")
+            .help("output format, nul prints NUL separated paths"),
+        )
+        .arg(
+          Arg::new("paths")
+            .long("paths")
+            .possible_values(["target", "source"])
+            .default_value("target")
+            .help("which paths to print with --format nul"),
+        ),
+    )
+    .subcommand(
+      Command::new("fmt").about("rewrites the configuration in canonical style").arg(
+        Arg::new("check")
+          .long("check")
+          .help("only check if the configuration is formatted")
+          .takes_value(false),
+      ),
+    )
+    .subcommand(
+      Command::new("export")
+        .about("prints the configuration for other tools to install it without dotcopter")
+        .arg(
+          Arg::new("format")
+            .long("format")
+            .possible_values(["sh", "home-manager", "ansible"])
+            .required(true)
+            .help("sh prints a POSIX shell script, home-manager a Nix module and ansible a playbook"),
+        ),
+    )
+    .subcommand(
+      Command::new("bundle")
+        .about("packs the configuration and its sources into an archive for offline machines")
+        .arg(
+          Arg::new("out")
+            .short('o')
+            .long("out")
+            .help("gzipped tar archive to write")
+            .takes_value(true)
+            .required(true),
+        ),
+    )
+    .subcommand(
+      Command::new("bundle-apply")
+        .about("checks a bundle against its manifest, unpacks it into the cache and applies it")
+        .arg(Arg::new("bundle").required(true))
+        .arg(
+          Arg::new("lenient")
+            .long("lenient")
+            .help("apply even if the configuration is invalid")
+            .takes_value(false),
+        ),
+    )
+    .subcommand(
+      Command::new("render")
+        .about("writes the files apply would create into a tar archive or directory")
+        .arg(
+          Arg::new("out")
+            .long("out")
+            .help("tar archive to write")
+            .takes_value(true)
+            .required_unless_present("out_dir"),
+        )
+        .arg(
+          Arg::new("out_dir")
+            .long("out-dir")
+            .help("empty directory to write the files to")
+            .takes_value(true)
+            .conflicts_with("out"),
+        )
+        .arg(
+          Arg::new("repo_dir")
+            .long("repo-dir")
+            .help("where the sources of links are placed, defaults to <home>/.dotfiles")
+            .takes_value(true),
+        )
+        .arg(
+          Arg::new("owner")
+            .long("owner")
+            .help("uid:gid of the files in the tar archive")
+            .takes_value(true)
+            .default_value("0:0"),
+        ),
     )
     .subcommand(
       Command::new("import")
         .about("imports dotfiles from a folder into the configuration")
-        .arg(Arg::new("dir").required(true)),
+        .arg(Arg::new("dir").required_unless_present_any(["from_stow", "from_chezmoi"]))
+        .arg(
+          Arg::new("max_depth")
+            .long("max-depth")
+            .help("how many directory levels to descend, 1 only looks at direct children, unlimited by default")
+            .takes_value(true),
+        )
+        .arg(
+          Arg::new("exclude")
+            .long("exclude")
+            .help("glob of paths to skip, matched against the relative path and the file name")
+            .takes_value(true)
+            .multiple_occurrences(true),
+        )
+        .arg(
+          Arg::new("cross_mounts")
+            .long("cross-mounts")
+            .help("descend into other file systems (pseudo file systems are always skipped)")
+            .takes_value(false),
+        )
+        .arg(
+          Arg::new("include_broken")
+            .long("include-broken")
+            .help("import broken links that point into the repository")
+            .takes_value(false),
+        )
+        .arg(
+          Arg::new("copies")
+            .long("copies")
+            .help("also import regular files with the same content as a file in the repository")
+            .takes_value(false),
+        )
+        .arg(
+          Arg::new("from_stow")
+            .long("from-stow")
+            .help("imports the packages of a GNU Stow directory, all packages if none are given")
+            .takes_value(true)
+            .multiple_values(true)
+            .value_name("stow-dir> <packages")
+            .min_values(1)
+            .conflicts_with("dir"),
+        )
+        .arg(
+          Arg::new("stow_target")
+            .long("stow-target")
+            .help("target directory of the stow packages, defaults to the parent of the stow directory")
+            .takes_value(true)
+            .requires("from_stow"),
+        )
+        .arg(
+          Arg::new("unstow")
+            .long("unstow")
+            .help("replaces the links created by stow with dotcopter links")
+            .takes_value(false)
+            .requires("from_stow"),
+        )
+        .arg(
+          Arg::new("from_chezmoi")
+            .long("from-chezmoi")
+            .value_name("dir")
+            .help("imports a chezmoi source directory")
+            .takes_value(true)
+            .conflicts_with_all(&["dir", "from_stow"]),
+        )
+        .arg(allow_secrets_arg()),
     )
+    .subcommand(
+      Command::new("audit")
+        .about("reports risky permissions of installed targets and the repository")
+        .arg(
+          Arg::new("format")
+            .long("format")
+            .possible_values(["text", "json"])
+            .default_value("text")
+            .help("output format"),
+        ),
+    )
+    .subcommand(
+      Command::new("scan")
+        .about("looks for private keys, tokens and other secrets in the repository")
+        .arg(
+          Arg::new("paths")
+            .multiple_values(true)
+            .help("files or directories to scan, defaults to the repository"),
+        ),
+    )
+}
+
+fn allow_secrets_arg<'a>() -> Arg<'a> {
+  Arg::new("allow_secrets")
+    .long("allow-secrets")
+    .help("add files even if they look like they contain secrets")
+    .takes_value(false)
 }
@@ -0,0 +1,157 @@
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, ScanError, TScalarStyle};
+use yaml_rust::Yaml;
+
+/// A YAML node that remembers where it was found in the source text.
+/// Unlike `Yaml`, mappings keep duplicate keys and their order.
+#[derive(Debug, PartialEq)]
+pub struct Node {
+  pub value: Value,
+  pub line: usize,
+  pub column: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+  Scalar(Yaml),
+  Sequence(Vec<Node>),
+  Mapping(Vec<(Node, Node)>),
+  Alias,
+}
+
+impl Node {
+  pub fn as_str(&self) -> Option<&str> {
+    match self.value {
+      Value::Scalar(Yaml::String(ref s)) => Some(s),
+      _ => None,
+    }
+  }
+
+  pub fn is_null(&self) -> bool {
+    self.value == Value::Scalar(Yaml::Null)
+  }
+
+  pub fn get(&self, key: &str) -> Option<&Node> {
+    match self.value {
+      Value::Mapping(ref entries) => entries.iter().rev().find(|(k, _)| k.as_str() == Some(key)).map(|(_, v)| v),
+      _ => None,
+    }
+  }
+
+  pub fn describe(&self) -> &'static str {
+    match self.value {
+      Value::Scalar(Yaml::String(_)) => "a string",
+      Value::Scalar(Yaml::Null) => "an empty value",
+      Value::Scalar(Yaml::Boolean(_)) => "a boolean",
+      Value::Scalar(Yaml::Integer(_)) | Value::Scalar(Yaml::Real(_)) => "a number",
+      Value::Scalar(_) => "a scalar",
+      Value::Sequence(_) => "a list",
+      Value::Mapping(_) => "a mapping",
+      Value::Alias => "an alias",
+    }
+  }
+}
+
+pub fn load(text: &str) -> Result<Vec<Node>, ScanError> {
+  let mut builder = Builder {
+    documents: Vec::new(),
+    stack: Vec::new(),
+  };
+  let mut parser = Parser::new(text.chars());
+  parser.load(&mut builder, true)?;
+  Ok(builder.documents)
+}
+
+enum Partial {
+  Sequence(Node),
+  Mapping(Node, Option<Node>),
+}
+
+struct Builder {
+  documents: Vec<Node>,
+  stack: Vec<Partial>,
+}
+
+impl Builder {
+  fn push(&mut self, node: Node) {
+    match self.stack.last_mut() {
+      None => self.documents.push(node),
+      Some(Partial::Sequence(sequence)) => {
+        if let Value::Sequence(ref mut items) = sequence.value {
+          items.push(node);
+        }
+      }
+      Some(Partial::Mapping(mapping, pending_key)) => match pending_key.take() {
+        None => *pending_key = Some(node),
+        Some(key) => {
+          if let Value::Mapping(ref mut entries) = mapping.value {
+            entries.push((key, node));
+          }
+        }
+      },
+    }
+  }
+}
+
+impl MarkedEventReceiver for Builder {
+  fn on_event(&mut self, event: Event, marker: Marker) {
+    let node = |value| Node {
+      value,
+      line: marker.line(),
+      column: marker.col() + 1,
+    };
+    match event {
+      Event::Scalar(value, style, _, _) => {
+        let scalar = if style == TScalarStyle::Plain {
+          match Yaml::from_str(&value) {
+            // yaml-rust also reads `0o` and `0x` numbers, keep them as written
+            Yaml::Integer(_) if !value.trim_start_matches(&['-', '+'][..]).chars().all(|c| c.is_ascii_digit()) => Yaml::String(value),
+            scalar => scalar,
+          }
+        } else {
+          Yaml::String(value)
+        };
+        self.push(node(Value::Scalar(scalar)));
+      }
+      Event::Alias(_) => self.push(node(Value::Alias)),
+      Event::SequenceStart(_) => self.stack.push(Partial::Sequence(node(Value::Sequence(Vec::new())))),
+      Event::MappingStart(_) => self.stack.push(Partial::Mapping(node(Value::Mapping(Vec::new())), None)),
+      Event::SequenceEnd | Event::MappingEnd => match self.stack.pop() {
+        Some(Partial::Sequence(done)) | Some(Partial::Mapping(done, _)) => self.push(done),
+        None => {}
+      },
+      _ => {}
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[test]
+  fn test_load_keeps_positions_and_duplicates() {
+    let s = "
+files:
+    ~/.vimrc: vimrc
+    ~/.vimrc:
+        src: other
+";
+    let documents = load(s).unwrap();
+    assert_that(&documents).has_length(1);
+    let files = documents[0].get("files").unwrap();
+    match files.value {
+      Value::Mapping(ref entries) => {
+        assert_that(entries).has_length(2);
+        let (key, value) = &entries[1];
+        assert_that(&key.as_str()).is_equal_to(Some("~/.vimrc"));
+        assert_that(&key.line).is_equal_to(4);
+        assert_that(&key.column).is_equal_to(5);
+        assert_that(&value.get("src").and_then(Node::as_str)).is_equal_to(Some("other"));
+        assert_that(&value.get("src").unwrap().line).is_equal_to(5);
+      }
+      _ => panic!("files should be a mapping"),
+    }
+  }
+}
@@ -1,7 +1,23 @@
-#[derive(Debug, PartialEq)]
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum DotFileType {
   LINK,
   COPY,
+  TEMPLATE,
+  ENCRYPTED,
+}
+
+impl fmt::Display for DotFileType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      DotFileType::LINK => write!(f, "link"),
+      DotFileType::COPY => write!(f, "copy"),
+      DotFileType::TEMPLATE => write!(f, "template"),
+      DotFileType::ENCRYPTED => write!(f, "encrypted"),
+    }
+  }
 }
 
 #[derive(Debug, PartialEq)]
@@ -9,4 +25,46 @@ pub struct DotFile {
   pub source: String,
   pub target: String,
   pub dot_file_type: DotFileType,
+  pub mode: Option<u32>,
+}
+
+/// Parses a permission mode like `0600` or `600`. Integers are read by their
+/// digits because YAML turns an unquoted `0600` into the number 600.
+pub fn parse_mode(mode: &str) -> Option<u32> {
+  let digits = mode.strip_prefix("0o").unwrap_or(mode);
+  if digits.is_empty() || digits.len() > 5 {
+    return None;
+  }
+  u32::from_str_radix(digits, 8).ok().filter(|mode| *mode <= 0o7777)
+}
+
+pub fn format_mode(mode: u32) -> String {
+  format!("{:04o}", mode)
+}
+
+/// Builds a dot file for the tests of the modules that take one.
+#[cfg(test)]
+pub fn a_dot_file(source: &str, target: &str, dot_file_type: DotFileType, mode: Option<u32>) -> DotFile {
+  DotFile {
+    source: source.to_string(),
+    target: target.to_string(),
+    dot_file_type,
+    mode,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[test]
+  fn test_parse_mode() {
+    assert_that(&parse_mode("0600")).is_equal_to(Some(0o600));
+    assert_that(&parse_mode("755")).is_equal_to(Some(0o755));
+    assert_that(&parse_mode("0o644")).is_equal_to(Some(0o644));
+    assert_that(&parse_mode("0800")).is_equal_to(None);
+    assert_that(&parse_mode("17777")).is_equal_to(None);
+    assert_that(&format_mode(0o600)).is_equal_to("0600".to_string());
+  }
 }
@@ -1,43 +1,618 @@
+use crate::errors::DotcopterError;
+use crate::marked::{self, Node, Value};
 use crate::model;
 use slog::warn;
 use slog::Logger;
 use yaml_rust::yaml;
-use yaml_rust::Yaml;
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
 
-pub fn add_dotfiles_to_config(log: &Logger, config: &Yaml, dotfiles: &[model::DotFile]) -> Yaml {
-  let mut new_hash = if let Yaml::Hash(config_hash) = config.clone() {
+/// Adds the dotfiles to the `files` section, replacing entries with the same
+/// target. The configuration is edited as text so comments, blank lines,
+/// indentation and the order of the existing entries are preserved. The
+/// comments of a replaced entry are kept.
+pub fn add_dotfiles_to_config(log: &Logger, config: &str, dotfiles: &[model::DotFile]) -> Result<String, DotcopterError> {
+  let mut text = config.to_string();
+  for dotfile in dotfiles {
+    match Layout::parse(&text).map(|layout| layout.upsert(dotfile)) {
+      Some(new_text) => text = new_text,
+      None => return rewrite_config(log, config, |files| add_dotfiles_to_files(files, dotfiles)),
+    }
+  }
+  Ok(text)
+}
+
+/// Removes every entry with one of the given targets from the `files` section,
+/// keeping the rest of the configuration untouched.
+pub fn remove_dotfiles_from_config(log: &Logger, config: &str, targets: &[String]) -> Result<String, DotcopterError> {
+  let mut text = config.to_string();
+  for target in targets {
+    match Layout::parse(&text) {
+      Some(layout) => text = layout.remove(target),
+      None => {
+        return rewrite_config(log, config, |files| {
+          for target in targets {
+            files.remove(&Yaml::String(target.to_string()));
+          }
+        })
+      }
+    }
+  }
+  Ok(text)
+}
+
+/// Points the given entries at their new sources. Only the source scalars are
+/// replaced so comments and formatting of the entries stay as they are.
+/// Entries that cannot be edited in place keep their other settings.
+pub fn update_sources(log: &Logger, config: &str, dotfiles: &[model::DotFile]) -> Result<String, DotcopterError> {
+  let mut text = config.to_string();
+  for dotfile in dotfiles {
+    if let Some(new_text) = Layout::parse(&text).and_then(|layout| layout.set_source(dotfile)) {
+      text = new_text;
+      continue;
+    }
+    text = match Layout::parse(&text) {
+      Some(layout) if !layout.has_target(&dotfile.target) => layout.upsert(dotfile),
+      _ => rewrite_config(log, &text, |files| set_source(files, dotfile))?,
+    };
+  }
+  Ok(text)
+}
+
+/// Gives the entries with target `old` the target `new`. Only the key is
+/// replaced so the settings and comments of the entries stay as they are.
+pub fn update_target(log: &Logger, config: &str, old: &str, new: &str) -> Result<String, DotcopterError> {
+  match Layout::parse(config).and_then(|layout| layout.set_target(old, new)) {
+    Some(new_text) => Ok(new_text),
+    None => rewrite_config(log, config, |files| {
+      if let Some(value) = files.remove(&Yaml::String(old.to_string())) {
+        files.insert(Yaml::String(new.to_string()), value);
+      }
+    }),
+  }
+}
+
+/// Rewrites the `files` section in canonical style: entries sorted by target,
+/// links without options in the short form and lowercase types. Comments and
+/// blank lines above an entry move with it, comments inside an entry move
+/// above it. Entries with unknown settings are kept as
+/// they are. Returns `None` if the layout of the configuration is not supported.
+pub fn format_config(config: &str) -> Option<String> {
+  Layout::parse(config).map(|layout| layout.format())
+}
+
+/// The line structure of a configuration as far as the mutators care about it.
+struct Layout {
+  lines: Vec<String>,
+  newline: &'static str,
+  files: Files,
+}
+
+enum Files {
+  Missing,
+  Empty {
+    key_line: usize,
+    indent: usize,
+  },
+  Block {
+    indent: usize,
+    nested_indent: usize,
+    entries: Vec<EntryLines>,
+  },
+}
+
+/// The lines `start..end` holding a single entry of the `files` section and
+/// the line and column where its source starts.
+struct EntryLines {
+  target: String,
+  start: usize,
+  end: usize,
+  source: Option<(usize, usize)>,
+  dot_file: Option<model::DotFile>,
+}
+
+impl Layout {
+  fn parse(text: &str) -> Option<Layout> {
+    let documents = marked::load(text).ok()?;
+    let lines: Vec<String> = text.lines().map(|line| line.to_string()).collect();
+    let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let files = match documents.len() {
+      0 => Files::Missing,
+      1 => files_of(&lines, &documents[0])?,
+      _ => return None,
+    };
+    Some(Layout { lines, newline, files })
+  }
+
+  fn upsert(mut self, dotfile: &model::DotFile) -> String {
+    match self.files {
+      Files::Missing => {
+        self.lines.push("files:".to_string());
+        self.lines.extend(render_entry(dotfile, 2, 2));
+      }
+      Files::Empty { key_line, indent } => {
+        let rendered = render_entry(dotfile, indent + 2, 2);
+        self.lines.splice(key_line + 1..key_line + 1, rendered);
+      }
+      Files::Block {
+        indent,
+        nested_indent,
+        ref entries,
+      } => {
+        let rendered = render_entry(dotfile, indent, nested_indent);
+        match entries.iter().rev().find(|entry| entry.target == dotfile.target) {
+          Some(entry) => {
+            let rendered = with_comments(&self.lines[entry.start..entry.end], rendered, indent);
+            self.lines.splice(entry.start..entry.end, rendered)
+          }
+          None => {
+            let end = entries.last().map(|entry| entry.end).unwrap_or(0);
+            self.lines.splice(end..end, rendered)
+          }
+        };
+      }
+    }
+    self.to_text()
+  }
+
+  fn remove(mut self, target: &str) -> String {
+    if let Files::Block { ref entries, .. } = self.files {
+      for entry in entries.iter().rev().filter(|entry| entry.target == target) {
+        self.lines.drain(entry.start..entry.end);
+      }
+    }
+    self.to_text()
+  }
+
+  fn has_target(&self, target: &str) -> bool {
+    match self.files {
+      Files::Block { ref entries, .. } => entries.iter().any(|entry| entry.target == target),
+      _ => false,
+    }
+  }
+
+  fn set_target(mut self, old: &str, new: &str) -> Option<String> {
+    let entries = match self.files {
+      Files::Block { ref entries, .. } => entries,
+      _ => return None,
+    };
+    let starts: Vec<usize> = entries.iter().filter(|entry| entry.target == old).map(|entry| entry.start).collect();
+    if starts.is_empty() {
+      return None;
+    }
+    for start in starts {
+      let line = &self.lines[start];
+      let key_start = indentation(line);
+      let key_end = key_start + key_length(&line[key_start..])?;
+      self.lines[start] = format!("{}{}{}", &line[..key_start], scalar(new), &line[key_end..]);
+    }
+    Some(self.to_text())
+  }
+
+  fn set_source(mut self, dotfile: &model::DotFile) -> Option<String> {
+    let (line_index, column) = match self.files {
+      Files::Block { ref entries, .. } => entries.iter().rev().find(|entry| entry.target == dotfile.target)?.source?,
+      _ => return None,
+    };
+    let line = &self.lines[line_index];
+    let start = line.char_indices().nth(column).map(|(index, _)| index)?;
+    let end = start + scalar_length(&line[start..])?;
+    self.lines[line_index] = format!("{}{}{}", &line[..start], scalar(&dotfile.source), &line[end..]);
+    Some(self.to_text())
+  }
+
+  fn format(mut self) -> String {
+    let (indent, nested_indent, entries) = match self.files {
+      Files::Block {
+        indent,
+        nested_indent,
+        ref entries,
+      } if !entries.is_empty() => (indent, nested_indent, entries),
+      _ => return self.to_text(),
+    };
+    let start = leading_comments_start(&self.lines, entries[0].start);
+    let mut blocks: Vec<(&str, Vec<String>)> = Vec::new();
+    let mut gap_start = start;
+    for entry in entries {
+      let mut lines: Vec<String> = self.lines[gap_start..entry.start].to_vec();
+      gap_start = entry.end;
+      let original = &self.lines[entry.start..entry.end];
+      let dot_file = match entry.dot_file {
+        Some(ref dot_file) => dot_file,
+        None => {
+          lines.extend(original.iter().cloned());
+          blocks.push((&entry.target, lines));
+          continue;
+        }
+      };
+      lines.extend(with_comments(original, render_entry(dot_file, indent, nested_indent), indent));
+      blocks.push((&entry.target, lines));
+    }
+    let first_target = blocks[0].0;
+    blocks.sort_by_key(|(target, _)| *target);
+    // The blank lines separating a block from the one above go to the block
+    // that was first before, so none end up right below the `files` key.
+    let separator = blocks[0].1.iter().take_while(|line| line.trim().is_empty()).count();
+    if let Some(index) = blocks.iter().skip(1).position(|(target, _)| *target == first_target) {
+      let blank_lines: Vec<String> = blocks[0].1.drain(..separator).collect();
+      blocks[index + 1].1.splice(0..0, blank_lines);
+    }
+    let end = entries[entries.len() - 1].end;
+    let formatted: Vec<String> = blocks.into_iter().flat_map(|(_, lines)| lines).collect();
+    self.lines.splice(start..end, formatted);
+    self.to_text()
+  }
+
+  fn to_text(&self) -> String {
+    let mut text = self.lines.join(self.newline);
+    text.push_str(self.newline);
+    text
+  }
+}
+
+/// The first of the comment lines right above the line `entry`, `entry` if
+/// there are none.
+fn leading_comments_start(lines: &[String], entry: usize) -> usize {
+  let mut start = entry;
+  for index in (0..entry).rev() {
+    let line = lines[index].trim_start();
+    if line.starts_with('#') {
+      start = index;
+    } else if !line.is_empty() {
+      break;
+    }
+  }
+  start
+}
+
+/// The `rendered` replacement of the `original` lines of an entry with their
+/// comments. A comment on a single line entry stays at its end, the others
+/// move above the entry.
+fn with_comments(original: &[String], mut rendered: Vec<String>, indent: usize) -> Vec<String> {
+  let comments: Vec<(usize, &str)> = original
+    .iter()
+    .enumerate()
+    .filter_map(|(index, line)| comment_start(line).map(|start| (index, line[start..].trim_end())))
+    .collect();
+  let mut lines = Vec::new();
+  match comments.as_slice() {
+    [(0, comment)] if rendered.len() == 1 => rendered[0] = format!("{} {}", rendered[0], comment),
+    _ => lines.extend(comments.iter().map(|(_, comment)| format!("{}{}", " ".repeat(indent), comment))),
+  }
+  lines.extend(rendered);
+  lines
+}
+
+fn files_of(lines: &[String], document: &Node) -> Option<Files> {
+  let root_entries = match document.value {
+    Value::Mapping(ref entries) => entries,
+    _ if document.is_null() => return Some(Files::Missing),
+    _ => return None,
+  };
+  if !root_entries.iter().all(|(key, _)| starts_line(lines, key)) {
+    return None;
+  }
+  let (files_key, files) = match root_entries.iter().rev().find(|(key, _)| key.as_str() == Some("files")) {
+    Some((key, value)) => (key, value),
+    None => return Some(Files::Missing),
+  };
+  if files.is_null() {
+    let key_line = files_key.line - 1;
+    let rest = strip_comment(&lines[key_line]).trim();
+    return if rest.ends_with(':') {
+      Some(Files::Empty {
+        key_line,
+        indent: files_key.column - 1,
+      })
+    } else {
+      None
+    };
+  }
+  let file_entries = match files.value {
+    Value::Mapping(ref entries) => entries,
+    _ => return None,
+  };
+  let mut entries = Vec::new();
+  let mut nested_indent = None;
+  for (key, value) in file_entries {
+    if !starts_line(lines, key) {
+      return None;
+    }
+    let target = key.as_str()?.to_string();
+    let start = key.line - 1;
+    let end = block_end(lines, start, key.column - 1);
+    if let (None, Value::Mapping(ref settings)) = (nested_indent, &value.value) {
+      nested_indent = settings.first().map(|(setting, _)| setting.column.saturating_sub(key.column));
+    }
+    let source = match value.value {
+      Value::Mapping(_) => value.get("src"),
+      _ => Some(value),
+    }
+    .filter(|source| source.as_str().is_some())
+    .map(|source| (source.line - 1, source.column - 1));
+    let dot_file = canonical_dot_file(&target, value);
+    entries.push(EntryLines {
+      target,
+      start,
+      end,
+      source,
+      dot_file,
+    });
+  }
+  let indent = file_entries.first().map(|(key, _)| key.column - 1)?;
+  let default_nested_indent = indent.saturating_sub(files_key.column - 1).max(2);
+  Some(Files::Block {
+    indent,
+    nested_indent: nested_indent.filter(|n| *n > 0).unwrap_or(default_nested_indent),
+    entries,
+  })
+}
+
+/// The entry as a `DotFile` if it only uses settings that can be rendered
+/// again without losing anything.
+fn canonical_dot_file(target: &str, value: &Node) -> Option<model::DotFile> {
+  let mut dot_file = model::DotFile {
+    source: String::new(),
+    target: target.to_string(),
+    dot_file_type: model::DotFileType::LINK,
+    mode: None,
+  };
+  match value.value {
+    Value::Scalar(Yaml::String(ref source)) => dot_file.source = source.to_string(),
+    Value::Mapping(ref settings) => {
+      let mut source = None;
+      for (key, value) in settings {
+        if key.as_str()? == "mode" {
+          let mode = match value.value {
+            Value::Scalar(Yaml::Integer(mode)) => mode.to_string(),
+            _ => value.as_str()?.to_string(),
+          };
+          dot_file.mode = Some(model::parse_mode(&mode)?);
+          continue;
+        }
+        match (key.as_str()?, value.as_str()?) {
+          ("src", src) => source = Some(src.to_string()),
+          ("type", file_type) => {
+            dot_file.dot_file_type = match file_type.to_lowercase().as_ref() {
+              "link" => model::DotFileType::LINK,
+              "copy" => model::DotFileType::COPY,
+              "template" => model::DotFileType::TEMPLATE,
+              "encrypted" => model::DotFileType::ENCRYPTED,
+              _ => return None,
+            }
+          }
+          _ => return None,
+        }
+      }
+      dot_file.source = source?;
+    }
+    _ => return None,
+  }
+  Some(dot_file)
+}
+
+fn starts_line(lines: &[String], node: &Node) -> bool {
+  lines
+    .get(node.line - 1)
+    .map(|line| line.chars().take(node.column - 1).all(|c| c == ' '))
+    .unwrap_or(false)
+}
+
+/// The end of the block that starts with a key at `start`: every following line
+/// that is blank or indented deeper than the key, without trailing blank lines.
+fn block_end(lines: &[String], start: usize, indent: usize) -> usize {
+  let mut end = start + 1;
+  for (index, line) in lines.iter().enumerate().skip(start + 1) {
+    if line.trim().is_empty() {
+      continue;
+    }
+    if indentation(line) <= indent {
+      break;
+    }
+    end = index + 1;
+  }
+  end
+}
+
+fn indentation(line: &str) -> usize {
+  line.chars().take_while(|c| *c == ' ').count()
+}
+
+fn strip_comment(line: &str) -> &str {
+  match comment_start(line) {
+    Some(index) => &line[..index],
+    None => line,
+  }
+}
+
+/// The byte index of the `#` starting a comment, ignoring `#` in quotes or
+/// inside of words.
+fn comment_start(line: &str) -> Option<usize> {
+  let mut quote = None;
+  let mut previous = ' ';
+  for (index, c) in line.char_indices() {
+    match (quote, c) {
+      (None, '#') if previous.is_whitespace() => return Some(index),
+      (None, '"') | (None, '\'') if previous.is_whitespace() || previous == ':' => quote = Some(c),
+      (Some(open), c) if c == open => quote = None,
+      _ => {}
+    }
+    previous = c;
+  }
+  None
+}
+
+/// The length of the single line scalar at the start of `text`, if it ends on
+/// this line.
+fn scalar_length(text: &str) -> Option<usize> {
+  let mut chars = text.char_indices();
+  match chars.next()? {
+    (_, '"') => {
+      let mut escaped = false;
+      for (index, c) in chars {
+        match c {
+          '\\' if !escaped => escaped = true,
+          '"' if !escaped => return Some(index + 1),
+          _ => escaped = false,
+        }
+      }
+      None
+    }
+    (_, '\'') => {
+      let mut previous_quote = None;
+      for (index, c) in chars {
+        match (c, previous_quote) {
+          ('\'', Some(_)) => previous_quote = None,
+          ('\'', None) => previous_quote = Some(index),
+          (_, Some(quote)) => return Some(quote + 1),
+          _ => {}
+        }
+      }
+      previous_quote.map(|quote| quote + 1)
+    }
+    (_, '|') | (_, '>') => None,
+    _ => Some(strip_comment(text).trim_end().len()),
+  }
+}
+
+/// The length of the single line key at the start of `text`.
+fn key_length(text: &str) -> Option<usize> {
+  match text.chars().next()? {
+    '"' | '\'' => scalar_length(text),
+    _ => {
+      let text = strip_comment(text).trim_end();
+      text.find(": ").or_else(|| text.strip_suffix(':').map(|key| key.len()))
+    }
+  }
+}
+
+fn render_entry(dotfile: &model::DotFile, indent: usize, nested_indent: usize) -> Vec<String> {
+  let padding = " ".repeat(indent);
+  let target = scalar(&dotfile.target);
+  match (&dotfile.dot_file_type, dotfile.mode) {
+    (model::DotFileType::LINK, None) => vec![format!("{}{}: {}", padding, target, scalar(&dotfile.source))],
+    (_, mode) => {
+      let nested_padding = " ".repeat(indent + nested_indent);
+      let mut lines = vec![
+        format!("{}{}:", padding, target),
+        format!("{}src: {}", nested_padding, scalar(&dotfile.source)),
+        format!("{}type: {}", nested_padding, dotfile.dot_file_type),
+      ];
+      if let Some(mode) = mode {
+        lines.push(format!("{}mode: \"{}\"", nested_padding, model::format_mode(mode)));
+      }
+      lines
+    }
+  }
+}
+
+/// Formats a string as a plain YAML scalar if it reads back unchanged as both
+/// key and value, otherwise as a double quoted one.
+fn scalar(value: &str) -> String {
+  let probe = format!("{0}: {0}", value);
+  let expected = Yaml::String(value.to_string());
+  let plain = !value.contains('\n')
+    && value.trim() == value
+    && match YamlLoader::load_from_str(&probe) {
+      Ok(documents) => match documents.as_slice() {
+        [Yaml::Hash(hash)] => hash.len() == 1 && hash.get(&expected) == Some(&expected),
+        _ => false,
+      },
+      Err(_) => false,
+    };
+  if plain {
+    return value.to_string();
+  }
+  let mut quoted = String::from("\"");
+  for c in value.chars() {
+    match c {
+      '"' => quoted.push_str("\\\""),
+      '\\' => quoted.push_str("\\\\"),
+      '\n' => quoted.push_str("\\n"),
+      '\t' => quoted.push_str("\\t"),
+      c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+      c => quoted.push(c),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// Edits the configuration as data when it cannot be edited as text. This
+/// loses comments, document markers and every document after the first, so
+/// such configurations are refused instead.
+fn rewrite_config<F: FnOnce(&mut yaml::Hash)>(log: &Logger, config: &str, update_files: F) -> Result<String, DotcopterError> {
+  if config
+    .lines()
+    .any(|line| comment_start(line).is_some() || line.starts_with("---") || line.starts_with("..."))
+  {
+    return Err(DotcopterError::Message(
+      "the configuration can not be edited in place and rewriting it would drop its comments or documents, use block style with a single document".to_string(),
+    ));
+  }
+  warn!(log, "Could not edit configuration in place. Rewriting it.");
+  let document = YamlLoader::load_from_str(config)
+    .ok()
+    .and_then(|mut documents| if documents.is_empty() { None } else { Some(documents.remove(0)) })
+    .unwrap_or(Yaml::Null);
+  let mut new_hash = if let Yaml::Hash(config_hash) = document.clone() {
     config_hash
   } else {
     warn!(log, "Configuration seems invalid. Overwriting it.");
     yaml::Hash::new()
   };
-  let new_files = add_dotfiles_to_files(&config["files"], dotfiles);
-  new_hash.insert(Yaml::String("files".to_string()), new_files);
-  Yaml::Hash(new_hash)
-}
-
-fn add_dotfiles_to_files(files: &Yaml, dotfiles: &[model::DotFile]) -> Yaml {
-  let mut new_hash = if let Yaml::Hash(files_hash) = files.clone() {
+  let mut files = if let Yaml::Hash(files_hash) = document["files"].clone() {
     files_hash
   } else {
     yaml::Hash::new()
   };
+  update_files(&mut files);
+  new_hash.insert(Yaml::String("files".to_string()), Yaml::Hash(files));
+  let mut out_str = String::new();
+  {
+    let mut emitter = YamlEmitter::new(&mut out_str);
+    emitter.dump(&Yaml::Hash(new_hash)).expect("emitting a hash of strings should not fail");
+  }
+  out_str.push('\n');
+  Ok(out_str)
+}
+
+/// Replaces the source of an entry, keeping its other settings.
+fn set_source(files: &mut yaml::Hash, dotfile: &model::DotFile) {
+  match files.get_mut(&Yaml::String(dotfile.target.to_string())) {
+    Some(Yaml::Hash(settings)) => match settings.get_mut(&Yaml::String("src".to_string())) {
+      Some(source) => *source = Yaml::String(dotfile.source.to_string()),
+      None => {
+        settings.insert(Yaml::String("src".to_string()), Yaml::String(dotfile.source.to_string()));
+      }
+    },
+    _ => add_dotfiles_to_files(files, std::slice::from_ref(dotfile)),
+  }
+}
+
+fn add_dotfiles_to_files(files: &mut yaml::Hash, dotfiles: &[model::DotFile]) {
   for dotfile in dotfiles {
-    let mut prop_hash = yaml::Hash::new();
-    prop_hash.insert(Yaml::String("src".to_string()), Yaml::String(dotfile.source.to_string()));
-    prop_hash.insert(Yaml::String("type".to_string()), Yaml::String(format!("{:?}", dotfile.dot_file_type)));
-    new_hash.insert(Yaml::String(dotfile.target.to_string()), Yaml::Hash(prop_hash));
+    let value = match (&dotfile.dot_file_type, dotfile.mode) {
+      (model::DotFileType::LINK, None) => Yaml::String(dotfile.source.to_string()),
+      (_, mode) => {
+        let mut prop_hash = yaml::Hash::new();
+        prop_hash.insert(Yaml::String("src".to_string()), Yaml::String(dotfile.source.to_string()));
+        prop_hash.insert(Yaml::String("type".to_string()), Yaml::String(dotfile.dot_file_type.to_string()));
+        if let Some(mode) = mode {
+          prop_hash.insert(Yaml::String("mode".to_string()), Yaml::String(model::format_mode(mode)));
+        }
+        Yaml::Hash(prop_hash)
+      }
+    };
+    files.insert(Yaml::String(dotfile.target.to_string()), value);
   }
-  Yaml::Hash(new_hash)
 }
+
 #[cfg(test)]
 mod tests {
   use super::*;
-  use crate::model::{DotFile, DotFileType};
+  use crate::model::{a_dot_file, DotFile, DotFileType};
   use slog::o;
   use spectral::prelude::*;
-  use yaml_rust::YamlLoader;
-  use yaml_rust::{Yaml, YamlEmitter};
 
   fn a_logger() -> Logger {
     use slog::Drain;
@@ -58,15 +633,16 @@ files:
         src: vimrc
         type: link
 ";
-    let yaml_documents = YamlLoader::load_from_str(s).unwrap();
-    let yaml_config = &yaml_documents[0];
     let logger = a_logger();
     let new_files = [DotFile {
       source: "test".to_string(),
       target: "~/test".to_string(),
       dot_file_type: DotFileType::LINK,
+      mode: None,
     }];
-    let new_config: Yaml = add_dotfiles_to_config(&logger, &yaml_config, &new_files);
+    let new_config: Yaml = YamlLoader::load_from_str(&add_dotfiles_to_config(&logger, s, &new_files).unwrap())
+      .unwrap()
+      .remove(0);
     let mut out_str = String::new();
     {
       let mut emitter = YamlEmitter::new(&mut out_str);
@@ -82,11 +658,287 @@ files:
   ~/.vimrc:
     src: vimrc
     type: link
-  ~/test:
-    src: test
-    type: LINK"
+  ~/test: test"
       .to_string();
 
     assert_that(&out_str).is_equal_to(expected);
   }
+
+  #[test]
+  fn test_add_dotfile_to_config_in_place() {
+    let s = "# my dotfiles
+files:
+    # plugins
+    ~/.tmux/plugins/tpm: tpm
+
+    ~/.tmux.conf:
+        src: tmux.conf
+        type: copy # keep a copy
+    ~/.vimrc:
+        src: vimrc
+        type: link
+
+# the end
+";
+    let new_files = [
+      a_dot_file("test", "~/test", DotFileType::LINK, None),
+      a_dot_file("copy", "~/copy", DotFileType::COPY, None),
+    ];
+    let new_config = add_dotfiles_to_config(&a_logger(), s, &new_files).unwrap();
+
+    let expected = "# my dotfiles
+files:
+    # plugins
+    ~/.tmux/plugins/tpm: tpm
+
+    ~/.tmux.conf:
+        src: tmux.conf
+        type: copy # keep a copy
+    ~/.vimrc:
+        src: vimrc
+        type: link
+    ~/test: test
+    ~/copy:
+        src: copy
+        type: copy
+
+# the end
+";
+    assert_that(&new_config.as_ref()).is_equal_to(expected);
+  }
+
+  #[test]
+  fn test_replace_existing_entry_in_place() {
+    let s = "files:
+  ~/.vimrc:
+    src: vimrc
+    type: copy
+  ~/.zshrc: zshrc
+";
+    let new_config = add_dotfiles_to_config(&a_logger(), s, &[a_dot_file("vim/vimrc", "~/.vimrc", DotFileType::LINK, None)]).unwrap();
+    assert_that(&new_config.as_ref()).is_equal_to("files:\n  ~/.vimrc: vim/vimrc\n  ~/.zshrc: zshrc\n");
+  }
+
+  #[test]
+  fn test_replace_keeps_comments() {
+    let s = "files:
+  ~/.vimrc: vimrc # the editor
+  ~/.zshrc:
+    # the shell
+    src: zshrc
+    type: copy # no link
+";
+    let replaced = [
+      a_dot_file("vim/vimrc", "~/.vimrc", DotFileType::LINK, None),
+      a_dot_file("zsh/zshrc", "~/.zshrc", DotFileType::LINK, None),
+    ];
+    let new_config = add_dotfiles_to_config(&a_logger(), s, &replaced).unwrap();
+    assert_that(&new_config.as_ref()).is_equal_to("files:\n  ~/.vimrc: vim/vimrc # the editor\n  # the shell\n  # no link\n  ~/.zshrc: zsh/zshrc\n");
+  }
+
+  #[test]
+  fn test_add_to_empty_or_missing_files() {
+    let link = [a_dot_file("vimrc", "~/.vimrc", DotFileType::LINK, None)];
+    assert_that(&add_dotfiles_to_config(&a_logger(), "", &link).unwrap().as_ref()).is_equal_to("files:\n  ~/.vimrc: vimrc\n");
+    assert_that(&add_dotfiles_to_config(&a_logger(), "files: # none yet\nother: 1\n", &link).unwrap().as_ref())
+      .is_equal_to("files: # none yet\n  ~/.vimrc: vimrc\nother: 1\n");
+    assert_that(&add_dotfiles_to_config(&a_logger(), "# just a comment\nother: 1", &link).unwrap().as_ref())
+      .is_equal_to("# just a comment\nother: 1\nfiles:\n  ~/.vimrc: vimrc\n");
+  }
+
+  #[test]
+  fn test_falls_back_to_rewriting_flow_style() {
+    let new_config = add_dotfiles_to_config(&a_logger(), "files: {~/a: a}", &[a_dot_file("b", "~/b", DotFileType::COPY, None)]).unwrap();
+    assert_that(&new_config.as_ref()).is_equal_to("---\nfiles:\n  ~/a: a\n  ~/b:\n    src: b\n    type: copy\n");
+    let link = [a_dot_file("b", "~/b", DotFileType::LINK, None)];
+    assert_that(&add_dotfiles_to_config(&a_logger(), "# mine\nfiles: {~/a: a}", &link).is_err()).is_true();
+    assert_that(&add_dotfiles_to_config(&a_logger(), "---\nfiles: {~/a: a}", &link).is_err()).is_true();
+    assert_that(&remove_dotfiles_from_config(&a_logger(), "files: {~/a: a}\n---\nother: 1\n", &["~/a".to_string()]).is_err()).is_true();
+  }
+
+  #[test]
+  fn test_remove_dotfiles_from_config() {
+    let s = "files:
+    # editor
+    ~/.vimrc:
+        src: vimrc
+        # nested comment
+        type: copy
+
+    ~/.zshrc: zshrc
+    ~/.vimrc: duplicate
+# trailing
+";
+    let new_config = remove_dotfiles_from_config(&a_logger(), s, &["~/.vimrc".to_string(), "~/.missing".to_string()]).unwrap();
+    assert_that(&new_config.as_ref()).is_equal_to("files:\n    # editor\n\n    ~/.zshrc: zshrc\n# trailing\n");
+  }
+
+  #[test]
+  fn test_update_sources_in_place() {
+    let s = "files:
+  ~/.vimrc: vimrc # the editor
+  ~/.gvimrc:
+    type: COPY
+    src: 'gvim''rc'   # quoted
+  ~/.zshrc: zshrc
+";
+    let updated = [
+      a_dot_file("vim/vimrc", "~/.vimrc", DotFileType::LINK, None),
+      a_dot_file("vim/gvimrc", "~/.gvimrc", DotFileType::COPY, None),
+      a_dot_file("new", "~/.new", DotFileType::LINK, None),
+    ];
+    let new_config = update_sources(&a_logger(), s, &updated).unwrap();
+    assert_that(&new_config.as_ref()).is_equal_to(
+      "files:
+  ~/.vimrc: vim/vimrc # the editor
+  ~/.gvimrc:
+    type: COPY
+    src: vim/gvimrc   # quoted
+  ~/.zshrc: zshrc
+  ~/.new: new
+",
+    );
+  }
+
+  #[test]
+  fn test_update_sources_keeps_settings() {
+    let s = "files: {~/.ssh/config: {src: ssh/config, type: copy, mode: \"0600\", owner: root}}";
+    let updated = [DotFile {
+      mode: Some(0o600),
+      ..a_dot_file("ssh/ssh_config", "~/.ssh/config", DotFileType::COPY, None)
+    }];
+    let new_config = update_sources(&a_logger(), s, &updated).unwrap();
+    assert_that(&new_config.as_ref())
+      .is_equal_to("---\nfiles:\n  ~/.ssh/config:\n    src: ssh/ssh_config\n    type: copy\n    mode: \"0600\"\n    owner: root\n");
+  }
+
+  #[test]
+  fn test_update_target_in_place() {
+    let s = "files:
+  # the editor
+  ~/.vimrc: vimrc # inline
+  \"~/.gvimrc\":
+    src: gvimrc
+    type: copy
+  ~/.zshrc: zshrc
+";
+    let new_config = update_target(&a_logger(), s, "~/.vimrc", "~/.config/vim/vimrc").unwrap();
+    let new_config = update_target(&a_logger(), &new_config, "~/.gvimrc", "~/.config/vim/gvimrc").unwrap();
+    assert_that(&new_config.as_ref()).is_equal_to(
+      "files:
+  # the editor
+  ~/.config/vim/vimrc: vimrc # inline
+  ~/.config/vim/gvimrc:
+    src: gvimrc
+    type: copy
+  ~/.zshrc: zshrc
+",
+    );
+    assert_that(&key_length("~/.vimrc: vimrc")).is_equal_to(Some(8));
+    assert_that(&key_length("~/.vimrc: # comment")).is_equal_to(Some(8));
+    assert_that(&key_length("~/.vimrc:")).is_equal_to(Some(8));
+  }
+
+  #[test]
+  fn test_format_config() {
+    let s = "# my dotfiles
+files:
+    ~/.zshrc:
+        src: zshrc
+        type: LINK # was uppercase
+
+    # editor
+    ~/.vimrc: vimrc # inline stays
+    ~/.gitconfig:
+        # keep a copy
+        type: COPY
+        src: gitconfig
+    ~/.weird:
+        src: weird
+        owner: root
+    ~/.ssh/config:
+        mode: 0600
+        src: ssh/config
+        type: Template
+other: 1
+";
+    let expected = "# my dotfiles
+files:
+    # keep a copy
+    ~/.gitconfig:
+        src: gitconfig
+        type: copy
+    ~/.ssh/config:
+        src: ssh/config
+        type: template
+        mode: \"0600\"
+
+    # editor
+    ~/.vimrc: vimrc # inline stays
+    ~/.weird:
+        src: weird
+        owner: root
+    # was uppercase
+    ~/.zshrc: zshrc
+other: 1
+";
+    let formatted = format_config(s).unwrap();
+    assert_that(&formatted.as_ref()).is_equal_to(expected);
+    assert_that(&format_config(&formatted).unwrap()).is_equal_to(formatted);
+  }
+
+  #[test]
+  fn test_format_config_keeps_leading_comments() {
+    let s = "# my dotfiles
+files:
+  # shell
+  ~/.zshrc: zshrc
+
+  # editor
+  ~/.vimrc: vimrc
+  ~/.gitconfig: gitconfig
+";
+    let expected = "# my dotfiles
+files:
+  ~/.gitconfig: gitconfig
+
+  # editor
+  ~/.vimrc: vimrc
+  # shell
+  ~/.zshrc: zshrc
+";
+    let formatted = format_config(s).unwrap();
+    assert_that(&formatted.as_ref()).is_equal_to(expected);
+    assert_that(&format_config(&formatted).unwrap()).is_equal_to(formatted);
+
+    let s = "files:\n  # shell\n  ~/.zshrc: zshrc\n\n  # editor\n  ~/.vimrc: vimrc\n";
+    let expected = "files:\n  # editor\n  ~/.vimrc: vimrc\n\n  # shell\n  ~/.zshrc: zshrc\n";
+    assert_that(&format_config(s).unwrap().as_ref()).is_equal_to(expected);
+  }
+
+  #[test]
+  fn test_comment_start() {
+    assert_that(&comment_start("a: b # c")).is_equal_to(Some(5));
+    assert_that(&comment_start("a: \"b # c\" # d")).is_equal_to(Some(11));
+    assert_that(&comment_start("a: b#c")).is_equal_to(None);
+  }
+
+  #[test]
+  fn test_scalar_length() {
+    assert_that(&scalar_length("vimrc # comment")).is_equal_to(Some(5));
+    assert_that(&scalar_length("\"a \\\" b\" # c")).is_equal_to(Some(8));
+    assert_that(&scalar_length("'it''s' x")).is_equal_to(Some(7));
+    assert_that(&scalar_length("\"open")).is_equal_to(None);
+    assert_that(&scalar_length("|")).is_equal_to(None);
+  }
+
+  #[test]
+  fn test_scalar_quoting() {
+    assert_that(&scalar("~/.vimrc")).is_equal_to("~/.vimrc".to_string());
+    assert_that(&scalar("~")).is_equal_to("\"~\"".to_string());
+    assert_that(&scalar("a #b")).is_equal_to("\"a #b\"".to_string());
+    assert_that(&scalar("key: value")).is_equal_to("\"key: value\"".to_string());
+    assert_that(&scalar("true")).is_equal_to("\"true\"".to_string());
+    assert_that(&scalar("say \"hi\"")).is_equal_to("say \"hi\"".to_string());
+  }
 }
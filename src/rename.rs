@@ -0,0 +1,198 @@
+use crate::alternate;
+use crate::errors::DotcopterError;
+use crate::expand;
+use crate::files;
+use crate::model::{DotFile, DotFileType};
+use crate::mutate;
+use slog::Logger;
+use slog::{error, info, o, warn};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Renames a file or directory in the repository together with its
+/// alternates, points every entry that uses it at the new path and relinks
+/// the installed links.
+pub fn rename(log: &Logger, config: &str, config_file: &str, dot_files: &[DotFile], old: &str, new: &str) -> Result<(), DotcopterError> {
+  let repo = env::current_dir()?;
+  let old_path = expand::normalize(&repo.join(old));
+  let new_path = expand::normalize(&repo.join(new));
+  if old_path.symlink_metadata().is_err() {
+    return Err(DotcopterError::Message(format!("{} does not exist", old_path.display())));
+  }
+  if new_path.symlink_metadata().is_ok() {
+    return Err(DotcopterError::Message(format!("{} already exists", new_path.display())));
+  }
+  let mut moves = vec![(old_path.clone(), new_path.clone())];
+  moves.extend(alternate_moves(&repo, &old_path, &new_path)?);
+  if let Some((_, to)) = moves.iter().find(|(_, to)| to.symlink_metadata().is_ok()) {
+    return Err(DotcopterError::Message(format!("{} already exists", to.display())));
+  }
+  let canonicalized_olds = moves.iter().map(|(from, _)| from.canonicalize()).collect::<Result<Vec<PathBuf>, _>>()?;
+
+  let mut updated = Vec::new();
+  for dot_file in dot_files {
+    let source = match expand::expand_path(&dot_file.source) {
+      Ok(source) => source,
+      Err(e) => {
+        warn!(log, "Skipping entry"; "target" => &dot_file.target, "error" => e.to_string());
+        continue;
+      }
+    };
+    if let Ok(rest) = expand::normalize(&repo.join(&source)).strip_prefix(&old_path) {
+      let new_base = if Path::new(&source).is_absolute() {
+        new_path.clone()
+      } else {
+        PathBuf::from(new)
+      };
+      let new_source = if rest.as_os_str().is_empty() { new_base } else { new_base.join(rest) };
+      updated.push(DotFile {
+        source: path_to_string(&new_source)?,
+        target: dot_file.target.clone(),
+        dot_file_type: dot_file.dot_file_type,
+        mode: dot_file.mode,
+      });
+    }
+  }
+
+  let new_config = if updated.is_empty() {
+    None
+  } else {
+    Some(mutate::update_sources(log, config, &updated)?)
+  };
+  for (index, (from, to)) in moves.iter().enumerate() {
+    if let Err(e) = files::move_path(from, to) {
+      error!(log, "Failed to rename file. Undoing."; "file" => format!("{}", from.display()), "error" => e.to_string());
+      move_back(log, &moves[..index]);
+      return Err(e);
+    }
+    info!(log, "Renamed file in repository"; "from" => format!("{}", from.display()), "to" => format!("{}", to.display()));
+  }
+
+  if let Some(new_config) = new_config {
+    if let Err(e) = fs::write(config_file, new_config) {
+      error!(log, "Failed to write config file. Undoing."; "error" => e.to_string());
+      move_back(log, &moves);
+      return Err(e.into());
+    }
+    info!(log, "Updated configuration"; "entries" => updated.len());
+  }
+
+  let facts = alternate::Facts::current();
+  for dot_file in updated.iter().filter(|dot_file| dot_file.dot_file_type == DotFileType::LINK) {
+    relink(&log.new(o!("target" => dot_file.target.clone())), dot_file, &canonicalized_olds, &repo, &facts);
+  }
+  Ok(())
+}
+
+/// The alternates of `old` and where they go when `old` becomes `new`. A
+/// `name##<condition>` alternate moves next to `new` with the same
+/// conditions, one below `host-<hostname>/` or `tag-<tag>/` moves to `new`
+/// below the same directory.
+fn alternate_moves(repo: &Path, old: &Path, new: &Path) -> Result<Vec<(PathBuf, PathBuf)>, DotcopterError> {
+  let source = old.strip_prefix(repo).unwrap_or(old);
+  let (old_name, new_name) = (file_name(old)?, file_name(new)?);
+  let mut moves = Vec::new();
+  for alternate in alternate::alternates(source, repo) {
+    let conditions = alternate
+      .file_name()
+      .and_then(|name| name.to_str())
+      .and_then(|name| name.strip_prefix(&format!("{}##", old_name)));
+    let to = match (conditions, alternate.components().next(), new.strip_prefix(repo)) {
+      (Some(conditions), _, _) => new.with_file_name(format!("{}##{}", new_name, conditions)),
+      (None, Some(dir), Ok(relative_new)) => repo.join(dir).join(relative_new),
+      (None, _, _) => {
+        return Err(DotcopterError::Message(format!(
+          "{} is outside the repository, its alternate {} cannot follow",
+          new.display(),
+          alternate.display()
+        )))
+      }
+    };
+    moves.push((repo.join(&alternate), to));
+  }
+  Ok(moves)
+}
+
+/// Undoes the moves that were done, the last one first.
+fn move_back(log: &Logger, moves: &[(PathBuf, PathBuf)]) {
+  for (from, to) in moves.iter().rev() {
+    if let Err(e) = files::move_path(to, from) {
+      error!(log, "Failed to move file back"; "file" => format!("{}", to.display()), "error" => e.to_string());
+    }
+  }
+}
+
+/// Gives the entries with target `old` the target `new` and moves the
+/// installed target there.
+pub fn retarget(log: &Logger, config: &str, config_file: &str, dot_files: &[DotFile], old: &str, new: &str) -> Result<(), DotcopterError> {
+  let matching: Vec<&DotFile> = dot_files.iter().filter(|dot_file| expand::same_path(&dot_file.target, old)).collect();
+  if matching.is_empty() {
+    return Err(DotcopterError::Message(format!("no entry has the target {}", old)));
+  }
+  if dot_files.iter().any(|dot_file| expand::same_path(&dot_file.target, new)) {
+    return Err(DotcopterError::Message(format!("an entry with the target {} already exists", new)));
+  }
+  let old_path = PathBuf::from(expand::expand_target(old)?);
+  let new_path = PathBuf::from(expand::expand_target(new)?);
+  if new_path.symlink_metadata().is_ok() {
+    return Err(DotcopterError::Message(format!("{} already exists", new_path.display())));
+  }
+
+  let mut new_config = config.to_string();
+  for dot_file in &matching {
+    new_config = mutate::update_target(log, &new_config, &dot_file.target, new)?;
+  }
+  fs::write(config_file, new_config)?;
+  info!(log, "Updated configuration"; "entries" => matching.len());
+
+  if old_path.symlink_metadata().is_err() {
+    info!(log, "Target is not installed");
+    return Ok(());
+  }
+  match files::move_path(&old_path, &new_path) {
+    Ok(_) => info!(log, "Moved installed target"; "from" => format!("{}", old_path.display()), "to" => format!("{}", new_path.display())),
+    Err(e) => error!(log, "Failed to move installed target. Apply to install it at the new target."; "error" => e.to_string()),
+  }
+  Ok(())
+}
+
+/// Points an installed link that still refers to one of the old locations at
+/// the new source, or its alternate for this host. Targets that are not links
+/// into an old location are left alone.
+fn relink(log: &Logger, dot_file: &DotFile, olds: &[PathBuf], repo: &Path, facts: &alternate::Facts) {
+  let (source, target) = match (expand::expand_path(&dot_file.source), expand::expand_target(&dot_file.target)) {
+    (Ok(source), Ok(target)) => (PathBuf::from(source), PathBuf::from(target)),
+    (Err(e), _) | (_, Err(e)) => {
+      error!(log, "Failed to expand path"; "error" => e.to_string());
+      return;
+    }
+  };
+  match fs::read_link(&target) {
+    Ok(link) if olds.iter().any(|old| link.starts_with(old)) => {
+      let source = alternate::resolve(log, &source, repo, facts);
+      let result = fs::remove_file(&target)
+        .map_err(DotcopterError::from)
+        .and_then(|_| files::link_dot_file(&source, &target));
+      match result {
+        Ok(_) => info!(log, "Relinked target"),
+        Err(e) => error!(log, "Failed to relink target"; "error" => e.to_string()),
+      }
+    }
+    _ => info!(log, "Target is not linked to the old location. Leaving it."),
+  }
+}
+
+fn file_name(path: &Path) -> Result<&str, DotcopterError> {
+  path
+    .file_name()
+    .and_then(|name| name.to_str())
+    .ok_or_else(|| DotcopterError::Message(format!("{} has no valid file name", path.display())))
+}
+
+fn path_to_string(path: &Path) -> Result<String, DotcopterError> {
+  path
+    .to_str()
+    .map(|path| path.to_string())
+    .ok_or_else(|| DotcopterError::Message(format!("{} is not valid unicode", path.display())))
+}
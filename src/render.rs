@@ -0,0 +1,323 @@
+use crate::alternate;
+use crate::errors::DotcopterError;
+use crate::expand;
+use crate::model::{DotFile, DotFileType};
+use crate::template;
+use slog::Logger;
+use slog::{debug, error, info, o};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use yaml_rust::Yaml;
+
+pub struct RenderOptions {
+  /// Absolute path of the home directory inside the rendered tree.
+  pub home: String,
+  /// Where the sources of links are placed inside the rendered tree.
+  pub repo_dir: String,
+  pub uid: u64,
+  pub gid: u64,
+  pub mtime: u64,
+}
+
+enum Node {
+  Dir(u32),
+  File { content: Vec<u8>, mode: u32 },
+  Link(PathBuf),
+}
+
+/// The files `apply` would create, keyed by their path relative to `/`.
+pub struct Tree {
+  nodes: BTreeMap<PathBuf, Node>,
+}
+
+/// Renders every entry into a tree rooted at `/` instead of touching the file
+/// system. Links point into `repo_dir`, which receives a copy of their
+/// sources. Entries that fail are reported and left out like `apply` does.
+pub fn build(log: &Logger, dot_files: &[DotFile], vars: &Yaml, repo: &Path, options: &RenderOptions) -> Tree {
+  let mut tree = Tree { nodes: BTreeMap::new() };
+  let facts = alternate::Facts::current();
+  for dot_file in dot_files {
+    let log = log.new(o!("target" => dot_file.target.clone(), "source" => dot_file.source.clone(), "type" => format!("{:?}", dot_file.dot_file_type)));
+    match add_dot_file(&mut tree, dot_file, vars, repo, &facts, options) {
+      Ok(_) => debug!(log, "Rendered entry"),
+      Err(e) => error!(log, "Failed to render entry"; "error" => e.to_string()),
+    }
+  }
+  tree
+}
+
+fn add_dot_file(
+  tree: &mut Tree,
+  dot_file: &DotFile,
+  vars: &Yaml,
+  repo: &Path,
+  facts: &alternate::Facts,
+  options: &RenderOptions,
+) -> Result<(), DotcopterError> {
+  let target = tree_path(&expand::expand_path_for_home(&dot_file.target, &options.home)?)?;
+  let source = expand::expand_path_on_host(&dot_file.source)?;
+  let source = alternate::select(Path::new(&source), repo, facts).unwrap_or_else(|| PathBuf::from(source));
+  let source = expand::normalize(&repo.join(source));
+  let metadata = source
+    .metadata()
+    .map_err(|_| DotcopterError::Message(format!("source {} does not exist", source.display())))?;
+  let node = match dot_file.dot_file_type {
+    DotFileType::LINK => {
+      let canonical_source = source.canonicalize()?;
+      match canonical_source.strip_prefix(repo.canonicalize()?) {
+        Ok(relative) => {
+          let in_repo = Path::new(&options.repo_dir).join(relative);
+          tree.add_recursively(&canonical_source, tree_path(&in_repo.to_string_lossy())?)?;
+          Node::Link(in_repo)
+        }
+        Err(_) => {
+          return Err(DotcopterError::Message(format!(
+            "source {} is outside the repository, a link to it would point into this host",
+            canonical_source.display()
+          )))
+        }
+      }
+    }
+    DotFileType::COPY if metadata.is_dir() => return Err(DotcopterError::Message("directories cannot be copied".to_string())),
+    DotFileType::COPY => Node::File {
+      content: fs::read(&source)?,
+      mode: dot_file.mode.unwrap_or(metadata.permissions().mode() & 0o7777),
+    },
+    DotFileType::TEMPLATE => Node::File {
+      content: template::render_without_secrets(&fs::read_to_string(&source)?, vars)?.into_bytes(),
+      mode: dot_file.mode.unwrap_or(0o644),
+    },
+    DotFileType::ENCRYPTED => return Err(DotcopterError::Message("encrypted entries are not rendered".to_string())),
+  };
+  tree.insert(target, node)
+}
+
+/// The path of an absolute target relative to the root of the tree.
+fn tree_path(target: &str) -> Result<PathBuf, DotcopterError> {
+  let target = Path::new(target);
+  if !target.is_absolute() {
+    return Err(DotcopterError::Message(format!("relative target {} cannot be rendered", target.display())));
+  }
+  Ok(
+    expand::normalize(target)
+      .components()
+      .filter(|component| matches!(component, Component::Normal(_)))
+      .collect(),
+  )
+}
+
+impl Tree {
+  fn insert(&mut self, path: PathBuf, node: Node) -> Result<(), DotcopterError> {
+    for ancestor in path.ancestors().skip(1).filter(|ancestor| !ancestor.as_os_str().is_empty()) {
+      match self.nodes.get(ancestor) {
+        None => {
+          self.nodes.insert(ancestor.to_path_buf(), Node::Dir(0o755));
+        }
+        Some(Node::Dir(_)) => {}
+        Some(_) => return Err(DotcopterError::Message(format!("/{} is not a directory", ancestor.display()))),
+      }
+    }
+    match (self.nodes.get(&path), &node) {
+      (None, _) => {
+        self.nodes.insert(path, node);
+        Ok(())
+      }
+      (Some(Node::Dir(_)), Node::Dir(_)) => Ok(()),
+      _ => Err(DotcopterError::Message(format!("/{} is already rendered by another entry", path.display()))),
+    }
+  }
+
+  /// Adds `source` with everything below it, sources shared by several links
+  /// are only added once.
+  fn add_recursively(&mut self, source: &Path, path: PathBuf) -> Result<(), DotcopterError> {
+    if self.nodes.contains_key(&path) && !source.is_dir() {
+      return Ok(());
+    }
+    let metadata = source.symlink_metadata()?;
+    let mode = metadata.permissions().mode() & 0o7777;
+    if metadata.file_type().is_symlink() {
+      self.insert(path, Node::Link(fs::read_link(source)?))
+    } else if metadata.is_dir() {
+      self.insert(path.clone(), Node::Dir(mode))?;
+      let mut children: Vec<_> = fs::read_dir(source)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<_, _>>()?;
+      children.sort();
+      for child in children {
+        self.add_recursively(&source.join(&child), path.join(&child))?;
+      }
+      Ok(())
+    } else {
+      self.insert(
+        path,
+        Node::File {
+          content: fs::read(source)?,
+          mode,
+        },
+      )
+    }
+  }
+
+  /// Writes the tree as a tar archive. Entries are sorted and all share the
+  /// same owner and modification time, so the archive only changes when a
+  /// file does.
+  pub fn write_tar<W: io::Write>(&self, writer: W, options: &RenderOptions) -> Result<(), DotcopterError> {
+    let mut builder = tar::Builder::new(writer);
+    for (path, node) in &self.nodes {
+      let mut header = tar::Header::new_gnu();
+      header.set_mtime(options.mtime);
+      header.set_uid(options.uid);
+      header.set_gid(options.gid);
+      match node {
+        Node::Dir(mode) => {
+          header.set_entry_type(tar::EntryType::Directory);
+          header.set_mode(*mode);
+          header.set_size(0);
+          builder.append_data(&mut header, path, io::empty())?;
+        }
+        Node::File { content, mode } => {
+          header.set_entry_type(tar::EntryType::Regular);
+          header.set_mode(*mode);
+          header.set_size(content.len() as u64);
+          builder.append_data(&mut header, path, content.as_slice())?;
+        }
+        Node::Link(destination) => {
+          header.set_entry_type(tar::EntryType::Symlink);
+          header.set_mode(0o777);
+          header.set_size(0);
+          builder.append_link(&mut header, path, destination)?;
+        }
+      }
+    }
+    builder.into_inner()?.flush()?;
+    Ok(())
+  }
+
+  /// Writes the tree below `dir`, which must not exist or be empty. Owners are
+  /// left alone, modes and modification times are set like in the archive.
+  pub fn write_dir(&self, log: &Logger, dir: &Path, options: &RenderOptions) -> Result<(), DotcopterError> {
+    if fs::read_dir(dir).map(|mut entries| entries.next().is_some()).unwrap_or(false) {
+      return Err(DotcopterError::Message(format!("{} is not empty", dir.display())));
+    }
+    fs::create_dir_all(dir)?;
+    for (path, node) in &self.nodes {
+      let out = dir.join(path);
+      match node {
+        Node::Dir(_) => fs::create_dir(&out)?,
+        Node::File { content, .. } => fs::write(&out, content)?,
+        Node::Link(destination) => std::os::unix::fs::symlink(destination, &out)?,
+      }
+    }
+    // children first, so writing them does not change their directory again
+    for (path, node) in self.nodes.iter().rev() {
+      let out = dir.join(path);
+      match node {
+        Node::Dir(mode) | Node::File { mode, .. } => fs::set_permissions(&out, fs::Permissions::from_mode(*mode))?,
+        Node::Link(_) => {}
+      }
+      set_mtime(&out, options.mtime)?;
+    }
+    info!(log, "Rendered files"; "count" => self.nodes.len());
+    Ok(())
+  }
+}
+
+/// Sets the modification time without following links.
+fn set_mtime(path: &Path, mtime: u64) -> Result<(), DotcopterError> {
+  let path = CString::new(path.as_os_str().as_bytes()).map_err(|e| DotcopterError::Message(e.to_string()))?;
+  let time = libc::timespec {
+    tv_sec: mtime as libc::time_t,
+    tv_nsec: 0,
+  };
+  let times = [time, time];
+  if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+    return Err(io::Error::last_os_error().into());
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::a_dot_file;
+  use spectral::prelude::*;
+  use std::env;
+
+  fn a_logger() -> Logger {
+    use slog::Drain;
+    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+    let drain = slog_term::FullFormat::new(plain).build().fuse();
+    Logger::root(drain, o!())
+  }
+
+  #[test]
+  fn test_render() {
+    let root = env::temp_dir().join(format!("dotcopter-render-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let repo = root.join("repo");
+    fs::create_dir_all(repo.join("nvim")).unwrap();
+    fs::write(repo.join("nvim/init.vim"), "set number\n").unwrap();
+    fs::write(repo.join("netrc"), "machine x\n").unwrap();
+    fs::write(repo.join("gitconfig"), "name = {{ name }}\n").unwrap();
+    fs::write(root.join("outside"), "").unwrap();
+    fs::set_permissions(repo.join("nvim"), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::set_permissions(repo.join("nvim/init.vim"), fs::Permissions::from_mode(0o644)).unwrap();
+    let dot_files = vec![
+      a_dot_file("nvim", "$XDG_CONFIG_HOME/nvim", DotFileType::LINK, None),
+      a_dot_file("netrc", "~/.netrc", DotFileType::COPY, Some(0o600)),
+      a_dot_file("gitconfig", "~/.gitconfig", DotFileType::TEMPLATE, None),
+      a_dot_file("netrc", "~/.netrc", DotFileType::COPY, None),
+      a_dot_file(&root.join("outside").to_string_lossy(), "~/.outside", DotFileType::LINK, None),
+    ];
+    let vars = &yaml_rust::YamlLoader::load_from_str("name: blubb").unwrap()[0];
+    let options = RenderOptions {
+      home: "/home/dev".to_string(),
+      repo_dir: "/home/dev/.dotfiles".to_string(),
+      uid: 1000,
+      gid: 1000,
+      mtime: 0,
+    };
+    let tree = build(&a_logger(), &dot_files, vars, &repo, &options);
+
+    let mut first = Vec::new();
+    tree.write_tar(&mut first, &options).unwrap();
+    let mut second = Vec::new();
+    build(&a_logger(), &dot_files, vars, &repo, &options).write_tar(&mut second, &options).unwrap();
+    assert_that(&(first == second)).is_true();
+
+    let mut archive = tar::Archive::new(first.as_slice());
+    let entries: Vec<(String, u32, u64)> = archive
+      .entries()
+      .unwrap()
+      .map(|entry| {
+        let entry = entry.unwrap();
+        let header = entry.header();
+        (entry.path().unwrap().display().to_string(), header.mode().unwrap(), header.uid().unwrap())
+      })
+      .collect();
+    assert_that(&entries).is_equal_to(vec![
+      ("home".to_string(), 0o755, 1000),
+      ("home/dev".to_string(), 0o755, 1000),
+      ("home/dev/.config".to_string(), 0o755, 1000),
+      ("home/dev/.config/nvim".to_string(), 0o777, 1000),
+      ("home/dev/.dotfiles".to_string(), 0o755, 1000),
+      ("home/dev/.dotfiles/nvim".to_string(), 0o755, 1000),
+      ("home/dev/.dotfiles/nvim/init.vim".to_string(), 0o644, 1000),
+      ("home/dev/.gitconfig".to_string(), 0o644, 1000),
+      ("home/dev/.netrc".to_string(), 0o600, 1000),
+    ]);
+
+    let out = root.join("out");
+    tree.write_dir(&a_logger(), &out, &options).unwrap();
+    assert_that(&fs::read_link(out.join("home/dev/.config/nvim")).unwrap()).is_equal_to(PathBuf::from("/home/dev/.dotfiles/nvim"));
+    assert_that(&fs::read_to_string(out.join("home/dev/.gitconfig")).unwrap()).is_equal_to("name = blubb\n".to_string());
+    assert_that(&(fs::metadata(out.join("home/dev/.netrc")).unwrap().permissions().mode() & 0o777)).is_equal_to(0o600);
+    assert_that(&tree.write_dir(&a_logger(), &out, &options).is_err()).is_true();
+    fs::remove_dir_all(&root).unwrap();
+  }
+}
@@ -0,0 +1,212 @@
+use crate::errors::DotcopterError;
+use slog::{Drain, OwnedKV, OwnedKVList, Record, RecordStatic, Serializer, KV};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+use yaml_rust::Yaml;
+
+/// Replaces a secret in log and diff output.
+const MASK: &str = "********";
+
+/// Shorter secrets are refused, masking them would garble unrelated output.
+const MIN_MASKED_LENGTH: usize = 4;
+
+/// The secrets of the configuration, set once from `main`.
+static SECRETS: OnceLock<Secrets> = OnceLock::new();
+
+/// Secrets from the `secrets` mapping of the configuration. Every secret is
+/// the output of a command, run at most once per run when a template first
+/// references it. Values are only kept in memory.
+pub struct Secrets {
+  commands: BTreeMap<String, String>,
+  resolved: Mutex<BTreeMap<String, Result<String, String>>>,
+}
+
+impl Secrets {
+  pub fn from_yaml(secrets: &Yaml) -> Secrets {
+    let commands = secrets
+      .as_hash()
+      .map(|secrets| {
+        secrets
+          .iter()
+          .filter_map(|(name, settings)| Some((name.as_str()?.to_string(), settings["cmd"].as_str()?.to_string())))
+          .collect()
+      })
+      .unwrap_or_default();
+    Secrets {
+      commands,
+      resolved: Mutex::new(BTreeMap::new()),
+    }
+  }
+
+  pub fn resolve(&self, name: &str) -> Result<String, DotcopterError> {
+    let command = self
+      .commands
+      .get(name)
+      .ok_or_else(|| DotcopterError::Message(format!("secret `{}` is not defined", name)))?;
+    let mut resolved = self.resolved.lock().unwrap_or_else(|e| e.into_inner());
+    resolved
+      .entry(name.to_string())
+      .or_insert_with(|| run(command).map_err(|e| format!("secret `{}`: {}", name, e)))
+      .clone()
+      .map_err(DotcopterError::Message)
+  }
+
+  /// Replaces every secret resolved so far in `text`, longer ones first.
+  pub fn mask(&self, text: &str) -> String {
+    let resolved = self.resolved.lock().unwrap_or_else(|e| e.into_inner());
+    let mut values: Vec<&String> = resolved
+      .values()
+      .filter_map(|value| value.as_ref().ok())
+      .filter(|value| !value.is_empty())
+      .collect();
+    values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+    let mut masked = text.to_string();
+    for value in values {
+      if masked.contains(value.as_str()) {
+        masked = masked.replace(value.as_str(), MASK);
+      }
+    }
+    masked
+  }
+}
+
+/// Runs `command` with `sh`, its output without the trailing newline is the
+/// secret. The terminal stays attached so the command can ask for a password.
+fn run(command: &str) -> Result<String, DotcopterError> {
+  let output = Command::new("sh")
+    .arg("-c")
+    .arg(command)
+    .stdin(Stdio::inherit())
+    .stderr(Stdio::inherit())
+    .output()
+    .map_err(|e| DotcopterError::Message(format!("failed to run `{}`: {}", command, e)))?;
+  if !output.status.success() {
+    return Err(DotcopterError::Message(format!("`{}` failed with {}", command, output.status)));
+  }
+  let value = String::from_utf8(output.stdout).map_err(|_| DotcopterError::Message(format!("`{}` printed invalid unicode", command)))?;
+  let value = value.trim_end_matches(&['\n', '\r'][..]);
+  if value.is_empty() {
+    return Err(DotcopterError::Message(format!("`{}` printed nothing", command)));
+  }
+  if value.chars().count() < MIN_MASKED_LENGTH {
+    return Err(DotcopterError::Message(format!(
+      "too short to be masked, `{}` printed fewer than {} characters",
+      command, MIN_MASKED_LENGTH
+    )));
+  }
+  Ok(value.to_string())
+}
+
+/// Uses the `secrets` mapping of the configuration. Only the first call has
+/// an effect.
+pub fn configure(secrets: &Yaml) {
+  let _ = SECRETS.set(Secrets::from_yaml(secrets));
+}
+
+/// The value of the secret `name`, running its command on first use.
+pub fn resolve(name: &str) -> Result<String, DotcopterError> {
+  match SECRETS.get() {
+    Some(secrets) => secrets.resolve(name),
+    None => Err(DotcopterError::Message(format!("secret `{}` is not defined", name))),
+  }
+}
+
+/// `text` with every secret resolved so far replaced.
+pub fn mask(text: &str) -> String {
+  match SECRETS.get() {
+    Some(secrets) => secrets.mask(text),
+    None => text.to_string(),
+  }
+}
+
+fn any_resolved() -> bool {
+  SECRETS
+    .get()
+    .map(|secrets| secrets.resolved.lock().map(|resolved| !resolved.is_empty()).unwrap_or(true))
+    .unwrap_or(false)
+}
+
+/// Masks the message and every value of a record before passing it on.
+pub struct MaskingDrain<D: Drain> {
+  drain: D,
+}
+
+impl<D: Drain> MaskingDrain<D> {
+  pub fn new(drain: D) -> MaskingDrain<D> {
+    MaskingDrain { drain }
+  }
+}
+
+impl<D: Drain> Drain for MaskingDrain<D> {
+  type Ok = D::Ok;
+  type Err = D::Err;
+
+  fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+    if !any_resolved() {
+      return self.drain.log(record, values);
+    }
+    let message = mask(&record.msg().to_string());
+    let mut record_values = MaskedValues::default();
+    let _ = record.kv().serialize(record, &mut record_values);
+    let mut logger_values = MaskedValues::default();
+    let _ = values.serialize(record, &mut logger_values);
+    let record_static = RecordStatic {
+      location: record.location(),
+      tag: record.tag(),
+      level: record.level(),
+    };
+    let masked_values = OwnedKVList::from(OwnedKV(logger_values));
+    self.drain.log(
+      &Record::new(&record_static, &format_args!("{}", message), slog::BorrowedKV(&record_values)),
+      &masked_values,
+    )
+  }
+
+  fn is_enabled(&self, level: slog::Level) -> bool {
+    self.drain.is_enabled(level)
+  }
+}
+
+/// Key-value pairs with masked values, emitted again in the order they were
+/// serialized.
+#[derive(Default)]
+struct MaskedValues(Vec<(slog::Key, String)>);
+
+impl Serializer for MaskedValues {
+  fn emit_arguments(&mut self, key: slog::Key, value: &fmt::Arguments) -> slog::Result {
+    self.0.push((key, mask(&value.to_string())));
+    Ok(())
+  }
+}
+
+impl KV for MaskedValues {
+  fn serialize(&self, _record: &Record, serializer: &mut dyn Serializer) -> slog::Result {
+    for (key, value) in &self.0 {
+      serializer.emit_str(key, value)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+  use yaml_rust::YamlLoader;
+
+  #[test]
+  fn test_resolve_and_mask() {
+    let config = "github_token: {cmd: \"printf 'ghp_123\\\\n'\"}\nbroken: {cmd: \"exit 1\"}\nempty: {cmd: \"true\"}\nshort: {cmd: \"echo e\"}\n";
+    let secrets = Secrets::from_yaml(&YamlLoader::load_from_str(config).unwrap()[0]);
+    assert_that(&secrets.mask("token ghp_123")).is_equal_to("token ghp_123".to_string());
+    assert_that(&secrets.resolve("github_token").unwrap()).is_equal_to("ghp_123".to_string());
+    assert_that(&secrets.resolve("broken").is_err()).is_true();
+    assert_that(&secrets.resolve("empty").is_err()).is_true();
+    assert_that(&secrets.resolve("missing").is_err()).is_true();
+    assert_that(&secrets.resolve("short").unwrap_err().to_string())
+      .is_equal_to("secret `short`: too short to be masked, `echo e` printed fewer than 4 characters".to_string());
+    assert_that(&secrets.mask("token ghp_123 for echo")).is_equal_to("token ******** for echo".to_string());
+  }
+}
@@ -0,0 +1,294 @@
+use crate::errors::DotcopterError;
+use crate::expand;
+use crate::files;
+use crate::import;
+use crate::model::{DotFile, DotFileType};
+use regex::Regex;
+use slog::Logger;
+use slog::{debug, error, info, o, warn};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const LOCAL_IGNORE: &str = ".stow-local-ignore";
+
+/// Stow's built in ignore list, used for packages without a `.stow-local-ignore`.
+const DEFAULT_IGNORE: [&str; 16] = [
+  "RCS",
+  ".+,v",
+  "CVS",
+  "\\.\\#.+",
+  "\\.cvsignore",
+  "\\.svn",
+  "_darcs",
+  "\\.hg",
+  "\\.git",
+  "\\.gitignore",
+  "\\.gitmodules",
+  ".+~",
+  "\\#.*\\#",
+  "^/README.*",
+  "^/LICENSE.*",
+  "^/COPYING",
+];
+
+/// Ignore patterns of a package. Patterns containing a slash are matched
+/// against the path inside the package with a leading slash, all others
+/// against the file name.
+struct Ignore {
+  path: Option<Regex>,
+  segment: Option<Regex>,
+}
+
+impl Ignore {
+  fn load(package: &Path) -> Result<Ignore, DotcopterError> {
+    match fs::read_to_string(package.join(LOCAL_IGNORE)) {
+      Ok(content) => Ignore::new(content.lines()),
+      Err(_) => Ignore::new(DEFAULT_IGNORE.iter().copied()),
+    }
+  }
+
+  fn new<'a, I: Iterator<Item = &'a str>>(lines: I) -> Result<Ignore, DotcopterError> {
+    let (path, segment): (Vec<&str>, Vec<&str>) = lines
+      .map(|line| line.split(" #").next().unwrap_or("").trim())
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .partition(|line| line.contains('/'));
+    let path = if path.is_empty() {
+      None
+    } else {
+      Some(Regex::new(&format!("(^|/)(?:{})$", path.join("|")))?)
+    };
+    let segment = if segment.is_empty() {
+      None
+    } else {
+      Some(Regex::new(&format!("^(?:{})$", segment.join("|")))?)
+    };
+    Ok(Ignore { path, segment })
+  }
+
+  fn is_ignored(&self, relative: &Path) -> bool {
+    let name = relative.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let path = format!("/{}", relative.to_string_lossy());
+    name == LOCAL_IGNORE
+      || self.segment.as_ref().map(|regex| regex.is_match(&name)).unwrap_or(false)
+      || self.path.as_ref().map(|regex| regex.is_match(&path)).unwrap_or(false)
+  }
+}
+
+/// Translates the stow packages below `stow_dir` into link entries for
+/// `target_dir`. All packages are used if `packages` is empty.
+///
+/// Like stow a directory becomes a single entry (tree folding) unless it
+/// already exists as a real directory in the target or more than one package
+/// contains it, then its contents become entries.
+pub fn scan_packages(log: &Logger, stow_dir: &Path, target_dir: &Path, packages: &[String]) -> Result<Vec<DotFile>, DotcopterError> {
+  let stow_dir = stow_dir.canonicalize()?;
+  let packages = if packages.is_empty() {
+    let mut packages = Vec::new();
+    for dir_entry in fs::read_dir(&stow_dir)? {
+      let dir_entry = dir_entry?;
+      let name = dir_entry.file_name().to_string_lossy().into_owned();
+      if dir_entry.file_type()?.is_dir() && !name.starts_with('.') {
+        packages.push(name);
+      }
+    }
+    packages.sort();
+    packages
+  } else {
+    packages.to_vec()
+  };
+
+  let mut package_dirs = Vec::new();
+  for package in &packages {
+    let package_dir = stow_dir.join(package);
+    if !package_dir.is_dir() {
+      return Err(DotcopterError::Message(format!("{} is not a stow package", package_dir.display())));
+    }
+    package_dirs.push((package_dir.clone(), Ignore::load(&package_dir)?));
+  }
+
+  let mut owners: HashMap<PathBuf, usize> = HashMap::new();
+  for (package_dir, ignore) in &package_dirs {
+    count_dirs(package_dir, Path::new(""), ignore, &mut owners)?;
+  }
+  let shared: HashSet<PathBuf> = owners.into_iter().filter(|(_, count)| *count > 1).map(|(dir, _)| dir).collect();
+
+  let repo = env::current_dir()?;
+  let mut dot_files = Vec::new();
+  for (package_dir, ignore) in &package_dirs {
+    let log = log.new(o!("package" => format!("{}", package_dir.display())));
+    let mut found = Vec::new();
+    scan_tree(&log, package_dir, Path::new(""), target_dir, ignore, &shared, &mut found)?;
+    for (source, target) in found {
+      let source = source.strip_prefix(&repo).map(Path::to_path_buf).unwrap_or(source);
+      dot_files.push(DotFile {
+        source: source.to_string_lossy().into_owned(),
+        target: import::replace_home_with_tilde(&log, &target.to_string_lossy())?,
+        dot_file_type: DotFileType::LINK,
+        mode: None,
+      });
+    }
+  }
+  Ok(dot_files)
+}
+
+fn count_dirs(package_dir: &Path, relative: &Path, ignore: &Ignore, owners: &mut HashMap<PathBuf, usize>) -> Result<(), DotcopterError> {
+  for dir_entry in fs::read_dir(package_dir.join(relative))? {
+    let dir_entry = dir_entry?;
+    let child = relative.join(dir_entry.file_name());
+    if dir_entry.file_type()?.is_dir() && !ignore.is_ignored(&child) {
+      *owners.entry(child.clone()).or_insert(0) += 1;
+      count_dirs(package_dir, &child, ignore, owners)?;
+    }
+  }
+  Ok(())
+}
+
+fn scan_tree(
+  log: &Logger,
+  package_dir: &Path,
+  relative: &Path,
+  target_dir: &Path,
+  ignore: &Ignore,
+  shared: &HashSet<PathBuf>,
+  found: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<(), DotcopterError> {
+  let mut children: Vec<PathBuf> = fs::read_dir(package_dir.join(relative))?
+    .map(|dir_entry| dir_entry.map(|dir_entry| relative.join(dir_entry.file_name())))
+    .collect::<Result<_, _>>()?;
+  children.sort();
+  for child in children {
+    if ignore.is_ignored(&child) {
+      debug!(log, "Ignored"; "path" => format!("{}", child.display()));
+      continue;
+    }
+    let source = package_dir.join(&child);
+    let target = target_dir.join(&child);
+    let source_is_dir = source.symlink_metadata()?.is_dir();
+    let unfold = source_is_dir
+      && match target.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => true,
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+          let points_here = fs::canonicalize(&target).map(|resolved| resolved == source).unwrap_or(false);
+          !points_here && target.is_dir()
+        }
+        _ => shared.contains(&child),
+      };
+    if unfold {
+      scan_tree(log, package_dir, &child, target_dir, ignore, shared, found)?;
+      continue;
+    }
+    match target.symlink_metadata() {
+      Ok(metadata) if !metadata.file_type().is_symlink() => {
+        warn!(log, "Target exists and is not managed by stow"; "target" => format!("{}", target.display()))
+      }
+      Ok(_) if fs::canonicalize(&target).ok() != Some(source.clone()) => {
+        warn!(log, "Target links somewhere else"; "target" => format!("{}", target.display()))
+      }
+      _ => {}
+    }
+    info!(log, "Found dotfile"; "target" => format!("{}", target.display()));
+    found.push((source, target));
+  }
+  Ok(())
+}
+
+/// Replaces the links stow created for `dot_files` with dotcopter links and
+/// links entries that are not installed yet. Other files are left alone.
+pub fn relink(log: &Logger, dot_files: &[DotFile]) {
+  for dot_file in dot_files {
+    let log = log.new(o!("target" => dot_file.target.clone()));
+    let (source, target) = match (expand::expand_path(&dot_file.source), expand::expand_target(&dot_file.target)) {
+      (Ok(source), Ok(target)) => (PathBuf::from(source), PathBuf::from(target)),
+      (Err(e), _) | (_, Err(e)) => {
+        error!(log, "Failed to expand path"; "error" => e.to_string());
+        continue;
+      }
+    };
+    let result = match target.symlink_metadata() {
+      Ok(metadata) if metadata.file_type().is_symlink() => {
+        if fs::canonicalize(&target).ok() != source.canonicalize().ok() {
+          warn!(log, "Target links somewhere else. Leaving it.");
+          continue;
+        }
+        fs::remove_file(&target).map_err(DotcopterError::from)
+      }
+      Ok(_) => {
+        warn!(log, "Target exists and is not a link. Use apply --force to replace it.");
+        continue;
+      }
+      Err(_) => Ok(()),
+    };
+    match result.and_then(|_| files::link_dot_file(&source, &target)) {
+      Ok(_) => info!(log, "Relinked target"),
+      Err(e) => error!(log, "Failed to relink target"; "error" => e.to_string()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  fn a_logger() -> Logger {
+    use slog::Drain;
+    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+    let drain = slog_term::FullFormat::new(plain).build().fuse();
+    Logger::root(drain, o!())
+  }
+
+  #[test]
+  fn test_ignore() {
+    let defaults = Ignore::new(DEFAULT_IGNORE.iter().copied()).unwrap();
+    assert_that(&defaults.is_ignored(Path::new("README.md"))).is_true();
+    assert_that(&defaults.is_ignored(Path::new(".config/README.md"))).is_false();
+    assert_that(&defaults.is_ignored(Path::new(".config/.git"))).is_true();
+    assert_that(&defaults.is_ignored(Path::new(".vimrc~"))).is_true();
+    assert_that(&defaults.is_ignored(Path::new(".vimrc"))).is_false();
+
+    let local = Ignore::new("# comment\n\nnotes\\.txt\n^/build/.*\n".lines()).unwrap();
+    assert_that(&local.is_ignored(Path::new("a/notes.txt"))).is_true();
+    assert_that(&local.is_ignored(Path::new("build/out"))).is_true();
+    assert_that(&local.is_ignored(Path::new("a/build/out"))).is_false();
+    assert_that(&local.is_ignored(Path::new("README"))).is_false();
+  }
+
+  #[test]
+  fn test_scan_packages_folds_unshared_dirs() {
+    let root = env::temp_dir().join(format!("dotcopter-stow-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    for dir in &["stow/vim/.vim/colors", "stow/nvim/.config/nvim", "stow/git/.config/git", "home"] {
+      fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    for file in &[
+      "stow/vim/.vimrc",
+      "stow/vim/README.md",
+      "stow/nvim/.config/nvim/init.vim",
+      "stow/git/.config/git/config",
+      "stow/git/notes",
+    ] {
+      fs::write(root.join(file), "").unwrap();
+    }
+    fs::write(root.join("stow/git/.stow-local-ignore"), "notes\n").unwrap();
+    let home = root.join("home");
+
+    let dot_files = scan_packages(&a_logger(), &root.join("stow"), &home, &[]).unwrap();
+    let pairs: Vec<(String, String)> = dot_files
+      .iter()
+      .map(|dot_file| {
+        let source = Path::new(&dot_file.source).strip_prefix(root.join("stow").canonicalize().unwrap()).unwrap();
+        let target = Path::new(&dot_file.target).strip_prefix(&home).unwrap();
+        (source.to_string_lossy().into_owned(), target.to_string_lossy().into_owned())
+      })
+      .collect();
+    assert_that(&pairs).is_equal_to(vec![
+      ("git/.config/git".to_string(), ".config/git".to_string()),
+      ("nvim/.config/nvim".to_string(), ".config/nvim".to_string()),
+      ("vim/.vim".to_string(), ".vim".to_string()),
+      ("vim/.vimrc".to_string(), ".vimrc".to_string()),
+    ]);
+    fs::remove_dir_all(&root).unwrap();
+  }
+}
@@ -0,0 +1,186 @@
+use crate::errors::DotcopterError;
+use crate::expand;
+use crate::host;
+use crate::secret;
+use yaml_rust::Yaml;
+
+enum Segment<'a> {
+  Text(&'a str),
+  Variable(&'a str),
+}
+
+/// Renders `{{ name }}` references with values from the `vars` mapping of the
+/// configuration. Nested values are referenced with dots (`{{ git.email }}`), a
+/// leading dot is optional. `{{-` and `-}}` trim the whitespace before or
+/// after the reference.
+///
+/// Facts about the host are available as `host.hostname`, `host.os`,
+/// `host.arch`, `host.username` and `host.home`, and under chezmoi's names
+/// (`chezmoi.hostname`, ..., `chezmoi.homeDir`) for imported templates.
+/// `secrets.<name>` runs the command of the secret from the configuration.
+pub fn render(template: &str, vars: &Yaml) -> Result<String, DotcopterError> {
+  render_with(template, vars, true)
+}
+
+/// Renders like `render` but fails if the template uses a secret, for output
+/// that is written somewhere other than the target.
+pub fn render_without_secrets(template: &str, vars: &Yaml) -> Result<String, DotcopterError> {
+  render_with(template, vars, false)
+}
+
+fn render_with(template: &str, vars: &Yaml, secrets: bool) -> Result<String, DotcopterError> {
+  let mut rendered = String::new();
+  for segment in parse(template)? {
+    match segment {
+      Segment::Text(text) => rendered.push_str(text),
+      Segment::Variable(name) => rendered.push_str(&lookup(name, vars, secrets)?),
+    }
+  }
+  Ok(rendered)
+}
+
+/// The expressions of a template that `render` does not understand, e.g.
+/// conditionals or function calls. Empty if the whole template is supported.
+pub fn unsupported_expressions(template: &str) -> Vec<String> {
+  match parse(template) {
+    Ok(segments) => segments
+      .iter()
+      .filter_map(|segment| match segment {
+        Segment::Variable(name) if !is_reference(name) => Some(name.to_string()),
+        _ => None,
+      })
+      .collect(),
+    Err(e) => vec![e.to_string()],
+  }
+}
+
+fn parse(template: &str) -> Result<Vec<Segment<'_>>, DotcopterError> {
+  let mut segments = Vec::new();
+  let mut rest = template;
+  let mut trim_next = false;
+  while let Some(start) = rest.find("{{") {
+    let mut text = &rest[..start];
+    if trim_next {
+      text = text.trim_start();
+    }
+    let after = &rest[start + 2..];
+    let end = after
+      .find("}}")
+      .ok_or_else(|| DotcopterError::Message(format!("unterminated `{{{{` in template: {}", after.lines().next().unwrap_or(""))))?;
+    let mut expression = &after[..end];
+    if let Some(trimmed) = expression.strip_prefix('-') {
+      text = text.trim_end();
+      expression = trimmed;
+    }
+    trim_next = expression.ends_with('-');
+    if trim_next {
+      expression = &expression[..expression.len() - 1];
+    }
+    segments.push(Segment::Text(text));
+    segments.push(Segment::Variable(expression.trim()));
+    rest = &after[end + 2..];
+  }
+  segments.push(Segment::Text(if trim_next { rest.trim_start() } else { rest }));
+  Ok(segments)
+}
+
+/// Actions of Go templates that look like plain names.
+const KEYWORDS: [&str; 10] = ["if", "else", "end", "range", "with", "define", "template", "block", "break", "continue"];
+
+fn is_reference(expression: &str) -> bool {
+  let path = expression.strip_prefix('.').unwrap_or(expression);
+  !path.is_empty()
+    && !KEYWORDS.contains(&expression)
+    && path.split('.').all(|part| {
+      part.chars().next().map(|c| c.is_ascii_alphabetic() || c == '_').unwrap_or(false) && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// chezmoi uses Go's names for the operating system and architecture.
+fn go_name(fact: &str) -> &str {
+  match fact {
+    "macos" => "darwin",
+    "x86_64" => "amd64",
+    "aarch64" => "arm64",
+    "x86" => "386",
+    other => other,
+  }
+}
+
+fn lookup(expression: &str, vars: &Yaml, secrets: bool) -> Result<String, DotcopterError> {
+  if !is_reference(expression) {
+    return Err(DotcopterError::Message(format!("unsupported template expression `{}`", expression)));
+  }
+  let path: Vec<&str> = expression.strip_prefix('.').unwrap_or(expression).split('.').collect();
+  let fact = match path.as_slice() {
+    ["host", "hostname"] | ["chezmoi", "hostname"] => Some(host::hostname()),
+    ["host", "os"] => Some(Some(host::os().to_string())),
+    ["host", "arch"] => Some(Some(host::arch().to_string())),
+    ["chezmoi", "os"] => Some(Some(go_name(host::os()).to_string())),
+    ["chezmoi", "arch"] => Some(Some(go_name(host::arch()).to_string())),
+    ["host", "username"] | ["chezmoi", "username"] => Some(host::username()),
+    ["host", "home"] | ["chezmoi", "homeDir"] => Some(expand::home_dir()),
+    _ => None,
+  };
+  if let Some(fact) = fact {
+    return fact.ok_or_else(|| DotcopterError::Message(format!("`{}` is not available on this host", expression)));
+  }
+  if let ["secrets", name] = path.as_slice() {
+    if !secrets {
+      return Err(DotcopterError::Message(format!(
+        "template uses the secret `{}`, secrets are only written by apply",
+        name
+      )));
+    }
+    return secret::resolve(name);
+  }
+  let mut value = vars;
+  for part in &path {
+    value = &value[*part];
+  }
+  match value {
+    Yaml::String(value) | Yaml::Real(value) => Ok(value.to_string()),
+    Yaml::Integer(value) => Ok(value.to_string()),
+    Yaml::Boolean(value) => Ok(value.to_string()),
+    Yaml::BadValue | Yaml::Null => Err(DotcopterError::Message(format!("template variable `{}` is not defined", expression))),
+    _ => Err(DotcopterError::Message(format!("template variable `{}` is not a scalar", expression))),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+  use yaml_rust::YamlLoader;
+
+  #[test]
+  fn test_render() {
+    let vars = &YamlLoader::load_from_str("name: blubb\ngit:\n  email: a@b.c\n  sign: true\n").unwrap()[0];
+    assert_that(&render("[user]\n  name = {{ name }}\n  email = {{ .git.email }}\n", vars).unwrap())
+      .is_equal_to("[user]\n  name = blubb\n  email = a@b.c\n".to_string());
+    assert_that(&render("a  {{- git.sign -}}\n  b", vars).unwrap()).is_equal_to("atrueb".to_string());
+    assert_that(&render("{{ host.os }}", vars).unwrap()).is_equal_to(std::env::consts::OS.to_string());
+    assert_that(&render("{{ .chezmoi.arch }}", vars).unwrap()).is_equal_to(go_name(std::env::consts::ARCH).to_string());
+    assert_that(&render("{{ missing }}", vars).is_err()).is_true();
+    assert_that(&render("{{ git }}", vars).is_err()).is_true();
+    assert_that(&render("{{ name", vars).is_err()).is_true();
+    assert_that(&render_without_secrets("name = {{ name }}", vars).unwrap()).is_equal_to("name = blubb".to_string());
+    assert_that(&render_without_secrets("token = {{ secrets.github_token }}", vars).unwrap_err().to_string())
+      .is_equal_to("template uses the secret `github_token`, secrets are only written by apply".to_string());
+  }
+
+  #[test]
+  fn test_go_name() {
+    assert_that(&go_name("macos")).is_equal_to("darwin");
+    assert_that(&go_name("linux")).is_equal_to("linux");
+    assert_that(&go_name("x86_64")).is_equal_to("amd64");
+    assert_that(&go_name("aarch64")).is_equal_to("arm64");
+  }
+
+  #[test]
+  fn test_unsupported_expressions() {
+    assert_that(&unsupported_expressions("{{ .chezmoi.hostname }}")).is_equal_to(Vec::<String>::new());
+    assert_that(&unsupported_expressions("{{ if eq .chezmoi.os \"darwin\" }}x{{ end }}"))
+      .is_equal_to(vec!["if eq .chezmoi.os \"darwin\"".to_string(), "end".to_string()]);
+  }
+}
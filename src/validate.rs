@@ -0,0 +1,297 @@
+use crate::marked::{self, Node, Value};
+use crate::model;
+use std::fmt;
+use yaml_rust::scanner::ScanError;
+
+const TOP_LEVEL_KEYS: [&str; 3] = ["files", "vars", "secrets"];
+const SETTING_KEYS: [&str; 3] = ["src", "type", "mode"];
+const SECRET_KEYS: [&str; 1] = ["cmd"];
+const FILE_TYPES: [&str; 4] = ["link", "copy", "template", "encrypted"];
+
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+  pub line: usize,
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for Problem {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}: {}", self.line, self.column, self.message)
+  }
+}
+
+/// Checks a configuration for everything the lenient parser in `config` would
+/// silently ignore or guess. Returns all problems in document order.
+pub fn validate(text: &str) -> Vec<Problem> {
+  let documents = match marked::load(text) {
+    Ok(documents) => documents,
+    Err(e) => return vec![syntax_problem(&e)],
+  };
+  let mut problems = Vec::new();
+  if let Some(document) = documents.first() {
+    validate_document(document, &mut problems);
+  }
+  for extra_document in documents.iter().skip(1) {
+    problems.push(problem(extra_document, "only the first document is used, this one is ignored".to_string()));
+  }
+  problems
+}
+
+pub fn syntax_problem(error: &ScanError) -> Problem {
+  #[allow(deprecated)]
+  let message = std::error::Error::description(error).to_string();
+  Problem {
+    line: error.marker().line(),
+    column: error.marker().col() + 1,
+    message,
+  }
+}
+
+fn validate_document(document: &Node, problems: &mut Vec<Problem>) {
+  let entries = match document.value {
+    Value::Mapping(ref entries) => entries,
+    _ if document.is_null() => return,
+    _ => {
+      problems.push(problem(
+        document,
+        format!("expected a mapping with a `files` key, found {}", document.describe()),
+      ));
+      return;
+    }
+  };
+  for (key, value) in entries {
+    match key.as_str() {
+      Some("files") => validate_files(value, problems),
+      Some("vars") => {
+        if !matches!(value.value, Value::Mapping(_)) && !value.is_null() {
+          problems.push(problem(value, format!("`vars` must be a mapping, found {}", value.describe())));
+        }
+      }
+      Some("secrets") => validate_secrets(value, problems),
+      Some(name) => problems.push(problem(key, unknown_key_message(name, &TOP_LEVEL_KEYS))),
+      None => problems.push(problem(key, format!("expected a string key, found {}", key.describe()))),
+    }
+  }
+}
+
+fn validate_secrets(secrets: &Node, problems: &mut Vec<Problem>) {
+  let entries = match secrets.value {
+    Value::Mapping(ref entries) => entries,
+    _ if secrets.is_null() => return,
+    _ => {
+      problems.push(problem(secrets, format!("`secrets` must be a mapping of names, found {}", secrets.describe())));
+      return;
+    }
+  };
+  for (name, settings) in entries {
+    if name.as_str().is_none() {
+      problems.push(problem(name, format!("secret name must be a string, found {}", name.describe())));
+      continue;
+    }
+    let entries = match settings.value {
+      Value::Mapping(ref entries) => entries,
+      _ => {
+        problems.push(problem(settings, format!("expected a mapping with `cmd`, found {}", settings.describe())));
+        continue;
+      }
+    };
+    for (key, value) in entries {
+      match key.as_str() {
+        Some("cmd") if value.as_str().is_none() => problems.push(problem(value, format!("`cmd` must be a string, found {}", value.describe()))),
+        Some("cmd") => {}
+        Some(key_name) => problems.push(problem(key, unknown_key_message(key_name, &SECRET_KEYS))),
+        None => problems.push(problem(key, format!("expected a string key, found {}", key.describe()))),
+      }
+    }
+    if settings.get("cmd").is_none() {
+      problems.push(problem(name, "missing `cmd`".to_string()));
+    }
+  }
+}
+
+fn validate_files(files: &Node, problems: &mut Vec<Problem>) {
+  let entries = match files.value {
+    Value::Mapping(ref entries) => entries,
+    _ if files.is_null() => return,
+    _ => {
+      problems.push(problem(files, format!("`files` must be a mapping of targets, found {}", files.describe())));
+      return;
+    }
+  };
+  for (target, settings) in entries {
+    if target.as_str().is_none() {
+      problems.push(problem(target, format!("target must be a string, found {}", target.describe())));
+      continue;
+    }
+    match settings.value {
+      Value::Scalar(yaml_rust::Yaml::String(_)) => {}
+      Value::Mapping(ref entries) => validate_settings(target, settings, entries, problems),
+      _ => problems.push(problem(settings, format!("expected a source path or a mapping, found {}", settings.describe()))),
+    }
+  }
+}
+
+fn validate_settings(target: &Node, settings: &Node, entries: &[(Node, Node)], problems: &mut Vec<Problem>) {
+  for (key, value) in entries {
+    let name = match key.as_str() {
+      Some(name) => name,
+      None => {
+        problems.push(problem(key, format!("expected a string key, found {}", key.describe())));
+        continue;
+      }
+    };
+    if !SETTING_KEYS.contains(&name) {
+      problems.push(problem(key, unknown_key_message(name, &SETTING_KEYS)));
+      continue;
+    }
+    if name == "mode" {
+      let mode = match value.value {
+        Value::Scalar(yaml_rust::Yaml::Integer(mode)) => Some(mode.to_string()),
+        _ => value.as_str().map(|mode| mode.to_string()),
+      };
+      match mode {
+        Some(mode) if model::parse_mode(&mode).is_none() => {
+          problems.push(problem(value, format!("invalid mode `{}`, expected an octal permission like \"0600\"", mode)))
+        }
+        Some(_) => {}
+        None => problems.push(problem(value, format!("`mode` must be a string, found {}", value.describe()))),
+      }
+      continue;
+    }
+    match value.as_str() {
+      None => problems.push(problem(value, format!("`{}` must be a string, found {}", name, value.describe()))),
+      Some(file_type) if name == "type" && !FILE_TYPES.contains(&file_type.to_lowercase().as_ref()) => {
+        problems.push(problem(value, unknown_value_message("type", file_type, &FILE_TYPES)))
+      }
+      Some(_) => {}
+    }
+  }
+  if settings.get("src").is_none() {
+    problems.push(problem(target, "missing `src`".to_string()));
+  }
+}
+
+/// A problem reported at the position of `node`.
+pub fn problem(node: &Node, message: String) -> Problem {
+  Problem {
+    line: node.line,
+    column: node.column,
+    message,
+  }
+}
+
+fn unknown_key_message(name: &str, known: &[&str]) -> String {
+  match suggest(name, known) {
+    Some(suggestion) => format!("unknown key `{}`, did you mean `{}`?", name, suggestion),
+    None => format!("unknown key `{}`, expected one of: {}", name, known.join(", ")),
+  }
+}
+
+fn unknown_value_message(name: &str, value: &str, known: &[&str]) -> String {
+  match suggest(&value.to_lowercase(), known) {
+    Some(suggestion) => format!("unknown {} `{}`, did you mean `{}`?", name, value, suggestion),
+    None => format!("unknown {} `{}`, expected one of: {}", name, value, known.join(", ")),
+  }
+}
+
+fn suggest<'a>(name: &str, known: &[&'a str]) -> Option<&'a str> {
+  known
+    .iter()
+    .map(|candidate| (edit_distance(name, candidate), *candidate))
+    .filter(|(distance, candidate)| *distance <= 2 && *distance < candidate.len())
+    .min_by_key(|(distance, _)| *distance)
+    .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut previous: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut current = vec![i + 1];
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+      current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+    }
+    previous = current;
+  }
+  previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use spectral::prelude::*;
+
+  #[test]
+  fn test_valid_config() {
+    let s = "
+files:
+    ~/.tmux/plugins/tpm: tpm
+    ~/.tmux.conf:
+        src: tmux.conf
+        type: COPY
+";
+    assert_that(&validate(s)).is_empty();
+    assert_that(&validate("files:\n  ~/.netrc: {src: netrc, type: copy, mode: 0o600}\n")).is_empty();
+    let messages: Vec<String> = validate("files:\n  ~/.netrc: {src: netrc, type: copy, mode: 0x180}\n")
+      .iter()
+      .map(|p| p.to_string())
+      .collect();
+    assert_that(&messages).is_equal_to(vec!["2:44: invalid mode `0x180`, expected an octal permission like \"0600\"".to_string()]);
+  }
+
+  #[test]
+  fn test_reports_all_problems_with_positions() {
+    let s = "
+files:
+    ~/.vimrc:
+        scr: vimrc
+        type: lnk
+    ~/.ssh/config:
+        src: ssh/config
+        mode: \"0800\"
+    ~/.zshrc: [zshrc]
+fils: {}
+";
+    let messages: Vec<String> = validate(s).iter().map(|p| p.to_string()).collect();
+    assert_that(&messages).is_equal_to(vec![
+      "4:9: unknown key `scr`, did you mean `src`?".to_string(),
+      "5:15: unknown type `lnk`, did you mean `link`?".to_string(),
+      "3:5: missing `src`".to_string(),
+      "8:15: invalid mode `0800`, expected an octal permission like \"0600\"".to_string(),
+      "9:15: expected a source path or a mapping, found a list".to_string(),
+      "10:1: unknown key `fils`, did you mean `files`?".to_string(),
+    ]);
+  }
+
+  #[test]
+  fn test_reports_secret_problems() {
+    let s = "
+secrets:
+    github_token:
+        cmd: pass show github/token
+    aws: {command: aws-vault}
+    npm: npm-token
+";
+    let messages: Vec<String> = validate(s).iter().map(|p| p.to_string()).collect();
+    assert_that(&messages).is_equal_to(vec![
+      "5:11: unknown key `command`, expected one of: cmd".to_string(),
+      "5:5: missing `cmd`".to_string(),
+      "6:10: expected a mapping with `cmd`, found a string".to_string(),
+    ]);
+  }
+
+  #[test]
+  fn test_reports_syntax_errors() {
+    let problems = validate("files:\n  a: b\n c: d\n");
+    assert_that(&problems).has_length(1);
+    assert_that(&problems[0].line).is_equal_to(3);
+  }
+
+  #[test]
+  fn test_suggest() {
+    assert_that(&suggest("scr", &SETTING_KEYS)).is_equal_to(Some("src"));
+    assert_that(&suggest("owner", &SETTING_KEYS)).is_equal_to(None);
+  }
+}